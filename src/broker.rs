@@ -1,85 +1,1092 @@
-use crate::client::{ClientMsg, Join, RoomMsg};
-use base32;
+//! The broker actor, which owns every room and player and is the only place game state changes.
+//! Client actors talk to it over a channel of `ClientMsg`s, and are sent `BrokerMsg`s back for their player
+
+use crate::client::{AdminCmd, AdminMsg, ClientMsg, Join, RoomMsg};
+use crate::deadlines::Deadlines;
+use crate::index::PlayerIndex;
+use crate::limits::{Limit, LimitInfo};
+use crate::metrics::Metrics;
+use crate::names::{
+    normalize_username, suffixed_username, username_key, UsernameErr, USERNAME_LENGTH,
+};
+use crate::password::{same_secret, RoomPassword};
+use crate::repo::SpyfallRepo;
+use crate::settings::{RoomSettings, SettingsErr, MIN_PLAYERS_TO_START_GAME};
+use crate::snapshot::{self, RoomSnapshot, SeatSnapshot};
+use crate::throttle::SlidingWindow;
+use crate::vote::Vote;
+use crate::{AsyncErr, AsyncResult, PlayerId, Role, RoomId, SessionToken};
 use fastrand::Rng;
-use serde::Serialize;
-use smol::channel::{self, Receiver, Sender};
-use spyfall::{find_index, AsyncErr, AsyncResult, PlayerId};
+use serde::{Deserialize, Serialize};
+use smol::channel::{self, Receiver, RecvError, Sender, TrySendError};
+use smol::{future, Timer};
 use std::collections::hash_map::{Entry, HashMap, OccupiedEntry, VacantEntry};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const ROOM_ID_BYTES: usize = 5;
-const MAX_ROOM_CREATION_ATTEMPTS: usize = 5;
-const MIN_PLAYERS_TO_START_GAME: usize = 3;
+// room codes are read out and typed in, Crockford base32 leaves out the letters that get mixed up
+const ROOM_CODE_ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// how long a code the host picks can be, in characters
+const MIN_REQUESTED_CODE_LENGTH: usize = 4;
+const MAX_REQUESTED_CODE_LENGTH: usize = 12;
+const RECONNECT_TOKEN_BYTES: usize = 10;
+// how many messages a client can fall behind on before it is cut off
+const CLIENT_BUFFER: usize = 64;
+// the most rooms a listing shows, the rest would never be scrolled to
+const MAX_LISTED_ROOMS: usize = 50;
+// a player's state is broadcast at most once per window, however often they toggle it
+const STATE_BROADCAST_DEBOUNCE: Duration = Duration::from_secs(1);
+// only the most recent rounds of a room are kept around for the recap
+const MAX_ROUND_HISTORY: usize = 50;
+// how many unexpired invites a room can have out at once, past this the oldest one stops working
+const MAX_OUTSTANDING_INVITES: usize = 10;
+// how often clients are told the time left on a round, until the final countdown
+const TIMER_TICK_INTERVAL: Duration = Duration::from_secs(15);
+// the last seconds of a round are counted down one at a time
+const FINAL_COUNTDOWN_SECS: u64 = 10;
+// with decoy rounds enabled, one round in this many has no spy
+const DECOY_ROUND_ODDS: usize = 5;
+// points for each spy when the spies win, and for each other player when they do
+const SPY_WIN_POINTS: u32 = 2;
+const NON_SPY_WIN_POINTS: u32 = 1;
+const CHAT_LENGTH: Limit = Limit::new("max_chat_chars", 500);
+// whatever the room's settings, past this a table is too big to question everyone and roles repeat absurdly
+const MAX_ROOM_PLAYERS: usize = 20;
+const ROOM_CAPACITY: Limit = Limit::new("max_room_players", MAX_ROOM_PLAYERS as u64);
 
-type RoomId = String;
 pub type JoinResult = Result<(Connected, Receiver<BrokerMsg>), JoinErr>;
+pub type AdminResult = Result<AdminReply, AdminErr>;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum BrokerMsg {
     Join(Arc<str>),
-    Left(Arc<str>),
+    Left {
+        name: Arc<str>,
+        reason: LeaveReason,
+    },
+    // the host removed a player, who is told too before their channel closes
+    Kicked {
+        target: Arc<str>,
+        by: Arc<str>,
+    },
+    // someone else is in charge of the room now
+    HostChanged {
+        host: Arc<str>,
+    },
+    // a player fixed their name, they keep their seat and everything else
+    Renamed {
+        from: Arc<str>,
+        to: Arc<str>,
+    },
+    // the player's connection dropped, as opposed to them leaving
+    Disconnected(Arc<str>),
+    // a player whose connection dropped is back in their seat
+    Reconnected {
+        name: Arc<str>,
+    },
+    // everyone in the room, in joining order, sent after every Join, Left and readiness change.
+    // The deltas are only there to animate, this is what clients should trust
+    Roster {
+        players: Vec<RosterEntry>,
+        host: PlayerId,
+        in_game: bool,
+    },
     Started(Start),
-    NotEnoughPlayers,
+    // a start asked for with fewer connected players than the room needs
+    NotEnoughPlayers {
+        have: usize,
+        need: usize,
+    },
+    // a start in a room dealing distinct roles, where no location has a role for each of `need` players
+    NotEnoughRoles {
+        need: usize,
+    },
+    // a start asked for while a round is being played, which the round carries on through
+    AlreadyStarted,
+    // sent by the client actor in place of forwarding a message, when a client sends too many
+    RateLimited(LimitInfo),
+    // sent by the client actor before closing a connection that sent a message over the size limit
+    MessageTooLarge(LimitInfo),
+    // sent by the client actor before closing a connection that speaks a protocol version it doesn't,
+    // along with the oldest and newest versions it does
+    UnsupportedVersion {
+        server_supports: (u16, u16),
+    },
+    ReadyChanged {
+        player: Arc<str>,
+        ready: bool,
+    },
+    History(Vec<RoundSummary>),
+    RoomInfo(RoomInfo),
+    // the location pack the next rounds are dealt from, every location when None
+    CategoryChanged {
+        category: Option<Arc<str>>,
+    },
+    Scores(BTreeMap<Arc<str>, u32>),
+    // the board the room's next round is dealt from
+    Locations(Vec<Arc<str>>),
+    Chat {
+        from: Arc<str>,
+        text: Arc<str>,
+    },
+    // sent only to a player whose chat message was dropped for coming too fast after their others
+    ChatRateLimited(LimitInfo),
+    // the round was decided, revealing the location and the spies
+    Revealed(RoundSummary),
+    // someone reached the room's target score, and the scores start over
+    MatchOver {
+        winner: Arc<str>,
+        scores: BTreeMap<Arc<str>, u32>,
+    },
+    // the round could not go on without the players who left, and is revealed as it stood
+    GameAborted {
+        reason: AbortReason,
+        summary: RoundSummary,
+    },
+    LocationMarked {
+        location: Arc<str>,
+        crossed: bool,
+    },
+    TimeUp {
+        round: u32,
+    },
+    PlayerIdle {
+        name: Arc<str>,
+    },
+    ExtensionRequested {
+        by: Arc<str>,
+    },
+    RoundExtended {
+        remaining_secs: u64,
+    },
+    // the time left on the round, sent every so often
+    TimerTick {
+        remaining_secs: u64,
+    },
+    // sent every second at the end of a round, clients too slow to take one just miss it
+    FinalCountdown {
+        remaining_secs: u64,
+    },
+    Paused {
+        by: Arc<str>,
+    },
+    // None for untimed rounds
+    Resumed {
+        remaining_secs: Option<u64>,
+    },
+    ExtensionRejected,
+    // sent only to the host who asked for it, anyone joining with the code gets past the password
+    Invite {
+        code: String,
+        expires_in_secs: u64,
+        uses: u32,
+    },
+    // the room was shut down, the last message a client gets from it
+    RoomClosed {
+        reason: CloseReason,
+    },
+    // sent only to the player whose request could not be carried out
+    Rejected(RoomErr),
+}
+
+impl From<RoomErr> for BrokerMsg {
+    fn from(err: RoomErr) -> Self {
+        BrokerMsg::Rejected(err)
+    }
+}
+
+/// Why a request made from inside a room was refused
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum RoomErr {
+    NoTimerRunning,
+    ExtensionAlreadyRequested,
+    NoVoteInProgress,
+    ChatTooLong(LimitInfo),
+    NoRoundInProgress,
+    NotTheSpy,
+    AlreadyGuessed,
+    NotTheHost,
+    RoundPaused,
+    RoundNotPaused,
+    NoSuchCategory,
+    // the player joined after the round was dealt
+    NotDealtIn,
+    NoSuchPlayer,
+    // roles are dealt by name, so names can only change between rounds
+    RoundInProgress,
+    InvalidUsername(UsernameErr),
+    UsernameTaken,
+    // the room waits on everyone being ready, and these players aren't yet
+    NotEveryoneReady(Vec<PlayerId>),
 }
 
 // returned when successfully joining the room
 #[derive(Debug, Clone, Serialize)]
 pub struct Connected {
-    pub room_id: String,
-    pub players: Vec<String>,
+    pub room_id: RoomId,
+    // the player's name as the room knows it, which may differ from the one they asked for
+    pub name: PlayerId,
+    // in joining order, the new player last
+    pub players: Vec<PlayerId>,
+    pub host: PlayerId,
+    // the players who have marked themselves ready, in joining order
+    pub ready: Vec<PlayerId>,
+    // whether a round is being played, which the player sits out if they weren't dealt in
+    pub in_game: bool,
+    pub phase: Phase,
+    // the player's own location notes for this round, restored when they rejoin
+    pub crossed_locations: Vec<String>,
+    // joining again with this takes the seat back after the connection drops
+    pub reconnect_token: String,
+    // stamped on every room message, so the broker knows it comes from this seat
+    pub id: SessionToken,
+    // the rules the host picked, the round timer among them
+    pub settings: RoomSettings,
+    // how many connected players it takes to start a round
+    pub min_players: usize,
+    // the players sitting out the round being played, in joining order
+    pub watching: Vec<PlayerId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RosterEntry {
+    pub name: PlayerId,
+    // false while the player's connection is down and their seat is held for them
+    pub connected: bool,
+    pub ready: bool,
+    // joined after the round was dealt, so they watch it and are dealt in from the next one
+    pub watching: bool,
 }
 
-// A user error when attempting to connect to the room
+/// A user error when attempting to connect to the room.
+/// Variants without context serialize as their name (`"NoSuchRoom"`), the rest as an object keyed by it
+/// (`{"UsernameTaken":{"suggested":"Ahab (2)"}}`), with every field always present
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum JoinErr {
     NoSuchRoom,
-    UsernameTaken,
+    // a free name to offer instead, None when the name's seat is being held for a player who dropped,
+    // who should come back with their reconnect token rather than under another name
+    UsernameTaken { suggested: Option<PlayerId> },
+    InvalidUsername(UsernameErr),
     FailedToCreateRoom,
+    // one of the server-wide caps (on rooms or players) was reached
+    ServerFull(LimitInfo),
+    InvalidSettings(SettingsErr),
+    WrongPassword,
+    // the invite joined with has expired or been used up, or was never the room's
+    InvalidInvite,
+    // the connection's address has created too many rooms lately
+    RateLimited(LimitInfo),
+    // the code asked for is already some other room's
+    CodeTaken,
+    InvalidCode(CodeErr),
+    // the reconnect token resumed with is for a seat that is gone, or never was
+    NoSuchSeat,
+    // every seat is taken, held ones included
+    RoomFull(LimitInfo),
+}
+
+/// Why a code asked for can't be a room's
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum CodeErr {
+    Length { min: usize, max: usize },
+    // codes are Crockford base32, once read the way they are normalized
+    InvalidCharacter,
+    // it contains one of the words the server won't hand out
+    Blocked,
+}
+
+impl JoinErr {
+    /// The variant's name, which join errors are counted under
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NoSuchRoom => "NoSuchRoom",
+            Self::UsernameTaken { .. } => "UsernameTaken",
+            Self::InvalidUsername(_) => "InvalidUsername",
+            Self::FailedToCreateRoom => "FailedToCreateRoom",
+            Self::ServerFull(_) => "ServerFull",
+            Self::InvalidSettings(_) => "InvalidSettings",
+            Self::WrongPassword => "WrongPassword",
+            Self::InvalidInvite => "InvalidInvite",
+            Self::RateLimited(_) => "RateLimited",
+            Self::CodeTaken => "CodeTaken",
+            Self::InvalidCode(_) => "InvalidCode",
+            Self::NoSuchSeat => "NoSuchSeat",
+            Self::RoomFull(_) => "RoomFull",
+        }
+    }
 }
 
 // sent directly to client actors.
 // Information is then decomposed by each actor to send the appropriate message back to the client.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GameInfo {
-    pub player_roles: HashMap<PlayerId, String>,
+    pub player_roles: HashMap<PlayerId, Role>,
     pub location: String,
     pub first: PlayerId,
-    pub spy: PlayerId,
+    // empty in a decoy round
+    pub spies: Vec<PlayerId>,
+    // what a blind spy is told instead of being revealed as the spy
+    pub spy_cover: Option<Assignment>,
+}
+
+/// Choices a debug build lets the starter make instead of leaving them to the rng.
+/// Anything that doesn't fit the room (a player not in it, an unknown location) is dealt randomly
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Forced {
+    pub spy: Option<PlayerId>,
+    pub location: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Start {
+    // counts up from 1 with every round the room starts, so stale messages can be told apart
+    round: u32,
     assignment: Option<Assignment>,
     first: Arc<str>,
+    // None when the room plays untimed rounds
+    remaining_secs: Option<u64>,
+    // the other spy, told to colluding spies only.
+    // Always serialized, so a spy's message has the same shape as everybody else's
+    partner: Option<Arc<str>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Assignment {
     location: Arc<str>,
-    role: String,
+    role: Role,
+}
+
+/// Why a player is no longer in the room, so clients can tell leaving apart from losing connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LeaveReason {
+    // the player left, or closed their tab cleanly
+    Voluntary,
+    // their connection dropped, and they didn't come back before their seat stopped being held
+    Disconnected,
+    // removed by the host
+    Kicked,
+    // they stopped interacting for too long
+    Idle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CloseReason {
+    // nobody in the room did anything for longer than the ttl
+    Idle,
+    // the host ended the room for everyone
+    ClosedByHost,
+    // an operator shut the room down
+    ClosedByAdmin,
+}
+
+/// What an operator's command did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum AdminReply {
+    Closed,
+    // sorted by id
+    Found { rooms: Vec<RoomId> },
+}
+
+/// Why an operator's command wasn't carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AdminErr {
+    // the token isn't the server's admin token, or the server has none
+    Unauthorized,
+    NoSuchRoom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AbortReason {
+    // which the non-spies win
+    SpyLeft,
+    NotEnoughPlayers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Winner {
+    Spy,
+    NonSpies,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Phase {
+    Lobby,
+    Playing,
+    Paused,
+}
+
+/// An overview of a room, for client UIs and for operators poking at the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomInfo {
+    players: Vec<PlayerId>,
+    rounds_played: u32,
+    created_at_secs_ago: u64,
+    phase: Phase,
+    settings: RoomSettings,
+    category: Option<String>,
+}
+
+/// A public room as listed to someone who hasn't joined it, without giving away who is in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomSummary {
+    room_id: RoomId,
+    player_count: usize,
+    // how many players the room can seat, held seats included
+    capacity: usize,
+    phase: Phase,
+}
+
+/// How busy the server is, cheap enough for a landing page to ask on every visit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ServerStats {
+    active_rooms: usize,
+    // held seats included
+    active_players: usize,
+    // paused rounds included
+    games_in_progress: usize,
+}
+
+/// A recap of a completed round, kept by the room for the rest of the session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoundSummary {
+    round: u32,
+    location: Arc<str>,
+    // empty when it was a decoy round
+    spies: Vec<Arc<str>>,
+    // None when the round was cut short without a winner
+    winner: Option<Winner>,
+    duration_secs: u64,
+    scores: BTreeMap<Arc<str>, u32>,
+}
+
+/// The round currently being played in a room
+#[derive(Debug)]
+struct Round {
+    number: u32,
+    location: String,
+    spies: Vec<PlayerId>,
+    started_at: Instant,
+    ends_at: Option<Instant>,
+    // a round can only be extended once, successful or not
+    extension_requested: bool,
+    extension_vote: Option<Vote>,
+    // when the host paused the round, its timer is frozen until they resume it
+    paused_at: Option<Instant>,
+    // what each player was dealt, to hand out again when they come back after a dropped connection
+    dealt: HashMap<PlayerId, Start>,
+}
+
+impl Round {
+    /// Whole seconds left on the round timer, which don't run down while paused
+    fn remaining_secs(&self) -> Option<u64> {
+        let now = self.paused_at.unwrap_or_else(Instant::now);
+        let ends_at = self.ends_at?;
+        Some(ends_at.saturating_duration_since(now).as_secs())
+    }
+
+    /// What the player was dealt, with the timer as it stands now
+    fn dealt_to(&self, name: &PlayerId) -> Option<Start> {
+        let dealt = self.dealt.get(name)?;
+        Some(Start {
+            remaining_secs: self.remaining_secs(),
+            ..dealt.clone()
+        })
+    }
+}
+
+/// The kinds of per-player state that can be toggled from the lobby
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateKind {
+    Ready,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateKey {
+    player: PlayerId,
+    kind: StateKind,
+}
+
+/// Timed events the broker schedules for itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Deadline {
+    // the debounce window for broadcasting a player's state has closed
+    StateBroadcast(RoomId, StateKey),
+    // the round timer of a room ran out
+    RoundOver(RoomId),
+    // time to tell a room how long its round has left
+    TimerTick(RoomId),
+    // time to look for players who stopped interacting
+    IdleSweep,
+    // time to look for rooms nobody has used in a while
+    RoomSweep,
+    // time to write the rooms down, in case the server goes away
+    Snapshot,
 }
 
 #[derive(Debug)]
 pub struct Room {
-    names: Vec<String>,
-    senders: Vec<Sender<BrokerMsg>>,
+    // in joining order, which deals are drawn in
+    players: Vec<Player>,
+    // where each player sits in players, by `username_key`
+    seats: HashMap<String, usize>,
+    // the player in charge of the room, the creator until they hand it over or leave
+    host: PlayerId,
+    ready: HashSet<PlayerId>,
+    // players whose connection dropped and when, their seats are held for a while (and until the round is over)
+    disconnected: HashMap<PlayerId, Instant>,
+    // what each player can rejoin with to take their seat back
+    reconnect_tokens: HashMap<PlayerId, String>,
+    // state changes that have been applied but are waiting on the debounce window to be broadcast
+    unsent_states: HashSet<StateKey>,
+    // players something sent to the whole room didn't reach, let go of once the broker is done with the message
+    unreachable: Vec<PlayerId>,
+    round: Option<Round>,
+    scores: HashMap<PlayerId, u32>,
+    history: VecDeque<RoundSummary>,
+    settings: RoomSettings,
+    // each player's private location notes, kept by name so they survive a page refresh
+    marks: HashMap<PlayerId, BTreeSet<String>>,
+    activity: HashMap<PlayerId, Activity>,
+    created_at: Instant,
+    // the last time anyone in the room sent anything
+    last_activity: Instant,
+    // players tied at the target score, only they can be the spy until the tie is broken
+    tie_break: Vec<PlayerId>,
+    rounds_started: u32,
+    // the round in which the spies used up their location guess
+    guessed_in_round: Option<u32>,
+    // the last few locations dealt, most recent last, which the next deals avoid
+    recent_locations: VecDeque<String>,
+    // the host's pick of location pack, rounds are dealt from every location when None
+    category: Option<String>,
+    // anyone with the room code can join when None
+    password: Option<RoomPassword>,
+    // what the host has handed out to get into the room without its password, by code
+    invites: HashMap<String, Invite>,
+    // how many connected players it takes to start a round
+    min_players: usize,
+}
+
+/// Someone seated in a room
+#[derive(Debug)]
+struct Player {
+    // made up by the broker, unlike the name nobody can claim it for themselves
+    id: SessionToken,
+    name: PlayerId,
+    sender: Sender<BrokerMsg>,
+}
+
+#[derive(Debug)]
+struct Invite {
+    expires_at: Instant,
+    uses_left: u32,
+}
+
+#[derive(Debug)]
+struct Activity {
+    last_seen: Instant,
+    // whether the room has already been told this player is idle
+    idle: bool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last_seen: Instant::now(),
+            idle: false,
+        }
+    }
+}
+
+impl Room {
+    fn new(
+        name: PlayerId,
+        sender: Sender<BrokerMsg>,
+        token: SessionToken,
+        settings: RoomSettings,
+    ) -> Self {
+        let mut room = Self {
+            min_players: settings.min_players,
+            host: name.clone(),
+            players: vec![],
+            seats: HashMap::new(),
+            ready: HashSet::new(),
+            disconnected: HashMap::new(),
+            reconnect_tokens: HashMap::new(),
+            unsent_states: HashSet::new(),
+            unreachable: Vec::new(),
+            round: None,
+            scores: HashMap::new(),
+            history: VecDeque::new(),
+            settings,
+            marks: HashMap::new(),
+            activity: HashMap::new(),
+            created_at: Instant::now(),
+            last_activity: Instant::now(),
+            tie_break: Vec::new(),
+            rounds_started: 0,
+            guessed_in_round: None,
+            recent_locations: VecDeque::new(),
+            category: None,
+            password: None,
+            invites: HashMap::new(),
+        };
+        room.add(Player {
+            id: token,
+            name,
+            sender,
+        });
+        room
+    }
+
+    /// What of the room outlives the server: everything but the round being played and the connections
+    fn snapshot(&self, room_id: &RoomId) -> RoomSnapshot {
+        RoomSnapshot {
+            room_id: room_id.clone(),
+            host: self.host.clone(),
+            seats: self
+                .names()
+                .map(|name| SeatSnapshot {
+                    name: name.clone(),
+                    reconnect_token: self.reconnect_tokens[name].clone(),
+                })
+                .collect(),
+            settings: self.settings.clone(),
+            min_players: self.min_players,
+            scores: self.scores.clone(),
+            history: self.history.iter().cloned().collect(),
+            tie_break: self.tie_break.clone(),
+            rounds_started: self.rounds_started,
+            recent_locations: self.recent_locations.iter().cloned().collect(),
+            category: self.category.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    /// A snapshotted room, back in its lobby with every seat held for its player to reconnect to.
+    /// The seats are indexed under new session tokens, None when there were no seats to bring back
+    fn restore(snapshot: RoomSnapshot, index: &mut PlayerIndex, rng: &Rng) -> Option<Self> {
+        let mut room: Option<Self> = None;
+        for seat in snapshot.seats {
+            let token = new_token(rng, index);
+            index.insert(token, &seat.name, &snapshot.room_id, &seat.reconnect_token);
+            // nothing listens to a held seat, broadcasts to it are dropped until its player is back
+            let (sender, _) = channel::bounded(1);
+            let name = seat.name.clone();
+            let room = match room.as_mut() {
+                None => room.insert(Self::new(name, sender, token, snapshot.settings.clone())),
+                Some(room) => {
+                    room.add(Player {
+                        id: token,
+                        name,
+                        sender,
+                    });
+                    room
+                }
+            };
+            room.disconnected.insert(seat.name.clone(), Instant::now());
+            room.reconnect_tokens
+                .insert(seat.name, seat.reconnect_token);
+        }
+        let mut room = room?;
+        room.host = snapshot.host;
+        room.min_players = snapshot.min_players;
+        room.scores = snapshot.scores;
+        room.history = snapshot.history.into();
+        room.tie_break = snapshot.tie_break;
+        room.rounds_started = snapshot.rounds_started;
+        room.recent_locations = snapshot.recent_locations.into();
+        room.category = snapshot.category;
+        room.password = snapshot.password;
+        Some(room)
+    }
+
+    /// Moves everything kept for the player in `seat` over to their new name
+    fn rename(&mut self, seat: usize, to: &PlayerId) {
+        let from = std::mem::replace(&mut self.players[seat].name, to.clone());
+        self.seats.remove(&username_key(&from));
+        self.seats.insert(username_key(to), seat);
+        fn rekey<V>(map: &mut HashMap<PlayerId, V>, from: &PlayerId, to: &PlayerId) {
+            if let Some(value) = map.remove(from) {
+                map.insert(to.clone(), value);
+            }
+        }
+        rekey(&mut self.disconnected, &from, to);
+        rekey(&mut self.reconnect_tokens, &from, to);
+        rekey(&mut self.scores, &from, to);
+        rekey(&mut self.marks, &from, to);
+        rekey(&mut self.activity, &from, to);
+        if self.ready.remove(&from) {
+            self.ready.insert(to.clone());
+        }
+        self.unsent_states = self
+            .unsent_states
+            .drain()
+            .map(|key| {
+                if key.player == from {
+                    StateKey {
+                        player: to.clone(),
+                        ..key
+                    }
+                } else {
+                    key
+                }
+            })
+            .collect();
+        for tied in self.tie_break.iter_mut().filter(|tied| **tied == from) {
+            *tied = to.clone();
+        }
+        if self.host == from {
+            self.host = to.clone();
+        }
+    }
+
+    /// Records that the player just did something
+    fn touch(&mut self, name: &PlayerId) {
+        if let Some(activity) = self.activity.get_mut(name) {
+            *activity = Activity::new();
+            self.last_activity = Instant::now();
+        }
+    }
+
+    fn crossed_locations(&self, name: &PlayerId) -> Vec<String> {
+        self.marks
+            .get(name)
+            .map(|marks| marks.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn host(&self) -> &PlayerId {
+        &self.host
+    }
+
+    fn names(&self) -> impl Iterator<Item = &PlayerId> {
+        self.players.iter().map(|player| &player.name)
+    }
+
+    fn senders(&self) -> impl Iterator<Item = &Sender<BrokerMsg>> {
+        self.players.iter().map(|player| &player.sender)
+    }
+
+    /// The players who can currently be dealt into a round
+    fn connected_players(&self) -> impl Iterator<Item = &PlayerId> {
+        self.names()
+            .filter(move |name| !self.disconnected.contains_key(*name))
+    }
+
+    /// The connected players dealt into the round being played, the ones it can go on with
+    fn playing(&self) -> impl Iterator<Item = &PlayerId> {
+        let dealt = self.round.as_ref().map(|round| &round.dealt);
+        self.connected_players()
+            .filter(move |name| dealt.is_some_and(|dealt| dealt.contains_key(*name)))
+    }
+
+    fn phase(&self) -> Phase {
+        match &self.round {
+            None => Phase::Lobby,
+            Some(round) if round.paused_at.is_some() => Phase::Paused,
+            Some(_) => Phase::Playing,
+        }
+    }
+
+    /// Whether anyone can find the room in the listing
+    fn listed(&self) -> bool {
+        self.settings.public && self.password.is_none()
+    }
+
+    fn info(&self) -> RoomInfo {
+        RoomInfo {
+            players: self.names().cloned().collect(),
+            rounds_played: self.rounds_started,
+            created_at_secs_ago: self.created_at.elapsed().as_secs(),
+            phase: self.phase(),
+            settings: self.settings.clone(),
+            category: self.category.clone(),
+        }
+    }
+
+    fn roster(&self) -> BrokerMsg {
+        let players = self
+            .names()
+            .map(|name| RosterEntry {
+                name: name.clone(),
+                connected: !self.disconnected.contains_key(name),
+                ready: self.ready.contains(name),
+                watching: self.is_watching(name),
+            })
+            .collect();
+        BrokerMsg::Roster {
+            players,
+            host: self.host.clone(),
+            in_game: self.round.is_some(),
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = (&PlayerId, SessionToken)> {
+        self.players.iter().map(|player| (&player.name, player.id))
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn reconnect_token_of(&self, name: &PlayerId) -> Option<&str> {
+        self.reconnect_tokens.get(name).map(String::as_str)
+    }
+
+    /// The seat whose current connection is `token`
+    fn seat_with(&self, token: SessionToken) -> Option<usize> {
+        self.players.iter().position(|player| player.id == token)
+    }
+
+    /// The seat of the player with exactly this name
+    fn seat_of(&self, name: &str) -> Option<usize> {
+        self.seat_like(name)
+            .filter(|seat| self.players[*seat].name == name)
+    }
+
+    /// Held seats are let go once the grace period and the round they were held for are over
+    fn hold_expired(&self, name: &PlayerId, grace: Duration) -> bool {
+        self.round.is_none()
+            && self
+                .disconnected
+                .get(name)
+                .is_some_and(|dropped_at| dropped_at.elapsed() >= grace)
+    }
+
+    /// The seat of the player whose name would be confused with this one
+    fn seat_like(&self, name: &str) -> Option<usize> {
+        self.seats.get(&username_key(name)).copied()
+    }
+
+    fn is_seated(&self, name: &str) -> bool {
+        self.seat_of(name).is_some()
+    }
+
+    /// Whether `presented` is the reconnect token of whoever was given the seat
+    fn owns_seat(&self, seat: usize, presented: Option<&str>) -> bool {
+        let token = self.reconnect_tokens.get(&self.players[seat].name);
+        presented.is_some_and(|presented| token.is_some_and(|token| token == presented))
+    }
+
+    /// Seats a player at the end of the table, active as of now
+    fn add(&mut self, player: Player) {
+        self.seats
+            .insert(username_key(&player.name), self.players.len());
+        self.activity.insert(player.name.clone(), Activity::new());
+        self.players.push(player);
+    }
+
+    /// Unseats the player with exactly this name along with everything kept for them,
+    /// handing the room to whoever has been in it the longest if they were the host
+    fn remove(&mut self, name: &PlayerId) -> Option<Player> {
+        let seat = self.seat_of(name)?;
+        let player = self.players.remove(seat);
+        self.seats.remove(&username_key(name));
+        for later in self.seats.values_mut().filter(|later| **later > seat) {
+            *later -= 1;
+        }
+        self.ready.remove(name);
+        self.disconnected.remove(name);
+        self.reconnect_tokens.remove(name);
+        self.scores.remove(name);
+        self.activity.remove(name);
+        self.unsent_states.retain(|key| key.player != *name);
+        self.marks.remove(name);
+        // whoever takes the name next wasn't dealt this round
+        if let Some(round) = self.round.as_mut() {
+            round.dealt.remove(name);
+        }
+        if self.host == *name {
+            // a held seat can't do any of the host's work, so it only gets the role when nobody else is left
+            let next = self
+                .players
+                .iter()
+                .find(|next| !self.disconnected.contains_key(&next.name))
+                .or_else(|| self.players.first());
+            if let Some(next) = next {
+                self.host = next.name.clone();
+            }
+        }
+        Some(player)
+    }
+
+    /// The player with exactly this name
+    fn get(&self, name: &str) -> Option<&Player> {
+        self.seat_of(name).map(|seat| &self.players[seat])
+    }
+
+    /// Sends `msg` to everyone seated, returning the seats it couldn't be delivered to
+    fn broadcast(&mut self, msg: BrokerMsg) -> Vec<usize> {
+        let missed = send_room(self.senders(), msg);
+        for seat in &missed {
+            self.unreachable.push(self.players[*seat].name.clone());
+        }
+        missed
+    }
+
+    fn token_of(&self, name: &PlayerId) -> Option<SessionToken> {
+        self.get(name).map(|player| player.id)
+    }
+
+    pub fn sender_for(&self, name: &PlayerId) -> Option<&Sender<BrokerMsg>> {
+        self.get(name).map(|player| &player.sender)
+    }
+
+    fn remember_location(&mut self, location: &str) {
+        self.recent_locations.push_back(location.to_string());
+        // the previous round's location is always avoided, the same place twice in a row is anticlimactic
+        let memory = self.settings.location_memory.max(1);
+        while self.recent_locations.len() > memory {
+            self.recent_locations.pop_front();
+        }
+    }
+
+    /// The cumulative score of every player in the room
+    fn score_table(&self) -> BTreeMap<Arc<str>, u32> {
+        self.names()
+            .map(|name| {
+                let score = self.scores.get(name).copied().unwrap_or(0);
+                (Arc::from(name.as_str()), score)
+            })
+            .collect()
+    }
+
+    /// Ends the round in progress (if any), scoring it and recording it in the room's history
+    fn end_round(&mut self, winner: Option<Winner>) -> Option<RoundSummary> {
+        let round = self.round.take()?;
+        if let Some(winner) = winner {
+            // whoever only watched the round has nothing to win from it
+            let dealt_in = self
+                .players
+                .iter()
+                .filter(|player| round.dealt.contains_key(&player.name));
+            for Player { name, .. } in dealt_in {
+                let points = match (winner, round.spies.contains(name)) {
+                    (Winner::Spy, true) => SPY_WIN_POINTS,
+                    (Winner::NonSpies, false) => NON_SPY_WIN_POINTS,
+                    _ => 0,
+                };
+                *self.scores.entry(name.clone()).or_insert(0) += points;
+            }
+        }
+        let scores = self.score_table();
+        let summary = RoundSummary {
+            round: round.number,
+            location: Arc::from(round.location),
+            spies: round.spies.into_iter().map(Arc::from).collect(),
+            winner,
+            duration_secs: round.started_at.elapsed().as_secs(),
+            scores,
+        };
+        if self.history.len() == MAX_ROUND_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(summary.clone());
+        Some(summary)
+    }
+
+    fn ready_players(&self) -> Vec<PlayerId> {
+        self.names()
+            .filter(|name| self.ready.contains(*name))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `code` lets someone in without the password, forgetting the invites that have expired
+    fn invite_valid(&mut self, code: &str, now: Instant) -> bool {
+        self.invites.retain(|_, invite| invite.expires_at > now);
+        self.invites.contains_key(code)
+    }
+
+    /// Counts a join against the invite, which is gone once it runs out of uses
+    fn use_invite(&mut self, code: &str) {
+        if let Some(invite) = self.invites.get_mut(code) {
+            invite.uses_left = invite.uses_left.saturating_sub(1);
+            if invite.uses_left == 0 {
+                self.invites.remove(code);
+            }
+        }
+    }
+
+    /// The locations the room's next round is dealt from, the host's category or every location
+    fn location_pool<'r>(&self, repo: &'r SpyfallRepo) -> &'r [String] {
+        self.category
+            .as_deref()
+            .and_then(|category| repo.locations_in(category))
+            .unwrap_or(repo.locations())
+    }
+
+    /// Whether a round is being played that the player wasn't dealt into
+    fn is_watching(&self, name: &PlayerId) -> bool {
+        self.round
+            .as_ref()
+            .is_some_and(|round| !round.dealt.contains_key(name))
+    }
+
+    /// What the player seated as `name` is told about the room on joining it
+    fn connected(&self, room_id: &RoomId, name: PlayerId, id: SessionToken) -> Connected {
+        Connected {
+            id,
+            reconnect_token: self.reconnect_tokens[&name].clone(),
+            room_id: room_id.clone(),
+            players: self.names().cloned().collect(),
+            host: self.host().clone(),
+            ready: self.ready_players(),
+            in_game: self.round.is_some(),
+            phase: self.phase(),
+            crossed_locations: self.crossed_locations(&name),
+            settings: self.settings.clone(),
+            min_players: self.min_players,
+            watching: self
+                .names()
+                .filter(|name| self.is_watching(name))
+                .cloned()
+                .collect(),
+            name,
+        }
+    }
+
+    /// Returns true if the player's readiness actually changed
+    fn set_ready(&mut self, name: &PlayerId, ready: bool) -> bool {
+        if !self.is_seated(name) {
+            false
+        } else if ready {
+            self.ready.insert(name.clone())
+        } else {
+            self.ready.remove(name)
+        }
+    }
+
+    /// The broadcast describing the current value of a player's state
+    fn state_msg(&self, key: &StateKey) -> Option<BrokerMsg> {
+        self.seat_of(&key.player)?;
+        let player = Arc::from(key.player.as_str());
+        Some(match key.kind {
+            StateKind::Ready => BrokerMsg::ReadyChanged {
+                player,
+                ready: self.ready.contains(&key.player),
+            },
+        })
+    }
 }
 
-impl PartialEq<Vec<String>> for Room {
-    fn eq(&self, other: &Vec<String>) -> bool {
-        self.names.eq(other)
+impl PartialEq<Vec<PlayerId>> for Room {
+    fn eq(&self, other: &Vec<PlayerId>) -> bool {
+        self.names().eq(other.iter())
     }
 }
 
 impl PartialEq<Room> for Room {
     fn eq(&self, other: &Self) -> bool {
-        self.names.eq(&other.names)
+        self.names().eq(other.names())
     }
 }
 
 impl Eq for Room {}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 pub struct RoomTable(HashMap<RoomId, Room>);
 
 impl RoomTable {
@@ -97,16 +1104,86 @@ impl RoomTable {
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&RoomId, &Room)> {
+        self.0.iter()
+    }
+
+    /// The public rooms, the ones that can still be joined between rounds first
+    pub fn summaries(&self) -> Vec<RoomSummary> {
+        let mut summaries = self
+            .iter()
+            .filter(|(_, room)| room.listed())
+            .map(|(room_id, room)| RoomSummary {
+                room_id: room_id.clone(),
+                player_count: room.players.len(),
+                capacity: MAX_ROOM_PLAYERS,
+                phase: room.phase(),
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by(|a, b| {
+            let in_game = |summary: &RoomSummary| summary.phase != Phase::Lobby;
+            (in_game(a), &a.room_id).cmp(&(in_game(b), &b.room_id))
+        });
+        summaries.truncate(MAX_LISTED_ROOMS);
+        summaries
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            active_rooms: self.0.len(),
+            active_players: self.0.values().map(|room| room.players.len()).sum(),
+            games_in_progress: self
+                .0
+                .values()
+                .filter(|room| room.phase() != Phase::Lobby)
+                .count(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&RoomId, &mut Room)> {
+        self.0.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.0.values().map(|room| room.players.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn get_room(&self, room_id: &RoomId) -> Option<&Room> {
         self.0.get(room_id)
     }
 
-    pub fn try_create_room<'a>(&'a mut self, rng: &Rng) -> Option<VacantEntry<'a, String, Room>> {
+    pub fn get_room_mut(&mut self, room_id: &RoomId) -> Option<&mut Room> {
+        self.0.get_mut(room_id)
+    }
+
+    pub fn remove_room(&mut self, room_id: &RoomId) -> Option<Room> {
+        self.0.remove(room_id)
+    }
+
+    /// The entry for a new room, if one can be made with the code asked for or a random one that isn't taken
+    pub fn try_create_room(&mut self, code: NewCode) -> Option<VacantEntry<'_, RoomId, Room>> {
+        let (rng, codes) = match code {
+            NewCode::Requested(room_id) => {
+                return match self.0.entry(room_id) {
+                    Entry::Vacant(vacant) => Some(vacant),
+                    Entry::Occupied(_) => None,
+                }
+            }
+            NewCode::Random(rng, codes) => (rng, codes),
+        };
         let mut unique_room_id = None;
         // hacky way of getting around using mutable references in a loop
-        for _ in 0..MAX_ROOM_CREATION_ATTEMPTS {
-            let room_id = create_room_id(rng);
-            if self.0.get(&room_id).is_none() {
+        for _ in 0..codes.attempts {
+            let room_id = create_room_id(rng, codes.length);
+            if !self.0.contains_key(&room_id) {
                 unique_room_id = Some(room_id);
                 break;
             }
@@ -123,18 +1200,12 @@ impl RoomTable {
 
     /// Attempts to remove a player from a room. Returns a mutable reference to the room if successful and the room still exists
     /// (room may be evicted if it is empty)
-    pub fn try_remove_player<'a>(&'a mut self, name: &PlayerId, room: RoomId) -> Option<&mut Room> {
+    pub fn try_remove_player(&mut self, name: &PlayerId, room: RoomId) -> Option<&mut Room> {
         if let Entry::Occupied(mut room_entry) = self.0.entry(room) {
-            let player_index = find_index(&room_entry.get().names, name);
-            if let Some(index) = player_index {
-                let room = room_entry.get_mut();
-                room.names.remove(index);
-                room.senders.remove(index);
-            }
-
-            if room_entry.get().names.is_empty() {
+            let removed = room_entry.get_mut().remove(name).is_some();
+            if room_entry.get().players.is_empty() {
                 room_entry.remove_entry();
-            } else if player_index.is_some() {
+            } else if removed {
                 return Some(room_entry.into_mut());
             }
         }
@@ -149,296 +1220,6608 @@ impl From<RoomTable> for HashMap<RoomId, Room> {
     }
 }
 
-fn create_room_id(rng: &Rng) -> String {
-    let bytes = [rng.u8(..); ROOM_ID_BYTES];
-    base32::encode(base32::Alphabet::Crockford, &bytes)
+fn create_room_id(rng: &Rng, length: usize) -> RoomId {
+    std::iter::repeat_with(|| ROOM_CODE_ALPHABET[rng.usize(..ROOM_CODE_ALPHABET.len())] as char)
+        .take(length)
+        .collect()
 }
 
-#[derive(Clone)]
-struct SpyfallRepo {
-    // mapping of locations and their associated roles
-    roles: HashMap<String, Vec<String>>,
-    locations: Vec<String>,
+/// Where the code of a new room comes from
+pub enum NewCode<'a> {
+    // one the host asked for, already normalized and validated
+    Requested(RoomId),
+    Random(&'a Rng, RoomCodes),
 }
 
-impl SpyfallRepo {
-    fn new() -> Self {
-        let roles_json = include_str!("../roles.json");
-        let roles = serde_json::from_str::<HashMap<_, _>>(roles_json)
-            .expect("Failed to parse the roles dataset, check role.json");
-        let locations = roles.keys().cloned().collect();
-        Self { roles, locations }
+/// How the codes rooms are joined by are made up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomCodes {
+    // in characters, shorter codes are easier to read out but run out sooner
+    pub length: usize,
+    // how many codes are tried for a new room before giving up, short codes need more
+    pub attempts: usize,
+}
+
+impl Default for RoomCodes {
+    fn default() -> Self {
+        Self {
+            length: 8,
+            attempts: 5,
+        }
+    }
+}
+
+/// The room code as it was generated, however it was typed in.
+/// Crockford base32 ignores case and reads O as 0 and I or L as 1, and people add dashes and spaces to read codes out
+fn normalize_room_id(code: &str) -> RoomId {
+    code.chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        })
+        .collect()
+}
+
+/// The room code a host asked for as it will be joined by, if it could be one
+fn validate_requested_code(code: &str, blocked: &[String]) -> Result<RoomId, CodeErr> {
+    let code = normalize_room_id(code.trim());
+    let length = MIN_REQUESTED_CODE_LENGTH..=MAX_REQUESTED_CODE_LENGTH;
+    if !length.contains(&code.chars().count()) {
+        return Err(CodeErr::Length {
+            min: MIN_REQUESTED_CODE_LENGTH,
+            max: MAX_REQUESTED_CODE_LENGTH,
+        });
+    }
+    if !code.bytes().all(|c| ROOM_CODE_ALPHABET.contains(&c)) {
+        return Err(CodeErr::InvalidCharacter);
+    }
+    // compared the way they would be typed in, so a 0 can't stand in for an O
+    if blocked
+        .iter()
+        .any(|word| code.contains(&normalize_room_id(word)))
+    {
+        return Err(CodeErr::Blocked);
+    }
+    Ok(code)
+}
+
+/// Limits the broker enforces across every room
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    // rooms are refused beyond this, so abandoned rooms can't exhaust memory
+    pub max_rooms: usize,
+    // and players beyond this, across every room
+    pub max_players: usize,
+    // how many connected players a room needs to start a round
+    pub min_players: usize,
+    // players who haven't done anything for this long are flagged as idle to the room
+    pub idle_after: Duration,
+    // and removed from the room altogether after this long
+    pub remove_idle_after: Duration,
+    // how long a dropped player's seat is held for them to reconnect to
+    pub reconnect_grace: Duration,
+    // how often rooms are checked for idle players
+    pub idle_sweep_interval: Duration,
+    // rooms nobody has sent anything to for this long are closed
+    pub room_ttl: Duration,
+    // the same for rooms in the middle of a round, which may just be a long round of questioning
+    pub playing_room_ttl: Duration,
+    // how often rooms are checked against the ttl
+    pub room_sweep_interval: Duration,
+    // where the rooms are written down every snapshot_interval, and brought back from on start.
+    // Rooms only live as long as the broker when None
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    // how many rooms one address can create within the window, so a script can't fill the table
+    pub max_rooms_per_peer: usize,
+    pub room_creation_window: Duration,
+    // how many chat messages a player can send within the window, so one player can't flood the room
+    pub max_chats_per_player: usize,
+    pub chat_window: Duration,
+    // how long an invite lets people in for, and how many of them
+    pub invite_ttl: Duration,
+    pub invite_uses: u32,
+    pub room_codes: RoomCodes,
+    // words no code a host asks for may contain
+    pub blocked_codes: Vec<String>,
+    // what operators send along with their commands, which nobody can send when None
+    pub admin_token: Option<String>,
+    // makes room ids and deals reproducible, a random seed is used when None
+    pub seed: Option<u64>,
+    // the locations and roles rounds are dealt from
+    pub repo: SpyfallRepo,
+    // kept up to date as the broker goes, shared with whoever serves them
+    pub metrics: Arc<Metrics>,
+}
+
+impl BrokerConfig {
+    fn room_limit(&self) -> Limit {
+        Limit::new("max_rooms", self.max_rooms as u64)
+    }
+
+    fn player_limit(&self) -> Limit {
+        Limit::new("max_players", self.max_players as u64)
+    }
+
+    fn creation_limit(&self) -> Limit {
+        Limit::new("max_rooms_per_peer", self.max_rooms_per_peer as u64)
+    }
+
+    fn chat_limit(&self) -> Limit {
+        Limit::new("max_chats_per_player", self.max_chats_per_player as u64)
+    }
+
+    /// Refuses settings the broker can't work with
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.invite_uses == 0 {
+            return Err("invites need to let at least one player in");
+        }
+        Ok(())
     }
 
-    fn locations(&self) -> &[String] {
-        &self.locations
+    /// Every limit the broker enforces, for the protocol manifest
+    pub fn limits(&self) -> Vec<Limit> {
+        vec![
+            self.room_limit(),
+            self.player_limit(),
+            self.creation_limit(),
+            self.chat_limit(),
+            CHAT_LENGTH,
+            ROOM_CAPACITY,
+            USERNAME_LENGTH,
+        ]
     }
+}
 
-    fn roles(&self, location: &str) -> &[String] {
-        &self.roles[location]
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            max_rooms: 1000,
+            max_players: 10_000,
+            min_players: MIN_PLAYERS_TO_START_GAME,
+            idle_after: Duration::from_secs(3 * 60),
+            remove_idle_after: Duration::from_secs(10 * 60),
+            reconnect_grace: Duration::from_secs(2 * 60),
+            idle_sweep_interval: Duration::from_secs(15),
+            room_ttl: Duration::from_secs(30 * 60),
+            playing_room_ttl: Duration::from_secs(3 * 60 * 60),
+            room_sweep_interval: Duration::from_secs(60),
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(30),
+            max_rooms_per_peer: 10,
+            room_creation_window: Duration::from_secs(10 * 60),
+            max_chats_per_player: 8,
+            chat_window: Duration::from_secs(10),
+            invite_ttl: Duration::from_secs(60 * 60),
+            invite_uses: 1,
+            room_codes: RoomCodes::default(),
+            blocked_codes: Vec::new(),
+            admin_token: None,
+            seed: None,
+            repo: SpyfallRepo::new(),
+            metrics: Arc::default(),
+        }
     }
 }
 
-pub async fn broker_actor(client_listener: Receiver<ClientMsg>) -> AsyncResult<RoomTable> {
-    let rng = Rng::new();
+pub async fn broker_actor(
+    client_listener: Receiver<ClientMsg>,
+    config: BrokerConfig,
+) -> AsyncResult<RoomTable> {
+    let rng = config.seed.map_or_else(Rng::new, Rng::with_seed);
     let mut rooms = RoomTable::new();
-    let repo = SpyfallRepo::new();
-    while let Ok(msg) = client_listener.recv().await {
+    let repo = config.repo.clone();
+    let mut deadlines = Deadlines::new();
+    let mut index = PlayerIndex::new();
+    // rooms recently created from each address
+    let mut creations = SlidingWindow::new(config.room_creation_window);
+    // chat messages recently sent from each seat
+    let mut chats = SlidingWindow::new(config.chat_window);
+    deadlines.schedule(
+        Deadline::IdleSweep,
+        Instant::now() + config.idle_sweep_interval,
+    );
+    deadlines.schedule(
+        Deadline::RoomSweep,
+        Instant::now() + config.room_sweep_interval,
+    );
+    if let Some(path) = &config.snapshot_path {
+        match snapshot::load(path).await {
+            Ok(snapshots) => restore_rooms(snapshots, &mut rooms, &mut index, &rng),
+            Err(err) => log::warn!("Starting without the rooms in {}: {}", path.display(), err),
+        }
+        deadlines.schedule(
+            Deadline::Snapshot,
+            Instant::now() + config.snapshot_interval,
+        );
+    }
+    loop {
+        // a debug assertion, it costs a scan of every room
+        #[cfg(debug_assertions)]
+        index.assert_consistent(&rooms);
+        for deadline in deadlines.expired(Instant::now()) {
+            fire_deadline(
+                &mut rooms,
+                &mut index,
+                &config,
+                deadline,
+                &mut deadlines,
+                &mut creations,
+                &mut chats,
+            )
+            .await?;
+        }
+        config
+            .metrics
+            .set_population(rooms.len(), rooms.player_count());
+        let msg = match recv_until(&client_listener, deadlines.next()).await {
+            Some(Ok(msg)) => msg,
+            // every client sender has been dropped
+            Some(Err(_)) => break,
+            // a deadline is due
+            None => continue,
+        };
         match msg {
-            ClientMsg::Join(Join { room, name }, sender) => match room {
-                Some(room_id) => {
-                    println!("Adding player {} to room {}", name, room_id);
-                    let join_res = add_player(&mut rooms, room_id, name).await?;
-                    sender.send(join_res).await?;
-                }
-                // Create a new room
-                _ => {
-                    println!("Creating a new room for player: {}", name);
-                    let msg_back = rooms
-                        .try_create_room(&rng)
-                        .map(|vacant_room| {
-                            let room_id = vacant_room.key().clone();
-                            let (sender, rx) = channel::bounded(1);
-                            let players = vec![name];
-                            let senders = vec![sender];
-                            vacant_room.insert(Room {
-                                names: players.clone(),
-                                senders,
-                            });
-                            (Connected { room_id, players }, rx)
-                        })
-                        .ok_or(JoinErr::FailedToCreateRoom);
-                    sender.send(msg_back).await?;
-                }
-            },
-            ClientMsg::Room(room_msg) => match room_msg {
-                RoomMsg::Leave { name, room } => {
-                    println!("Removing {} from room {}", name, room);
-                    if let Some(room) = rooms.try_remove_player(&name, room) {
-                        send_room(&room.senders, BrokerMsg::Left(Arc::from(name))).await?;
+            ClientMsg::Join(
+                Join {
+                    room,
+                    name,
+                    settings,
+                    password,
+                    reconnect_token,
+                    requested_code,
+                    invite,
+                    peer,
+                    // checked by the client actor
+                    protocol_version: _,
+                },
+                sender,
+            ) => {
+                let population = (rooms.len(), index.player_count());
+                let join_res = match (room, normalize_username(&name)) {
+                    (_, Err(err)) => {
+                        log::debug!("Refused the username {:?}: {:?}", name, err);
+                        Err(JoinErr::InvalidUsername(err))
                     }
-                }
-                RoomMsg::Start { room } => {
-                    if let Some(room) = rooms.get_room(&room) {
-                        if room.names.len() < MIN_PLAYERS_TO_START_GAME {
-                            send_room(&room.senders, BrokerMsg::NotEnoughPlayers).await?;
-                        } else {
-                            let names = room.names.clone();
-                            let mut game_info = assign_roles(names, &repo, &rng);
-                            let location = Arc::from(game_info.location);
-                            let first = Arc::from(game_info.first);
-                            for (name, sender) in room.names.iter().zip(&room.senders) {
-                                let assignment = if *name == game_info.spy {
-                                    None
-                                } else {
-                                    let role = game_info
-                                        .player_roles
-                                        .remove(name)
-                                        .ok_or_else(|| format!("no role assigned to {}", name))?;
-                                    Some(Assignment {
-                                        role,
-                                        location: Arc::clone(&location),
-                                    })
+                    (Some(room_id), Ok(name)) => {
+                        log::info!("Adding player {} to room {}", name, room_id);
+                        // a seat held past its grace is let go now rather than at the next sweep, freeing the name
+                        let room_id = normalize_room_id(&room_id);
+                        let expired = rooms.get_room(&room_id).and_then(|room| {
+                            let held = &room.players[room.seat_like(&name)?].name;
+                            room.hold_expired(held, config.reconnect_grace)
+                                .then(|| held.clone())
+                        });
+                        if let Some(held) = expired {
+                            log::info!(
+                                "Letting go of the seat held for {} in room {}",
+                                held,
+                                room_id
+                            );
+                            let (room_id, reason) = (room_id.clone(), LeaveReason::Disconnected);
+                            remove_player(
+                                &mut rooms,
+                                &mut index,
+                                &mut deadlines,
+                                room_id,
+                                held,
+                                reason,
+                            )
+                            .await?;
+                        }
+                        // taking back a seat (or being told the name is taken) doesn't add anyone
+                        let new_seat = rooms.get_room(&room_id).is_some_and(|room| {
+                            match room.seat_like(&name) {
+                                None => true,
+                                Some(seat) => {
+                                    room.settings.auto_rename
+                                        && !room.owns_seat(seat, reconnect_token.as_deref())
+                                }
+                            }
+                        });
+                        let full = config.player_limit().check(index.player_count() as u64 + 1);
+                        match full {
+                            Err(info) if new_seat => Err(JoinErr::ServerFull(info)),
+                            _ => {
+                                let credentials = Credentials {
+                                    password,
+                                    reconnect_token,
+                                    invite,
                                 };
-                                sender
-                                    .send(BrokerMsg::Started(Start {
-                                        assignment,
-                                        first: Arc::clone(&first),
-                                    }))
+                                // made up front, the rng can't be held across the join's awaits
+                                let token = new_token(&rng, &index);
+                                let reconnect_token = new_reconnect_token(&rng);
+                                let joined = add_player(
+                                    &mut rooms,
+                                    &mut index,
+                                    room_id,
+                                    name,
+                                    credentials,
+                                    token,
+                                    reconnect_token,
+                                )
+                                .await?;
+                                if let (true, Ok((connected, _))) = (new_seat, &joined) {
+                                    let (room_id, name) = (&connected.room_id, &connected.name);
+                                    announce_join(
+                                        &mut rooms,
+                                        &mut index,
+                                        &mut deadlines,
+                                        room_id,
+                                        name,
+                                    )
                                     .await?;
+                                }
+                                joined
                             }
-                        };
+                        }
                     }
+                    // Create a new room
+                    (None, Ok(name)) => {
+                        log::info!("Creating a new room for player: {}", name);
+                        let limit = config.creation_limit();
+                        match peer.map(|peer| creations.try_record(peer, limit, Instant::now())) {
+                            Some(Err(info)) => {
+                                log::warn!("{:?} is creating rooms too quickly", peer);
+                                Err(JoinErr::RateLimited(info))
+                            }
+                            _ => {
+                                let request = RoomRequest {
+                                    settings,
+                                    password,
+                                    code: requested_code,
+                                };
+                                create_room(&mut rooms, &mut index, &rng, &config, name, request)
+                            }
+                        }
+                    }
+                };
+                if let Err(err) = &join_res {
+                    config.metrics.join_failed(err.kind());
+                }
+                if rooms.len() > population.0 {
+                    warn_near_capacity("rooms", rooms.len(), config.max_rooms);
+                }
+                if index.player_count() > population.1 {
+                    warn_near_capacity("players", index.player_count(), config.max_players);
+                }
+                deliver(&sender, join_res);
+            }
+            ClientMsg::Resume(reconnect_token, sender) => {
+                let seat = index
+                    .seat_of(&reconnect_token)
+                    .map(|(room_id, token)| (room_id.clone(), token));
+                let resumed = match seat {
+                    None => Err(JoinErr::NoSuchSeat),
+                    Some((room_id, held_by)) => {
+                        let room = rooms.get_room(&room_id).expect("indexed rooms exist");
+                        let seat = room.seat_with(held_by).expect("indexed seats exist");
+                        let name = room.players[seat].name.clone();
+                        // the same grace a join by name gets, an expired seat is let go of rather than resumed
+                        if room.hold_expired(&name, config.reconnect_grace) {
+                            let reason = LeaveReason::Disconnected;
+                            remove_player(
+                                &mut rooms,
+                                &mut index,
+                                &mut deadlines,
+                                room_id,
+                                name,
+                                reason,
+                            )
+                            .await?;
+                            Err(JoinErr::NoSuchSeat)
+                        } else {
+                            log::info!("Resuming {} in room {}", name, room_id);
+                            let token = new_token(&rng, &index);
+                            let room = rooms.get_room_mut(&room_id).expect("just looked up");
+                            Ok(retake_seat(room, &mut index, &room_id, seat, token).await?)
+                        }
+                    }
+                };
+                if let Err(err) = &resumed {
+                    config.metrics.join_failed(err.kind());
+                }
+                deliver(&sender, resumed);
+            }
+            ClientMsg::Admin(AdminMsg { admin_token, cmd }, sender) => {
+                let authorized = config
+                    .admin_token
+                    .as_deref()
+                    .is_some_and(|expected| same_secret(expected, &admin_token));
+                let res = match cmd {
+                    _ if !authorized => {
+                        log::warn!("Refused an admin command, the admin token is wrong");
+                        Err(AdminErr::Unauthorized)
+                    }
+                    AdminCmd::ForceClose { room } => {
+                        let room_id = normalize_room_id(&room);
+                        if rooms.get_room(&room_id).is_none() {
+                            Err(AdminErr::NoSuchRoom)
+                        } else {
+                            log::info!("Force closing room {}", room_id);
+                            let reason = CloseReason::ClosedByAdmin;
+                            close_room(&mut rooms, &mut index, &mut deadlines, &room_id, reason)
+                                .await?;
+                            Ok(AdminReply::Closed)
+                        }
+                    }
+                    AdminCmd::FindPlayer { name } => {
+                        let mut found: Vec<RoomId> =
+                            index.rooms_with_name(&name).cloned().collect();
+                        found.sort();
+                        Ok(AdminReply::Found { rooms: found })
+                    }
+                };
+                deliver(&sender, res);
+            }
+            ClientMsg::ListRooms(sender) => {
+                deliver(&sender, rooms.summaries());
+            }
+            ClientMsg::GetLimits(sender) => {
+                deliver(&sender, config.limits());
+            }
+            ClientMsg::Stats(sender) => {
+                deliver(&sender, rooms.stats());
+            }
+            ClientMsg::GetLocations(sender) => {
+                deliver(
+                    &sender,
+                    repo.locations().iter().map(|l| Arc::from(&**l)).collect(),
+                );
+            }
+            ClientMsg::Room(id, room_msg) => {
+                if let Some((room_id, name)) = room_msg.sender() {
+                    if let Some(room) = rooms.get_room_mut(room_id) {
+                        // e.g. a connection that was replaced by a reconnect, it no longer speaks for the seat
+                        if index.room_of(id) != Some(room_id) || room.token_of(name) != Some(id) {
+                            log::debug!(
+                                "Ignoring a message for {} in {} from another seat",
+                                name,
+                                room_id
+                            );
+                            continue;
+                        }
+                        room.touch(name);
+                    }
+                }
+                let room_id = room_msg.sender().map(|(room_id, _)| room_id.clone());
+                match room_msg {
+                    RoomMsg::Leave { name, room } => {
+                        log::info!("Removing {} from room {}", name, room);
+                        let reason = LeaveReason::Voluntary;
+                        remove_player(&mut rooms, &mut index, &mut deadlines, room, name, reason)
+                            .await?;
+                    }
+                    RoomMsg::TransferHost { room, from, to } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            transfer_host(room, from, to).await?;
+                        }
+                    }
+                    RoomMsg::Rename { room, from, to } => {
+                        if let Some(room_entry) = rooms.get_room_mut(&room) {
+                            rename_player(room_entry, &room, &mut index, from, to).await?;
+                        }
+                    }
+                    RoomMsg::Kick { room, by, target } => {
+                        log::info!("{} is kicking {} from room {}", by, target, room);
+                        kick_player(&mut rooms, &mut index, &mut deadlines, room, by, target)
+                            .await?;
+                    }
+                    RoomMsg::CreateInvite { room, name } => match rooms.get_room_mut(&room) {
+                        Some(room_ref) if *room_ref.host() != name => {
+                            reply(room_ref, &name, RoomErr::NotTheHost).await?
+                        }
+                        Some(room_ref) => {
+                            let now = Instant::now();
+                            // the expired ones go first, and a host at the cap gives up their oldest for the new one
+                            room_ref.invites.retain(|_, invite| invite.expires_at > now);
+                            if room_ref.invites.len() >= MAX_OUTSTANDING_INVITES {
+                                let oldest = room_ref
+                                    .invites
+                                    .iter()
+                                    .min_by_key(|(_, invite)| invite.expires_at)
+                                    .map(|(code, _)| code.clone());
+                                if let Some(oldest) = oldest {
+                                    room_ref.invites.remove(&oldest);
+                                }
+                            }
+                            let code = new_reconnect_token(&rng);
+                            let invite = Invite {
+                                expires_at: now + config.invite_ttl,
+                                uses_left: config.invite_uses,
+                            };
+                            room_ref.invites.insert(code.clone(), invite);
+                            let msg = BrokerMsg::Invite {
+                                code,
+                                expires_in_secs: config.invite_ttl.as_secs(),
+                                uses: config.invite_uses,
+                            };
+                            reply(room_ref, &name, msg).await?;
+                        }
+                        None => {}
+                    },
+                    RoomMsg::CloseRoom { room, name } => match rooms.get_room(&room) {
+                        Some(room_ref) if *room_ref.host() != name => {
+                            reply(room_ref, &name, RoomErr::NotTheHost).await?
+                        }
+                        Some(_) => {
+                            log::info!("{} is closing room {}", name, room);
+                            let reason = CloseReason::ClosedByHost;
+                            close_room(&mut rooms, &mut index, &mut deadlines, &room, reason)
+                                .await?;
+                        }
+                        None => {}
+                    },
+                    RoomMsg::Disconnect { name, room } => {
+                        log::info!("{} disconnected from room {}", name, room);
+                        if let Some(room_ref) = rooms.get_room_mut(&room) {
+                            let seated = room_ref.is_seated(&name);
+                            if seated && !room_ref.disconnected.contains_key(&name) {
+                                hold_seat(&room, room_ref, name, &mut deadlines).await?;
+                            }
+                        }
+                    }
+                    RoomMsg::SetReady { room, name, ready } => {
+                        if let Some(room_ref) = rooms.get_room_mut(&room) {
+                            if room_ref.set_ready(&name, ready) {
+                                let key = StateKey {
+                                    player: name,
+                                    kind: StateKind::Ready,
+                                };
+                                broadcast_state(room, room_ref, key, &mut deadlines).await?;
+                            }
+                        }
+                    }
+                    RoomMsg::Start {
+                        room: room_id,
+                        name,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room_id) {
+                            if ready_to_deal(&room_id, room, &name, &mut deadlines).await? {
+                                match deal_round(room, Forced::default(), &repo, &rng) {
+                                    Ok(game_info) => {
+                                        start_round(&room_id, room, game_info, &mut deadlines)
+                                            .await?;
+                                        config.metrics.game_started();
+                                    }
+                                    Err(need) => {
+                                        let msg = BrokerMsg::NotEnoughRoles { need };
+                                        reply(room, &name, msg).await?
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    #[cfg(feature = "debug-controls")]
+                    RoomMsg::DebugStart {
+                        room: room_id,
+                        name,
+                        spy,
+                        location,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room_id) {
+                            if ready_to_deal(&room_id, room, &name, &mut deadlines).await? {
+                                match deal_round(room, Forced { spy, location }, &repo, &rng) {
+                                    Ok(game_info) => {
+                                        start_round(&room_id, room, game_info, &mut deadlines)
+                                            .await?;
+                                        config.metrics.game_started();
+                                    }
+                                    Err(need) => {
+                                        let msg = BrokerMsg::NotEnoughRoles { need };
+                                        reply(room, &name, msg).await?
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    RoomMsg::GuessLocation {
+                        room,
+                        name,
+                        location,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            guess_location(room, name, location).await?;
+                        }
+                    }
+                    RoomMsg::SetCategory {
+                        room,
+                        name,
+                        category,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            set_category(room, name, category, &repo).await?;
+                        }
+                    }
+                    RoomMsg::Pause {
+                        room: room_id,
+                        name,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room_id) {
+                            pause_round(room, name).await?;
+                        }
+                    }
+                    RoomMsg::Resume {
+                        room: room_id,
+                        name,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room_id) {
+                            resume_round(&room_id, room, name, &mut deadlines).await?;
+                        }
+                    }
+                    RoomMsg::RequestExtension { room, name } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            request_extension(room, name).await?;
+                        }
+                    }
+                    RoomMsg::VoteExtension {
+                        room: room_id,
+                        name,
+                        approve,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room_id) {
+                            let voting = room.playing().any(|player| *player == name);
+                            match room.round.as_mut().and_then(|r| r.extension_vote.as_mut()) {
+                                Some(vote) if voting => {
+                                    vote.cast(name, approve);
+                                    settle_extension_vote(&room_id, room, &mut deadlines).await?;
+                                }
+                                _ => reply(room, &name, RoomErr::NoVoteInProgress).await?,
+                            }
+                        }
+                    }
+                    RoomMsg::MarkLocation {
+                        room,
+                        name,
+                        location,
+                        crossed,
+                    } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            if !repo.has_location(&location) {
+                                log::debug!("{} tried to mark unknown location {}", name, location);
+                            } else if let Some(sender) = room.sender_for(&name).cloned() {
+                                let marks = room.marks.entry(name).or_default();
+                                if crossed {
+                                    marks.insert(location.clone());
+                                } else {
+                                    marks.remove(&location);
+                                }
+                                let location = Arc::from(location);
+                                let msg = BrokerMsg::LocationMarked { location, crossed };
+                                deliver(&sender, msg);
+                            }
+                        }
+                    }
+                    RoomMsg::Chat { room, name, text } => {
+                        if let Some(room) = rooms.get_room_mut(&room) {
+                            let limit = config.chat_limit();
+                            relay_chat(room, id, name, text, &mut chats, limit).await?;
+                        }
+                    }
+                    RoomMsg::GetScores { room, name } => {
+                        if let Some(room) = rooms.get_room(&room) {
+                            reply(room, &name, BrokerMsg::Scores(room.score_table())).await?;
+                        }
+                    }
+                    RoomMsg::GetLocations { room, name } => {
+                        if let Some(room) = rooms.get_room(&room) {
+                            let pool = room.location_pool(&repo);
+                            let locations = pool.iter().map(|l| Arc::from(&**l)).collect();
+                            reply(room, &name, BrokerMsg::Locations(locations)).await?;
+                        }
+                    }
+                    RoomMsg::GetRoomInfo { room, name } => {
+                        if let Some(room) = rooms.get_room(&room) {
+                            reply(room, &name, BrokerMsg::RoomInfo(room.info())).await?;
+                        }
+                    }
+                    RoomMsg::GetAssignment { room, name } => {
+                        if let Some(room) = rooms.get_room(&room) {
+                            let msg = match &room.round {
+                                None => BrokerMsg::Rejected(RoomErr::NoRoundInProgress),
+                                Some(round) => round.dealt_to(&name).map_or(
+                                    BrokerMsg::Rejected(RoomErr::NotDealtIn),
+                                    BrokerMsg::Started,
+                                ),
+                            };
+                            reply(room, &name, msg).await?;
+                        }
+                    }
+                    RoomMsg::GetHistory { room, name } => {
+                        if let Some(room) = rooms.get_room(&room) {
+                            if let Some(sender) = room.sender_for(&name) {
+                                let history = room.history.iter().cloned().collect();
+                                deliver(sender, BrokerMsg::History(history));
+                            }
+                        }
+                    }
+                }
+                let missed = room_id
+                    .as_ref()
+                    .and_then(|room_id| rooms.get_room_mut(room_id))
+                    .map(|room| std::mem::take(&mut room.unreachable));
+                if let (Some(room_id), Some(missed)) = (room_id, missed) {
+                    let_go_of_unreachable(&mut rooms, &mut index, &mut deadlines, &room_id, missed)
+                        .await?;
+                }
+            }
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    index.assert_consistent(&rooms);
+    Ok(rooms)
+}
+
+/// Gets the room ready for a new round, returning whether one can be dealt: there must be enough
+/// players, and no round already being played
+async fn ready_to_deal(
+    room_id: &RoomId,
+    room: &mut Room,
+    name: &PlayerId,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<bool> {
+    // a double-click on start must not deal everyone new roles
+    if room.round.is_some() {
+        reply(room, name, BrokerMsg::AlreadyStarted).await?;
+        return Ok(false);
+    }
+    room.last_activity = Instant::now();
+    // everyone should see the true lobby state before roles are dealt
+    flush_states(room_id, room, deadlines).await?;
+    let have = room.connected_players().count();
+    let need = room.min_players;
+    if have < need {
+        room.broadcast(BrokerMsg::NotEnoughPlayers { have, need });
+        return Ok(false);
+    }
+    if room.settings.require_ready {
+        let waiting = room
+            .connected_players()
+            .filter(|player| !room.ready.contains(*player))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !waiting.is_empty() {
+            reply(room, name, RoomErr::NotEveryoneReady(waiting)).await?;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Deals the room's next round to everyone connected, at a location from the room's category.
+/// The spy is drawn from any players tied for the match win.
+/// Refused with how many roles a location would need, when the room deals distinct roles and none has enough
+fn deal_round(
+    room: &Room,
+    forced: Forced,
+    repo: &SpyfallRepo,
+    rng: &Rng,
+) -> Result<GameInfo, usize> {
+    let forced_location = forced
+        .location
+        .filter(|location| repo.has_location(location));
+    let mut locations = match &forced_location {
+        Some(forced) => std::slice::from_ref(forced),
+        None => room.location_pool(repo),
+    };
+    let players = room.connected_players().cloned().collect::<Vec<_>>();
+    let enough;
+    if room.settings.distinct_roles {
+        // the spy needs no role, unless the round turns out to be a decoy
+        let need = players.len() - usize::from(!room.settings.decoy_rounds());
+        enough = locations
+            .iter()
+            .filter(|location| repo.roles(location).len() >= need)
+            .cloned()
+            .collect::<Vec<_>>();
+        if enough.is_empty() {
+            return Err(need);
+        }
+        locations = &enough;
+    }
+    let tied = match forced.spy {
+        Some(spy) if players.contains(&spy) => vec![spy],
+        _ => room
+            .tie_break
+            .iter()
+            .filter(|name| players.contains(name))
+            .cloned()
+            .collect::<Vec<_>>(),
+    };
+    let spy_pool = Some(&tied[..]).filter(|tied| !tied.is_empty());
+    Ok(assign_roles(
+        players,
+        spy_pool,
+        locations,
+        &room.recent_locations,
+        repo,
+        rng,
+        &room.settings,
+    ))
+}
+
+/// Sends each player in the room their part of the dealt game
+async fn start_round(
+    room_id: &RoomId,
+    room: &mut Room,
+    mut game_info: GameInfo,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    // notes from the last round are no use in the next one
+    room.marks.clear();
+    room.remember_location(&game_info.location);
+    room.rounds_started += 1;
+
+    let location = Arc::from(game_info.location.as_str());
+    let first = Arc::from(game_info.first.as_str());
+    let ends_at = room
+        .settings
+        .round_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let colluding = room.settings.spies_collude && game_info.spy_cover.is_none();
+    let mut dealt = HashMap::new();
+    let mut missed = vec![];
+    for Player { name, sender, .. } in &room.players {
+        // a player whose seat is still held from the last round sits this one out
+        if room.disconnected.contains_key(name) {
+            continue;
+        }
+        let is_spy = game_info.spies.contains(name);
+        let partner = game_info
+            .spies
+            .iter()
+            .find(|spy| colluding && is_spy && *spy != name)
+            .map(|spy| Arc::from(spy.as_str()));
+        let assignment = if is_spy {
+            game_info.spy_cover.clone()
+        } else {
+            let role = game_info
+                .player_roles
+                .remove(name)
+                .ok_or_else(|| format!("no role assigned to {}", name))?;
+            Some(Assignment {
+                role,
+                location: Arc::clone(&location),
+            })
+        };
+        let start = Start {
+            round: room.rounds_started,
+            assignment,
+            first: Arc::clone(&first),
+            remaining_secs: ends_at.map(remaining_secs),
+            partner,
+        };
+        dealt.insert(name.clone(), start.clone());
+        if !deliver(sender, BrokerMsg::Started(start)) {
+            missed.push(name.clone());
+        }
+    }
+    room.unreachable.extend(missed);
+
+    if let Some(ends_at) = ends_at {
+        schedule_round_timer(room_id, ends_at, deadlines);
+    }
+    room.round = Some(Round {
+        number: room.rounds_started,
+        location: game_info.location,
+        spies: game_info.spies,
+        started_at: Instant::now(),
+        ends_at,
+        extension_requested: false,
+        extension_vote: None,
+        paused_at: None,
+        dealt,
+    });
+    Ok(())
+}
+
+/// Schedules the end of a round running until `ends_at`, along with the timer updates on the way
+fn schedule_round_timer(room_id: &RoomId, ends_at: Instant, deadlines: &mut Deadlines<Deadline>) {
+    deadlines.schedule(Deadline::RoundOver(room_id.clone()), ends_at);
+    match next_tick(ends_at, Instant::now()) {
+        Some(at) => deadlines.schedule(Deadline::TimerTick(room_id.clone()), at),
+        None => deadlines.cancel(&Deadline::TimerTick(room_id.clone())),
+    }
+}
+
+/// When the next timer update is due after `now`. Updates come every `TIMER_TICK_INTERVAL`
+/// until the final countdown, which falls on each whole second left
+fn next_tick(ends_at: Instant, now: Instant) -> Option<Instant> {
+    let remaining = ends_at.checked_duration_since(now)?;
+    let countdown = Duration::from_secs(FINAL_COUNTDOWN_SECS);
+    if remaining > countdown {
+        return Some((now + TIMER_TICK_INTERVAL).min(ends_at - countdown));
+    }
+    let whole = remaining.as_secs();
+    let at = ends_at - Duration::from_secs(whole);
+    if whole > 0 && at > now {
+        Some(at)
+    } else if whole > 1 {
+        Some(ends_at - Duration::from_secs(whole - 1))
+    } else {
+        // the round ending is the last word
+        None
+    }
+}
+
+/// Tells the room how long its round has left, and schedules the next update
+async fn tick_timer(
+    room_id: &RoomId,
+    room: &mut Room,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    // paused rounds are rescheduled when they resume, and untimed ones have nothing to tell
+    let ends_at = match &room.round {
+        Some(round) if round.paused_at.is_none() => match round.ends_at {
+            Some(ends_at) => ends_at,
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+    let now = Instant::now();
+    // ticks are due on whole seconds, and may fire a little late
+    let remaining_secs =
+        (ends_at.saturating_duration_since(now) + Duration::from_millis(500)).as_secs();
+    if remaining_secs > FINAL_COUNTDOWN_SECS {
+        room.broadcast(BrokerMsg::TimerTick { remaining_secs });
+    } else if remaining_secs > 0 {
+        // a client that is behind can miss a number, rather than being cut off over the countdown
+        for sender in room.senders() {
+            let _ = sender.try_send(BrokerMsg::FinalCountdown { remaining_secs });
+        }
+    }
+    if let Some(at) = next_tick(ends_at, now) {
+        deadlines.schedule(Deadline::TimerTick(room_id.clone()), at);
+    }
+    Ok(())
+}
+
+fn remaining_secs(ends_at: Instant) -> u64 {
+    ends_at.saturating_duration_since(Instant::now()).as_secs()
+}
+
+/// Keeps the seat of a player whose connection dropped, so they can come back to it (and their role) with their reconnect token
+async fn hold_seat(
+    room_id: &RoomId,
+    room: &mut Room,
+    name: PlayerId,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    room.disconnected.insert(name.clone(), Instant::now());
+    room.broadcast(BrokerMsg::Disconnected(Arc::from(name.as_str())));
+    room.broadcast(room.roster());
+    // the player is out of the electorate until they come back, which may settle the vote
+    if let Some(vote) = room.round.as_mut().and_then(|r| r.extension_vote.as_mut()) {
+        vote.withdraw(&name);
+        settle_extension_vote(room_id, room, deadlines).await?;
+    }
+    Ok(())
+}
+
+/// Removes a player from the room, telling everyone left why and cleaning up after them
+async fn remove_player(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    deadlines: &mut Deadlines<Deadline>,
+    room_id: RoomId,
+    name: PlayerId,
+    reason: LeaveReason,
+) -> AsyncResult<()> {
+    let token = rooms.get_room(&room_id).and_then(|r| r.token_of(&name));
+    if let Some(token) = token {
+        index.remove(token, &name);
+    }
+    let was_host = rooms
+        .get_room(&room_id)
+        .is_some_and(|room| *room.host() == name);
+    if let Some(room) = rooms.try_remove_player(&name, room_id.clone()) {
+        room.last_activity = Instant::now();
+        let left = BrokerMsg::Left {
+            name: Arc::from(name.as_str()),
+            reason,
+        };
+        room.broadcast(left);
+        room.broadcast(room.roster());
+        if was_host {
+            let host = Arc::from(room.host().as_str());
+            room.broadcast(BrokerMsg::HostChanged { host });
+        }
+        abort_unplayable_round(&room_id, room, &name, deadlines).await?;
+        // the player's ballot no longer counts, which may settle the vote
+        if let Some(vote) = room.round.as_mut().and_then(|r| r.extension_vote.as_mut()) {
+            vote.withdraw(&name);
+            settle_extension_vote(&room_id, room, deadlines).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Hands the room over to `to` on behalf of the host
+async fn transfer_host(room: &mut Room, from: PlayerId, to: PlayerId) -> AsyncResult<()> {
+    if *room.host() != from {
+        return reply(room, &from, RoomErr::NotTheHost).await;
+    }
+    if !room.is_seated(&to) {
+        return reply(room, &from, RoomErr::NoSuchPlayer).await;
+    }
+    if to == from {
+        return Ok(());
+    }
+    room.host = to;
+    let host = Arc::from(room.host().as_str());
+    room.broadcast(BrokerMsg::HostChanged { host });
+    Ok(())
+}
+
+/// Changes a player's name in the lobby, keeping their seat, score and everything else keyed by it
+async fn rename_player(
+    room: &mut Room,
+    room_id: &RoomId,
+    index: &mut PlayerIndex,
+    from: PlayerId,
+    to: String,
+) -> AsyncResult<()> {
+    let seat = match room.seat_of(&from) {
+        Some(seat) => seat,
+        None => return Ok(()),
+    };
+    if room.round.is_some() {
+        return reply(room, &from, RoomErr::RoundInProgress).await;
+    }
+    let to = match normalize_username(&to) {
+        Ok(to) => to,
+        Err(err) => return reply(room, &from, RoomErr::InvalidUsername(err)).await,
+    };
+    // changing the casing of your own name is fine
+    if room.seat_like(&to).is_some_and(|other| other != seat) {
+        return reply(room, &from, RoomErr::UsernameTaken).await;
+    }
+    if to == from {
+        return Ok(());
+    }
+    log::info!("{} is now {} in room {}", from, to, room_id);
+    let token = room.players[seat].id;
+    index.remove(token, &from);
+    index.insert(token, &to, room_id, &room.reconnect_tokens[&from]);
+    room.rename(seat, &to);
+    let msg = BrokerMsg::Renamed {
+        from: Arc::from(from),
+        to: Arc::from(to),
+    };
+    room.broadcast(msg);
+    room.broadcast(room.roster());
+    Ok(())
+}
+
+/// Removes `target` from the room on behalf of the host, the same way as if they had left
+async fn kick_player(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    deadlines: &mut Deadlines<Deadline>,
+    room_id: RoomId,
+    by: PlayerId,
+    target: PlayerId,
+) -> AsyncResult<()> {
+    let room = match rooms.get_room(&room_id) {
+        Some(room) => room,
+        None => return Ok(()),
+    };
+    if *room.host() != by {
+        return reply(room, &by, RoomErr::NotTheHost).await;
+    }
+    if !room.is_seated(&target) {
+        return reply(room, &by, RoomErr::NoSuchPlayer).await;
+    }
+    // the last thing the kicked player hears, their client closes the websocket on it
+    let kicked = BrokerMsg::Kicked {
+        target: Arc::from(target.as_str()),
+        by: Arc::from(by),
+    };
+    reply(room, &target, kicked).await?;
+    remove_player(
+        rooms,
+        index,
+        deadlines,
+        room_id,
+        target,
+        LeaveReason::Kicked,
+    )
+    .await
+}
+
+/// Ends the round early if it can't be played without `gone`
+async fn abort_unplayable_round(
+    room_id: &RoomId,
+    room: &mut Room,
+    gone: &PlayerId,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    let (reason, winner) = match &room.round {
+        Some(round) if round.spies.contains(gone) => (AbortReason::SpyLeft, Some(Winner::NonSpies)),
+        // watchers and held seats can't play the round out
+        Some(_) if room.playing().count() < room.min_players => {
+            (AbortReason::NotEnoughPlayers, None)
+        }
+        _ => return Ok(()),
+    };
+    deadlines.cancel(&Deadline::RoundOver(room_id.clone()));
+    if let Some(summary) = room.end_round(winner) {
+        announce_round_end(room, BrokerMsg::GameAborted { reason, summary }).await?;
+    }
+    settle_match(room).await
+}
+
+/// Flags players who have gone quiet to the rest of their room, and removes those who stay that way
+async fn sweep_idle_players(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    config: &BrokerConfig,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    let mut to_remove = vec![];
+    for (room_id, room) in rooms.iter_mut() {
+        // a client actor that went without a word (or was cut off for falling behind) is a dropped connection
+        let dead = room
+            .players
+            .iter()
+            .filter(|player| {
+                player.sender.is_closed() && !room.disconnected.contains_key(&player.name)
+            })
+            .map(|player| player.name.clone())
+            .collect::<Vec<_>>();
+        for name in dead {
+            log::info!(
+                "Holding the seat of {} in room {}, their client is gone",
+                name,
+                room_id
+            );
+            hold_seat(room_id, room, name, deadlines).await?;
+        }
+        let gone = room
+            .disconnected
+            .keys()
+            .filter(|name| room.hold_expired(name, config.reconnect_grace))
+            .map(|name| (room_id.clone(), name.clone(), LeaveReason::Disconnected));
+        to_remove.extend(gone);
+        if room.round.is_none() && !room.settings.afk_in_lobby {
+            continue;
+        }
+        let mut newly_idle = vec![];
+        for (name, activity) in room.activity.iter_mut() {
+            let quiet_for = activity.last_seen.elapsed();
+            if quiet_for >= config.remove_idle_after {
+                to_remove.push((room_id.clone(), name.clone(), LeaveReason::Idle));
+            } else if quiet_for >= config.idle_after && !activity.idle {
+                activity.idle = true;
+                newly_idle.push(name.clone());
+            }
+        }
+        for name in newly_idle {
+            let msg = BrokerMsg::PlayerIdle {
+                name: Arc::from(name),
+            };
+            room.broadcast(msg);
+        }
+    }
+    for (room_id, name, reason) in to_remove {
+        log::info!("Removing {} from room {}: {:?}", name, room_id, reason);
+        remove_player(rooms, index, deadlines, room_id, name, reason).await?;
+    }
+    Ok(())
+}
+
+/// Closes rooms that have gone unused for longer than the ttl, e.g. because every player
+/// disconnected without the broker hearing about it
+async fn sweep_stale_rooms(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    config: &BrokerConfig,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    let stale = rooms
+        .iter()
+        .filter(|(_, room)| {
+            let ttl = match room.round {
+                Some(_) => config.playing_room_ttl,
+                None => config.room_ttl,
+            };
+            room.last_activity.elapsed() >= ttl
+        })
+        .map(|(room_id, _)| room_id.clone())
+        .collect::<Vec<_>>();
+    for room_id in stale {
+        log::info!("Closing room {} after it went unused", room_id);
+        close_room(rooms, index, deadlines, &room_id, CloseReason::Idle).await?;
+    }
+    Ok(())
+}
+
+/// Removes the room along with everyone in it, telling them why, even in the middle of a round
+async fn close_room(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    deadlines: &mut Deadlines<Deadline>,
+    room_id: &RoomId,
+    reason: CloseReason,
+) -> AsyncResult<()> {
+    if let Some(mut room) = rooms.remove_room(room_id) {
+        for (name, token) in room.members() {
+            index.remove(token, name);
+        }
+        deadlines.cancel(&Deadline::RoundOver(room_id.clone()));
+        deadlines.cancel(&Deadline::TimerTick(room_id.clone()));
+        // dropping the room's senders then ends every client's room loop
+        room.broadcast(BrokerMsg::RoomClosed { reason });
+    }
+    Ok(())
+}
+
+/// Sends a message to a single player of the room, if they are still in it
+async fn reply(room: &Room, name: &PlayerId, msg: impl Into<BrokerMsg>) -> AsyncResult<()> {
+    if let Some(sender) = room.sender_for(name) {
+        deliver(sender, msg.into());
+    }
+    Ok(())
+}
+
+/// Switches the location pack the room's next rounds are dealt from, on behalf of the host
+async fn set_category(
+    room: &mut Room,
+    name: PlayerId,
+    category: Option<String>,
+    repo: &SpyfallRepo,
+) -> AsyncResult<()> {
+    if *room.host() != name {
+        return reply(room, &name, RoomErr::NotTheHost).await;
+    }
+    if let Some(category) = &category {
+        if repo.locations_in(category).is_none() {
+            return reply(room, &name, RoomErr::NoSuchCategory).await;
+        }
+    }
+    let msg = BrokerMsg::CategoryChanged {
+        category: category.as_deref().map(Arc::from),
+    };
+    room.category = category;
+    room.broadcast(msg);
+    Ok(())
+}
+
+/// Freezes the round timer on behalf of the host
+async fn pause_round(room: &mut Room, name: PlayerId) -> AsyncResult<()> {
+    if *room.host() != name {
+        return reply(room, &name, RoomErr::NotTheHost).await;
+    }
+    match room.round.as_mut() {
+        None => reply(room, &name, RoomErr::NoRoundInProgress).await,
+        Some(round) if round.paused_at.is_some() => reply(room, &name, RoomErr::RoundPaused).await,
+        Some(round) => {
+            round.paused_at = Some(Instant::now());
+            let msg = BrokerMsg::Paused {
+                by: Arc::from(name),
+            };
+            room.broadcast(msg);
+            Ok(())
+        }
+    }
+}
+
+/// Restarts a paused round timer with the time it had left
+async fn resume_round(
+    room_id: &RoomId,
+    room: &mut Room,
+    name: PlayerId,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    if *room.host() != name {
+        return reply(room, &name, RoomErr::NotTheHost).await;
+    }
+    let round = match room.round.as_mut() {
+        None => return reply(room, &name, RoomErr::NoRoundInProgress).await,
+        Some(round) => round,
+    };
+    let paused_at = match round.paused_at.take() {
+        None => return reply(room, &name, RoomErr::RoundNotPaused).await,
+        Some(paused_at) => paused_at,
+    };
+    if let Some(ends_at) = round.ends_at.as_mut() {
+        *ends_at += paused_at.elapsed();
+        schedule_round_timer(room_id, *ends_at, deadlines);
+    }
+    let msg = BrokerMsg::Resumed {
+        remaining_secs: round.remaining_secs(),
+    };
+    room.broadcast(msg);
+    Ok(())
+}
+
+/// A spy's one guess at the location, which decides the round either way
+async fn guess_location(room: &mut Room, name: PlayerId, location: String) -> AsyncResult<()> {
+    if !room.is_seated(&name) {
+        return Ok(());
+    }
+    let round = match room.round.as_ref() {
+        Some(round) => round,
+        // the round a guess decides is over by the time a second one arrives
+        None if room
+            .guessed_in_round
+            .is_some_and(|r| r == room.rounds_started) =>
+        {
+            return reply(room, &name, RoomErr::AlreadyGuessed).await;
+        }
+        None => return reply(room, &name, RoomErr::NoRoundInProgress).await,
+    };
+    if !round.spies.contains(&name) {
+        return reply(room, &name, RoomErr::NotTheSpy).await;
+    }
+    if round.paused_at.is_some() {
+        return reply(room, &name, RoomErr::RoundPaused).await;
+    }
+    let winner = if round.location == location {
+        Winner::Spy
+    } else {
+        Winner::NonSpies
+    };
+    room.guessed_in_round = Some(round.number);
+    if let Some(summary) = room.end_round(Some(winner)) {
+        announce_round_end(room, BrokerMsg::Revealed(summary)).await?;
+    }
+    settle_match(room).await
+}
+
+/// Tells the room how its round ended, then the roster now that nobody is watching rather than playing
+async fn announce_round_end(room: &mut Room, msg: BrokerMsg) -> AsyncResult<()> {
+    room.broadcast(msg);
+    room.broadcast(room.roster());
+    Ok(())
+}
+
+/// Ends the match once a single player has reached the room's target score.
+/// Players tied for the lead at the target go into a tie-break round instead
+async fn settle_match(room: &mut Room) -> AsyncResult<()> {
+    let target = match room.settings.target_score {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let score = |name: &PlayerId| room.scores.get(name).copied().unwrap_or(0);
+    let best = room.names().map(score).max().unwrap_or(0);
+    if best < target {
+        return Ok(());
+    }
+    let mut leaders = room
+        .names()
+        .filter(|name| score(name) == best)
+        .cloned()
+        .collect::<Vec<_>>();
+    if leaders.len() > 1 {
+        room.tie_break = leaders;
+        return Ok(());
+    }
+    let msg = BrokerMsg::MatchOver {
+        winner: Arc::from(leaders.remove(0)),
+        scores: room.score_table(),
+    };
+    room.scores.clear();
+    room.tie_break.clear();
+    room.broadcast(msg);
+    Ok(())
+}
+
+/// Passes a chat message on to the whole room, sender included so they see where it landed,
+/// as long as it comes from one of its players who isn't sending too many
+async fn relay_chat(
+    room: &mut Room,
+    id: SessionToken,
+    name: PlayerId,
+    text: String,
+    chats: &mut SlidingWindow<SessionToken>,
+    limit: Limit,
+) -> AsyncResult<()> {
+    if !room.is_seated(&name) {
+        return Ok(());
+    }
+    // line breaks are kept, other control characters could mess with how the chat is shown
+    let text = text
+        .chars()
+        .filter(|c| *c == '\n' || !c.is_control())
+        .collect::<String>();
+    if text.trim().is_empty() {
+        return Ok(());
+    }
+    if let Err(info) = CHAT_LENGTH.check(text.chars().count() as u64) {
+        return reply(room, &name, RoomErr::ChatTooLong(info)).await;
+    }
+    if let Err(info) = chats.try_record(id, limit, Instant::now()) {
+        return reply(room, &name, BrokerMsg::ChatRateLimited(info)).await;
+    }
+    let msg = BrokerMsg::Chat {
+        from: Arc::from(name),
+        text: Arc::from(text),
+    };
+    room.broadcast(msg);
+    Ok(())
+}
+
+/// Opens a vote on extending the round timer, with the requester voting in favour
+async fn request_extension(room: &mut Room, name: PlayerId) -> AsyncResult<()> {
+    if !room.is_seated(&name) {
+        return Ok(());
+    }
+    // a watcher has no say in a round they aren't playing
+    let playing = room.playing().any(|player| *player == name);
+    let round = match room.round.as_mut() {
+        Some(round) if round.ends_at.is_some() => round,
+        _ => return reply(room, &name, RoomErr::NoTimerRunning).await,
+    };
+    if !playing {
+        return Ok(());
+    }
+    if round.extension_requested {
+        return reply(room, &name, RoomErr::ExtensionAlreadyRequested).await;
+    }
+    round.extension_requested = true;
+    let mut vote = Vote::new();
+    vote.cast(name.clone(), true);
+    round.extension_vote = Some(vote);
+    room.broadcast(BrokerMsg::ExtensionRequested {
+        by: Arc::from(name),
+    });
+    Ok(())
+}
+
+/// Applies the outcome of the extension vote once the room has a majority either way
+async fn settle_extension_vote(
+    room_id: &RoomId,
+    room: &mut Room,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    // watchers and held seats have no say
+    let electorate = room.playing().count();
+    let round = match room.round.as_mut() {
+        Some(round) => round,
+        None => return Ok(()),
+    };
+    let outcome = round
+        .extension_vote
+        .as_ref()
+        .and_then(|vote| vote.outcome(electorate));
+    let msg = match (outcome, round.ends_at) {
+        (Some(true), Some(ends_at)) => {
+            let ends_at = ends_at + Duration::from_secs(room.settings.extension_secs);
+            round.ends_at = Some(ends_at);
+            schedule_round_timer(room_id, ends_at, deadlines);
+            BrokerMsg::RoundExtended {
+                remaining_secs: round.remaining_secs().unwrap_or(0),
+            }
+        }
+        (Some(_), _) => BrokerMsg::ExtensionRejected,
+        (None, _) => return Ok(()),
+    };
+    round.extension_vote = None;
+    room.broadcast(msg);
+    Ok(())
+}
+
+/// Waits for the next client message, giving up at `deadline` (if any) so timed work can run.
+/// Returns None when the deadline is reached first
+async fn recv_until(
+    client_listener: &Receiver<ClientMsg>,
+    deadline: Option<Instant>,
+) -> Option<Result<ClientMsg, RecvError>> {
+    let recv = async { Some(client_listener.recv().await) };
+    match deadline {
+        Some(at) => {
+            let timeout = async {
+                Timer::at(at).await;
+                None
+            };
+            future::or(recv, timeout).await
+        }
+        None => recv.await,
+    }
+}
+
+async fn fire_deadline(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    config: &BrokerConfig,
+    deadline: Deadline,
+    deadlines: &mut Deadlines<Deadline>,
+    creations: &mut SlidingWindow<IpAddr>,
+    chats: &mut SlidingWindow<SessionToken>,
+) -> AsyncResult<()> {
+    match deadline {
+        Deadline::IdleSweep => {
+            sweep_idle_players(rooms, index, config, deadlines).await?;
+            deadlines.schedule(
+                Deadline::IdleSweep,
+                Instant::now() + config.idle_sweep_interval,
+            );
+        }
+        Deadline::RoomSweep => {
+            sweep_stale_rooms(rooms, index, config, deadlines).await?;
+            creations.prune(Instant::now());
+            chats.prune(Instant::now());
+            deadlines.schedule(
+                Deadline::RoomSweep,
+                Instant::now() + config.room_sweep_interval,
+            );
+        }
+        Deadline::Snapshot => {
+            if let Some(path) = &config.snapshot_path {
+                save_rooms(path, rooms).await;
+            }
+            deadlines.schedule(
+                Deadline::Snapshot,
+                Instant::now() + config.snapshot_interval,
+            );
+        }
+        Deadline::TimerTick(room_id) => {
+            if let Some(room) = rooms.get_room_mut(&room_id) {
+                tick_timer(&room_id, room, deadlines).await?;
+            }
+        }
+        Deadline::RoundOver(room_id) => {
+            if let Some(room) = rooms.get_room_mut(&room_id) {
+                // a paused round is rescheduled when it resumes
+                let timed_out = room
+                    .round
+                    .as_ref()
+                    .filter(|round| round.paused_at.is_none())
+                    .and_then(|round| round.ends_at)
+                    .is_some_and(|ends_at| ends_at <= Instant::now());
+                if timed_out {
+                    if let Some(summary) = room.end_round(None) {
+                        let msg = BrokerMsg::TimeUp {
+                            round: summary.round,
+                        };
+                        announce_round_end(room, msg).await?;
+                    }
+                    settle_match(room).await?;
+                }
+            }
+        }
+        Deadline::StateBroadcast(room_id, key) => {
+            // the room may have been evicted in the meantime
+            if let Some(room) = rooms.get_room_mut(&room_id) {
+                if room.unsent_states.remove(&key) {
+                    send_state(room, &key).await?;
+                    // keep the window open so a further toggle is still debounced
+                    deadlines.schedule(
+                        Deadline::StateBroadcast(room_id, key),
+                        Instant::now() + STATE_BROADCAST_DEBOUNCE,
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Broadcasts a player's changed state straight away, unless it was already broadcast within the debounce window.
+/// In that case the latest value is sent once the window closes
+async fn broadcast_state(
+    room_id: RoomId,
+    room: &mut Room,
+    key: StateKey,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    if deadlines.contains(&Deadline::StateBroadcast(room_id.clone(), key.clone())) {
+        room.unsent_states.insert(key);
+    } else {
+        send_state(room, &key).await?;
+        deadlines.schedule(
+            Deadline::StateBroadcast(room_id, key),
+            Instant::now() + STATE_BROADCAST_DEBOUNCE,
+        );
+    }
+    Ok(())
+}
+
+/// Broadcasts the current value of a player's state, followed by the lobby it leaves the room in
+async fn send_state(room: &mut Room, key: &StateKey) -> AsyncResult<()> {
+    if let Some(msg) = room.state_msg(key) {
+        room.broadcast(msg);
+        room.broadcast(room.roster());
+    }
+    Ok(())
+}
+
+/// Immediately broadcasts any state changes still waiting on their debounce window
+async fn flush_states(
+    room_id: &RoomId,
+    room: &mut Room,
+    deadlines: &mut Deadlines<Deadline>,
+) -> AsyncResult<()> {
+    let unsent = room.unsent_states.drain().collect::<Vec<_>>();
+    for key in unsent {
+        send_state(room, &key).await?;
+        deadlines.schedule(
+            Deadline::StateBroadcast(room_id.clone(), key),
+            Instant::now() + STATE_BROADCAST_DEBOUNCE,
+        );
+    }
+    Ok(())
+}
+
+/// A secret for a player to take their seat back with, only ever sent to them
+fn new_reconnect_token(rng: &Rng) -> String {
+    let bytes = std::iter::repeat_with(|| rng.u8(..))
+        .take(RECONNECT_TOKEN_BYTES)
+        .collect::<Vec<_>>();
+    base32::encode(base32::Alphabet::Crockford, &bytes)
+}
+
+/// Brings back the rooms of the last snapshot, so players reconnecting with their tokens find them again
+fn restore_rooms(
+    snapshots: Vec<RoomSnapshot>,
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    rng: &Rng,
+) {
+    for snapshot in snapshots {
+        let room_id = snapshot.room_id.clone();
+        if let Some(room) = Room::restore(snapshot, index, rng) {
+            rooms.0.insert(room_id, room);
+        }
+    }
+    if !rooms.is_empty() {
+        log::info!("Restored {} rooms from the last snapshot", rooms.len());
+    }
+}
+
+/// Writes every room down, a failed write is only logged and tried again at the next snapshot
+async fn save_rooms(path: &Path, rooms: &RoomTable) {
+    let snapshots = rooms
+        .iter()
+        .map(|(room_id, room)| room.snapshot(room_id))
+        .collect::<Vec<_>>();
+    if let Err(err) = snapshot::save(path, &snapshots).await {
+        log::warn!(
+            "Failed to snapshot the rooms to {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// A session token that no current player holds
+fn new_token(rng: &Rng, index: &PlayerIndex) -> SessionToken {
+    loop {
+        let token = rng.u64(..);
+        if !index.contains_token(token) {
+            return token;
+        }
+    }
+}
+
+/// Warns once each time a count climbs into the last tenth of what the server allows
+fn warn_near_capacity(what: &str, count: usize, max: usize) {
+    if count == max - max / 10 {
+        log::warn!(
+            "The server is nearly full, with {} of {} {}",
+            count,
+            max,
+            what
+        );
+    }
+}
+
+/// What the player creating a room asked it to be like
+struct RoomRequest {
+    settings: RoomSettings,
+    password: Option<String>,
+    code: Option<String>,
+}
+
+fn create_room(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    rng: &Rng,
+    config: &BrokerConfig,
+    name: PlayerId,
+    request: RoomRequest,
+) -> JoinResult {
+    let RoomRequest {
+        settings,
+        password,
+        code,
+    } = request;
+    settings.validate().map_err(JoinErr::InvalidSettings)?;
+    let code = code
+        .map(|code| validate_requested_code(&code, &config.blocked_codes))
+        .transpose()
+        .map_err(JoinErr::InvalidCode)?;
+    // counting the room about to be created
+    config
+        .room_limit()
+        .check(rooms.len() as u64 + 1)
+        .map_err(JoinErr::ServerFull)?;
+    config
+        .player_limit()
+        .check(index.player_count() as u64 + 1)
+        .map_err(JoinErr::ServerFull)?;
+    // a code that was asked for is never swapped for a random one
+    let (code, taken) = match code {
+        Some(code) => (NewCode::Requested(code), JoinErr::CodeTaken),
+        None => (
+            NewCode::Random(rng, config.room_codes),
+            JoinErr::FailedToCreateRoom,
+        ),
+    };
+    let vacant_room = rooms.try_create_room(code).ok_or(taken)?;
+    let room_id = vacant_room.key().clone();
+    let (sender, rx) = channel::bounded(CLIENT_BUFFER);
+    let token = new_token(rng, index);
+    let reconnect_token = new_reconnect_token(rng);
+    index.insert(token, &name, &room_id, &reconnect_token);
+    let room = vacant_room.insert(Room::new(name.clone(), sender, token, settings));
+    room.password = password.map(|password| RoomPassword::new(&password, rng));
+    // the host can ask for more players than the server does, but not fewer
+    room.min_players = room.settings.min_players.max(config.min_players);
+    room.reconnect_tokens.insert(name.clone(), reconnect_token);
+    Ok((room.connected(&room_id, name, token), rx))
+}
+
+/// What a player joining an existing room can prove
+#[derive(Default)]
+struct Credentials {
+    password: Option<String>,
+    reconnect_token: Option<String>,
+    // stands in for the password while it is valid
+    invite: Option<String>,
+}
+
+/// attempts to add a player
+/// the outermost error is a programatic error (unexpected)
+/// the inner result is what to send back to the client (errors of usage, and are expected)
+async fn add_player(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    room_id: RoomId,
+    mut name: PlayerId,
+    credentials: Credentials,
+    token: SessionToken,
+    reconnect_token: String,
+) -> Result<JoinResult, AsyncErr> {
+    let room_id = normalize_room_id(&room_id);
+    let mut room_entry = match rooms.get_room_entry(room_id.clone()) {
+        Ok(room_entry) => room_entry,
+        Err(e) => return Ok(Err(e)),
+    };
+    // only used up once the player is seated, a refused join keeps it for the next try
+    let mut invited = None;
+    let room = room_entry.get_mut();
+    let seat = room.seat_like(&name);
+    // only whoever was given the seat's token can take it, dropped or not (their old connection may not know yet).
+    // The token already proves they were let in, so the password isn't asked for again
+    if let Some(seat) =
+        seat.filter(|seat| room.owns_seat(*seat, credentials.reconnect_token.as_deref()))
+    {
+        return Ok(Ok(retake_seat(room, index, &room_id, seat, token).await?));
+    }
+    if let Some(expected) = &room.password {
+        let password_ok = credentials
+            .password
+            .is_some_and(|password| expected.matches(&password));
+        match credentials.invite {
+            _ if password_ok => {}
+            Some(code) if room.invite_valid(&code, Instant::now()) => invited = Some(code),
+            Some(_) => return Ok(Err(JoinErr::InvalidInvite)),
+            None => return Ok(Err(JoinErr::WrongPassword)),
+        }
+    }
+
+    if let Some(seat) = seat {
+        let room = room_entry.get_mut();
+        let suffixed = suffixed_username(&name, |suffixed| room.seat_like(suffixed).is_some());
+        if !room.settings.auto_rename {
+            let held = room.disconnected.contains_key(&room.players[seat].name);
+            let suggested = (!held).then_some(suffixed);
+            return Ok(Err(JoinErr::UsernameTaken { suggested }));
+        }
+        // the name the player is seated under is in their Connected
+        log::info!(
+            "Seating another {} in room {} as {}",
+            name,
+            room_id,
+            suffixed
+        );
+        name = suffixed;
+    }
+    // checked before anyone is told about the player, who isn't going to be seated
+    let room = room_entry.get();
+    if let Err(info) = ROOM_CAPACITY.check(room.players.len() as u64 + 1) {
+        log::debug!("Turned {} away from room {}, it is full", name, room_id);
+        return Ok(Err(JoinErr::RoomFull(info)));
+    }
+    // nobody is left to reach and nobody's seat is being held, the way a room is left when its creator's
+    // connection died before they were even told they were in it
+    let unreachable = room.players.iter().all(|player| player.sender.is_closed());
+    if unreachable && room.disconnected.is_empty() {
+        log::info!("Evicting room {}, nobody in it can be reached", room_id);
+        for player in &room_entry.remove().players {
+            index.remove(player.id, &player.name);
+        }
+        return Ok(Err(JoinErr::NoSuchRoom));
+    }
+
+    let (sender, rx) = channel::bounded(CLIENT_BUFFER);
+    // insert new player
+    let room = room_entry.get_mut();
+    index.insert(token, &name, &room_id, &reconnect_token);
+    room.add(Player {
+        id: token,
+        name: name.clone(),
+        sender,
+    });
+    room.reconnect_tokens.insert(name.clone(), reconnect_token);
+    room.last_activity = Instant::now();
+    if let Some(code) = invited {
+        room.use_invite(&code);
+    }
+    Ok(Ok((room.connected(&room_id, name, token), rx)))
+}
+
+/// Tells the rest of the room about a player `add_player` has just seated, once nothing can refuse them anymore.
+/// Whoever the news can't reach is gone without having left, and is let go of rather than failing the join
+async fn announce_join(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    deadlines: &mut Deadlines<Deadline>,
+    room_id: &RoomId,
+    name: &PlayerId,
+) -> AsyncResult<()> {
+    let room = match rooms.get_room(room_id) {
+        Some(room) => room,
+        None => return Ok(()),
+    };
+    // the new player is seated last, and is told the roster through their Connected
+    let others = room.players.len() - 1;
+    let joined = BrokerMsg::Join(Arc::from(name.as_str()));
+    let missed = send_room(room.senders().take(others), joined);
+    send_room(room.senders().take(others), room.roster());
+    let missed = missed
+        .into_iter()
+        .map(|seat| room.players[seat].name.clone())
+        .collect();
+    let_go_of_unreachable(rooms, index, deadlines, room_id, missed).await
+}
+
+/// Removes those of `missed` a broadcast couldn't be delivered to because their client actor is gone
+/// without having left, rather than letting one dead connection hold up the room.
+/// Nothing reaches a held seat until its player is back, so those are kept
+async fn let_go_of_unreachable(
+    rooms: &mut RoomTable,
+    index: &mut PlayerIndex,
+    deadlines: &mut Deadlines<Deadline>,
+    room_id: &RoomId,
+    missed: Vec<PlayerId>,
+) -> AsyncResult<()> {
+    for name in missed {
+        let gone = rooms.get_room(room_id).is_some_and(|room| {
+            let closed = room.get(&name).is_some_and(|p| p.sender.is_closed());
+            closed && !room.disconnected.contains_key(&name)
+        });
+        if gone {
+            log::info!(
+                "Letting go of {} in room {}, they can't be reached",
+                name,
+                room_id
+            );
+            let reason = LeaveReason::Disconnected;
+            remove_player(rooms, index, deadlines, room_id.clone(), name, reason).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Hands a seat to the player's new connection, replaying the room's state to it
+async fn retake_seat(
+    room: &mut Room,
+    index: &mut PlayerIndex,
+    room_id: &RoomId,
+    seat: usize,
+    token: SessionToken,
+) -> AsyncResult<(Connected, Receiver<BrokerMsg>)> {
+    let name = room.players[seat].name.clone();
+    index.remove(room.players[seat].id, &name);
+    index.insert(token, &name, room_id, &room.reconnect_tokens[&name]);
+    room.players[seat].id = token;
+    room.disconnected.remove(&name);
+    room.touch(&name);
+    room.last_activity = Instant::now();
+    // nothing reads the new channel until the join is answered, so it is left out
+    let others = room
+        .players
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != seat)
+        .map(|(_, player)| player.sender.clone())
+        .collect::<Vec<_>>();
+    let reconnected = BrokerMsg::Reconnected {
+        name: Arc::from(name.as_str()),
+    };
+    send_room(&others, reconnected);
+    send_room(&others, room.roster());
+
+    let (sender, rx) = channel::bounded(CLIENT_BUFFER);
+    deliver(&sender, room.roster());
+    if let Some(start) = room.round.as_ref().and_then(|round| round.dealt_to(&name)) {
+        deliver(&sender, BrokerMsg::Started(start));
+    }
+    // replacing the old sender ends the previous connection's room loop, if it hadn't already
+    room.players[seat].sender = sender;
+    Ok((room.connected(room_id, name, token), rx))
+}
+
+/// Sends `msg` to every one of `senders`, returning the positions of those it couldn't be delivered to
+fn send_room<'a>(
+    senders: impl IntoIterator<Item = &'a Sender<BrokerMsg>>,
+    msg: BrokerMsg,
+) -> Vec<usize> {
+    let senders = senders.into_iter().collect::<Vec<_>>();
+    let mut missed = vec![];
+    if let Some((last, rest)) = senders.split_last() {
+        for (i, sender) in rest.iter().enumerate() {
+            if !deliver(sender, msg.clone()) {
+                missed.push(i);
+            }
+        }
+        // the last one gets the message itself, to avoid an extra clone
+        if !deliver(last, msg) {
+            missed.push(rest.len());
+        }
+    }
+    missed
+}
+
+/// Sends a message to one client actor without waiting on it, so a client that stopped reading can't hold up the broker.
+/// Such a client is cut off once it falls `CLIENT_BUFFER` messages behind, its actor sees the channel close.
+/// A closed channel means the client actor has already gone, and the message it sent on its way out
+/// (if any) is still queued behind this one, so it is not an error for the broker. Returns whether the message was queued
+fn deliver<T>(sender: &Sender<T>, msg: T) -> bool {
+    match sender.try_send(msg) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            log::warn!("Cut off a client that stopped reading its messages");
+            sender.close();
+            false
+        }
+        Err(TrySendError::Closed(_)) => {
+            log::debug!("Dropped a message for a client that has already disconnected");
+            false
+        }
+    }
+}
+
+/// Deals a round at one of `locations` to `players`. Spies are only drawn from `spy_pool` when one is given,
+/// and the location is one of those not in `recent_locations` unless that leaves none
+fn assign_roles(
+    mut players: Vec<PlayerId>,
+    spy_pool: Option<&[PlayerId]>,
+    locations: &[String],
+    recent_locations: &VecDeque<String>,
+    repo: &SpyfallRepo,
+    rng: &Rng,
+    settings: &RoomSettings,
+) -> GameInfo {
+    let first_player_index = rng.usize(..players.len());
+    let mut candidates = locations
+        .iter()
+        .filter(|location| !recent_locations.contains(location))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        candidates = locations.iter().collect();
+    }
+    let location = candidates[rng.usize(..candidates.len())];
+    let mut roles = repo.roles(location).to_vec();
+    rng.shuffle(&mut roles);
+    let mut first = players[first_player_index].clone();
+    let decoy = settings.decoy_rounds() && rng.usize(..DECOY_ROUND_ODDS) == 0;
+    let spy_count = if decoy {
+        0
+    } else if settings.two_spies() && players.len() >= settings.two_spies_from {
+        2
+    } else {
+        1
+    };
+    let mut spies = Vec::new();
+    for _ in 0..spy_count {
+        let eligible = (0..players.len())
+            .filter(|i| spy_pool.is_none_or(|pool| pool.contains(&players[*i])))
+            .collect::<Vec<_>>();
+        if eligible.is_empty() {
+            break;
+        }
+        spies.push(players.remove(eligible[rng.usize(..eligible.len())]));
+    }
+    // what is left of players are the non-spies
+    if settings.spy_never_first && spies.contains(&first) && !players.is_empty() {
+        first = players[rng.usize(..players.len())].clone();
+    }
+    // a cover at the real location would make the spy a regular player, so when the round's
+    // locations have no other the cover comes from the whole repo, and a repo of one location deals an ordinary spy
+    let mut covers = locations
+        .iter()
+        .filter(|candidate| *candidate != location)
+        .collect::<Vec<_>>();
+    if covers.is_empty() {
+        covers = repo
+            .locations()
+            .iter()
+            .filter(|candidate| *candidate != location)
+            .collect();
+    }
+    let spy_cover = match covers.len() {
+        n if n > 0 && !spies.is_empty() && settings.blind_spy() => {
+            let cover_location = covers[rng.usize(..n)];
+            let cover_roles = repo.roles(cover_location);
+            Some(Assignment {
+                location: Arc::from(cover_location.as_str()),
+                role: cover_roles[rng.usize(..cover_roles.len())].clone(),
+            })
+        }
+        _ => None,
+    };
+    let player_roles = roles
+        .into_iter()
+        .cycle()
+        .zip(players)
+        .map(|(role, player)| (player, role))
+        .collect::<HashMap<_, _>>();
+    GameInfo {
+        player_roles,
+        first,
+        spies,
+        spy_cover,
+        location: location.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::PROTOCOL_VERSION;
+    use crate::settings::{Variant, Variants};
+    use std::ops::Deref;
+
+    /// A player's end of the room, with the id their room messages have to carry
+    struct Seat {
+        id: SessionToken,
+        rx: Receiver<BrokerMsg>,
+    }
+
+    impl Deref for Seat {
+        type Target = Receiver<BrokerMsg>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.rx
+        }
+    }
+
+    /// Joins `name` to `room` (or creates a new room), returning what the broker sent back
+    async fn join(
+        broker_tx: &Sender<ClientMsg>,
+        name: &str,
+        room: Option<RoomId>,
+    ) -> Result<(Connected, Receiver<BrokerMsg>), JoinErr> {
+        join_with(broker_tx, name, room, RoomSettings::default()).await
+    }
+
+    async fn join_with(
+        broker_tx: &Sender<ClientMsg>,
+        name: &str,
+        room: Option<RoomId>,
+        settings: RoomSettings,
+    ) -> Result<(Connected, Receiver<BrokerMsg>), JoinErr> {
+        let join = Join {
+            name: name.to_string(),
+            room,
+            settings,
+            password: None,
+            reconnect_token: None,
+            requested_code: None,
+            invite: None,
+            protocol_version: PROTOCOL_VERSION,
+            peer: None,
+        };
+        send_join(broker_tx, join).await
+    }
+
+    async fn resume(broker_tx: &Sender<ClientMsg>, reconnect_token: &str) -> JoinResult {
+        let (client_tx, client_rx) = channel::bounded(1);
+        let resume = ClientMsg::Resume(reconnect_token.to_string(), client_tx);
+        broker_tx.send(resume).await.unwrap();
+        client_rx.recv().await.unwrap()
+    }
+
+    async fn send_join(
+        broker_tx: &Sender<ClientMsg>,
+        join: Join,
+    ) -> Result<(Connected, Receiver<BrokerMsg>), JoinErr> {
+        let (client_tx, client_rx) = channel::bounded(1);
+        broker_tx
+            .send(ClientMsg::Join(join, client_tx))
+            .await
+            .unwrap();
+        client_rx.recv().await.unwrap()
+    }
+
+    /// Creates a room holding every player in `names`, draining the join and roster broadcasts along the way
+    async fn room_with(broker_tx: &Sender<ClientMsg>, names: &[&str]) -> (RoomId, Vec<Seat>) {
+        room_with_settings(broker_tx, names, RoomSettings::default()).await
+    }
+
+    async fn room_with_settings(
+        broker_tx: &Sender<ClientMsg>,
+        names: &[&str],
+        settings: RoomSettings,
+    ) -> (RoomId, Vec<Seat>) {
+        let (Connected { room_id, id, .. }, rx) = join_with(broker_tx, names[0], None, settings)
+            .await
+            .unwrap();
+        let mut channels = vec![Seat { id, rx }];
+        for (i, name) in names.iter().enumerate().skip(1) {
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (Connected { id, .. }, rx, rosters) =
+                join_watched(broker_tx, name, &room_id, &watchers).await;
+            let roster = roster_of(&names[..=i], &[]);
+            assert!(rosters.iter().all(|sent| *sent == roster));
+            channels.push(Seat { id, rx });
+        }
+        (room_id, channels)
+    }
+
+    /// Joins `room_id` while the players on `watchers` take the join and roster broadcasts,
+    /// which the broker only answers the join after. Returns the roster each watcher was sent
+    async fn join_watched(
+        broker_tx: &Sender<ClientMsg>,
+        name: &str,
+        room_id: &RoomId,
+        watchers: &[&Seat],
+    ) -> (Connected, Receiver<BrokerMsg>, Vec<BrokerMsg>) {
+        let joining = async { join(broker_tx, name, Some(room_id.clone())).await.unwrap() };
+        let broadcasts = async {
+            for chan in watchers {
+                assert_eq!(chan.recv().await.unwrap(), BrokerMsg::Join(Arc::from(name)));
+            }
+            let mut rosters = vec![];
+            for chan in watchers {
+                rosters.push(chan.recv().await.unwrap());
+            }
+            rosters
+        };
+        let ((connected, rx), rosters) = future::zip(joining, broadcasts).await;
+        (connected, rx, rosters)
+    }
+
+    fn left(name: &str, reason: LeaveReason) -> BrokerMsg {
+        BrokerMsg::Left {
+            name: Arc::from(name),
+            reason,
+        }
+    }
+
+    /// The roster of a lobby seating `names` in joining order, with `disconnected` greyed out
+    fn roster_of(names: &[&str], disconnected: &[&str]) -> BrokerMsg {
+        let players = names
+            .iter()
+            .map(|name| RosterEntry {
+                name: name.to_string(),
+                connected: !disconnected.contains(name),
+                ready: false,
+                watching: false,
+            })
+            .collect();
+        BrokerMsg::Roster {
+            players,
+            host: names[0].to_string(),
+            in_game: false,
+        }
+    }
+
+    /// The same roster, sent while a round is being played
+    fn mid_round(mut roster: BrokerMsg) -> BrokerMsg {
+        if let BrokerMsg::Roster { in_game, .. } = &mut roster {
+            *in_game = true;
+        }
+        roster
+    }
+
+    /// Collects messages from `chan` until nothing arrives for `idle`
+    async fn drain(chan: &Receiver<BrokerMsg>, idle: Duration) -> Vec<BrokerMsg> {
+        let mut msgs = vec![];
+        loop {
+            let next = future::or(async { chan.recv().await.ok() }, async {
+                Timer::after(idle).await;
+                None
+            });
+            match next.await {
+                Some(msg) => msgs.push(msg),
+                None => return msgs,
+            }
+        }
+    }
+
+    #[test]
+    fn assign_roles_properties() {
+        let repo = SpyfallRepo::new();
+        let players = (b'a'..=b'z')
+            .map(char::from)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let rng = Rng::new();
+
+        let game_info = assign_roles(
+            players.clone(),
+            None,
+            repo.locations(),
+            &VecDeque::new(),
+            &repo,
+            &rng,
+            &RoomSettings::default(),
+        );
+        assert_eq!(game_info.spies.len(), 1, "only decoy rounds have no spy");
+        let spy = &game_info.spies[0];
+        assert!(game_info
+            .player_roles
+            .keys()
+            .find(|non_spy| *non_spy == spy)
+            .is_none());
+        assert_eq!(game_info.player_roles.len(), players.len() - 1);
+        assert_eq!(game_info.spy_cover, None);
+
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::TwoSpies, Variant::DecoyRounds]),
+            ..RoomSettings::default()
+        };
+        for seed in 0..500 {
+            let rng = Rng::with_seed(seed);
+            for players in [&players[..3], &players[..5]] {
+                for settings in [&RoomSettings::default(), &settings] {
+                    let game_info = assign_roles(
+                        players.to_vec(),
+                        None,
+                        repo.locations(),
+                        &VecDeque::new(),
+                        &repo,
+                        &rng,
+                        settings,
+                    );
+                    assert!(!game_info.spies.contains(&game_info.first), "seed {}", seed);
+                    assert!(players.contains(&game_info.first));
+                }
+            }
+        }
+
+        // groups who don't mind can still let the spy go first
+        let settings = RoomSettings {
+            spy_never_first: false,
+            ..RoomSettings::default()
+        };
+        let spy_first = (0..500)
+            .map(|_| {
+                assign_roles(
+                    players[..3].to_vec(),
+                    None,
+                    repo.locations(),
+                    &VecDeque::new(),
+                    &repo,
+                    &rng,
+                    &settings,
+                )
+            })
+            .any(|game_info| game_info.spies.contains(&game_info.first));
+        assert!(spy_first);
+    }
+
+    #[test]
+    fn recent_locations_are_not_dealt_again() {
+        let repo = SpyfallRepo::new();
+        let (sender, _rx) = channel::bounded(1);
+        let mut room = Room::new("Ahab".to_string(), sender, 0, RoomSettings::default());
+        let players = vec![
+            "Ahab".to_string(),
+            "Ishmael".to_string(),
+            "Stubb".to_string(),
+        ];
+        let rng = Rng::with_seed(11);
+        let mut dealt = vec![];
+        for _ in 0..100 {
+            let deal = assign_roles(
+                players.clone(),
+                None,
+                repo.locations(),
+                &room.recent_locations,
+                &repo,
+                &rng,
+                &room.settings,
+            );
+            room.remember_location(&deal.location);
+            dealt.push(deal.location);
+        }
+        assert_eq!(room.recent_locations.len(), room.settings.location_memory);
+        for window in dealt.windows(room.settings.location_memory + 1) {
+            let unique = window.iter().collect::<HashSet<_>>();
+            assert_eq!(unique.len(), window.len(), "repeated in {:?}", window);
+        }
+
+        // a room remembering every location falls back to all of them
+        room.settings.location_memory = repo.locations().len();
+        room.recent_locations = repo.locations().iter().cloned().collect();
+        let deal = assign_roles(
+            players,
+            None,
+            repo.locations(),
+            &room.recent_locations,
+            &repo,
+            &rng,
+            &room.settings,
+        );
+        assert!(repo.has_location(&deal.location));
+    }
+
+    #[test]
+    fn locations_never_repeat_back_to_back() {
+        let repo = SpyfallRepo::new();
+        let (sender, _rx) = channel::bounded(1);
+        let settings = RoomSettings {
+            location_memory: 0,
+            ..RoomSettings::default()
+        };
+        let mut room = Room::new("Ahab".to_string(), sender, 0, settings);
+        let players = vec![
+            "Ahab".to_string(),
+            "Ishmael".to_string(),
+            "Stubb".to_string(),
+        ];
+        let rng = Rng::with_seed(5);
+        let mut previous = None;
+        for _ in 0..500 {
+            let deal = assign_roles(
+                players.clone(),
+                None,
+                repo.locations(),
+                &room.recent_locations,
+                &repo,
+                &rng,
+                &room.settings,
+            );
+            assert_ne!(previous.as_ref(), Some(&deal.location));
+            room.remember_location(&deal.location);
+            previous = Some(deal.location);
+        }
+    }
+
+    #[test]
+    fn decoy_rounds_sometimes_deal_no_spy() {
+        let repo = SpyfallRepo::new();
+        let players = vec![
+            "Ahab".to_string(),
+            "Ishmael".to_string(),
+            "Stubb".to_string(),
+        ];
+        let rng = Rng::with_seed(7);
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::DecoyRounds]),
+            ..RoomSettings::default()
+        };
+
+        let deals = (0..200)
+            .map(|_| {
+                assign_roles(
+                    players.clone(),
+                    None,
+                    repo.locations(),
+                    &VecDeque::new(),
+                    &repo,
+                    &rng,
+                    &settings,
+                )
+            })
+            .collect::<Vec<_>>();
+        let decoys = deals.iter().filter(|deal| deal.spies.is_empty()).count();
+        assert!(decoys > 0 && decoys < deals.len());
+        for deal in deals.iter().filter(|deal| deal.spies.is_empty()) {
+            // everyone knows the location when nobody is the spy
+            assert_eq!(deal.player_roles.len(), players.len());
+        }
+    }
+
+    #[test]
+    fn large_rooms_are_dealt_two_spies() {
+        let repo = SpyfallRepo::new();
+        let players = (b'a'..b'h')
+            .map(char::from)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let mut settings = RoomSettings {
+            variants: Variants::from(vec![Variant::TwoSpies]),
+            ..RoomSettings::default()
+        };
+        for seed in 0..100 {
+            let rng = Rng::with_seed(seed);
+            let deal = assign_roles(
+                players.clone(),
+                None,
+                repo.locations(),
+                &VecDeque::new(),
+                &repo,
+                &rng,
+                &settings,
+            );
+            assert_eq!(deal.spies.len(), 2, "seed {}", seed);
+            assert_ne!(deal.spies[0], deal.spies[1]);
+            assert!(deal
+                .spies
+                .iter()
+                .all(|spy| !deal.player_roles.contains_key(spy)));
+            assert_eq!(deal.player_roles.len(), players.len() - 2);
+        }
+
+        settings.two_spies_from = players.len() + 1;
+        let deal = assign_roles(
+            players,
+            None,
+            repo.locations(),
+            &VecDeque::new(),
+            &repo,
+            &Rng::new(),
+            &settings,
+        );
+        assert_eq!(deal.spies.len(), 1);
+    }
+
+    #[test]
+    fn colluding_spies_are_told_about_each_other() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck", "Stubb"];
+            let settings = RoomSettings {
+                variants: Variants::from(vec![Variant::TwoSpies]),
+                spies_collude: true,
+                ..RoomSettings::default()
+            };
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+
+            let starts = start(&broker_tx, &room, &channels).await;
+            let (spies, non_spies): (Vec<_>, Vec<_>) = names
+                .iter()
+                .zip(&starts)
+                .partition(|(_, start)| start.assignment.is_none());
+            assert_eq!(spies.len(), 2);
+            assert_eq!(spies[0].1.partner.as_deref(), Some(*spies[1].0));
+            assert_eq!(spies[1].1.partner.as_deref(), Some(*spies[0].0));
+
+            // nothing but the values tells a spy's message apart
+            let keys = |start: &Start| match serde_json::to_value(start).unwrap() {
+                serde_json::Value::Object(fields) => fields.keys().cloned().collect::<Vec<_>>(),
+                json => panic!("Start serialized to {}", json),
+            };
+            for (_, start) in &non_spies {
+                assert_eq!(start.partner, None);
+                assert_eq!(keys(start), keys(spies[0].1));
+            }
+            let json = serde_json::to_string(non_spies[0].1).unwrap();
+            assert!(json.ends_with(r#""remaining_secs":null,"partner":null}"#));
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn blind_spy_is_dealt_a_different_location() {
+        let repo = SpyfallRepo::new();
+        let players = vec![
+            "Ahab".to_string(),
+            "Ishmael".to_string(),
+            "Stubb".to_string(),
+        ];
+        let rng = Rng::with_seed(7);
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::BlindSpy, Variant::DecoyRounds]),
+            ..RoomSettings::default()
+        };
+
+        for _ in 0..100 {
+            let deal = assign_roles(
+                players.clone(),
+                None,
+                repo.locations(),
+                &VecDeque::new(),
+                &repo,
+                &rng,
+                &settings,
+            );
+            match (deal.spies.first(), deal.spy_cover) {
+                (Some(_), Some(cover)) => {
+                    assert_ne!(&*cover.location, deal.location);
+                    assert!(repo.roles(&cover.location).contains(&cover.role));
+                }
+                (None, None) => {}
+                (spy, cover) => panic!("spy {:?} was dealt cover {:?}", spy, cover),
+            }
+        }
+
+        // a round that can only be dealt at one location still covers the spy with another
+        let only = &repo.locations()[..1];
+        for _ in 0..20 {
+            let deal = assign_roles(
+                players.clone(),
+                None,
+                only,
+                &VecDeque::new(),
+                &repo,
+                &rng,
+                &settings,
+            );
+            assert_eq!(deal.location, only[0]);
+            if let Some(cover) = deal.spy_cover {
+                assert_ne!(&*cover.location, deal.location);
+            }
+        }
+
+        // and a repo with no other location deals an ordinary spy
+        let repo = SpyfallRepo::from_json(r#"{"Pequod": ["Captain", "Harpooner"]}"#).unwrap();
+        let deal = assign_roles(
+            players.clone(),
+            None,
+            repo.locations(),
+            &VecDeque::new(),
+            &repo,
+            &rng,
+            &RoomSettings {
+                variants: Variants::from(vec![Variant::BlindSpy]),
+                ..RoomSettings::default()
+            },
+        );
+        assert_eq!(deal.spies.len(), 1);
+        assert_eq!(deal.spy_cover, None);
+    }
+
+    #[test]
+    fn enter_leave_room_deletes_room() {
+        smol::block_on(async {
+            let player_name = "Ahab".to_string();
+            let (client_tx, client_rx) = channel::bounded(1);
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(async {
+                // at the end, the table should be empty
+                let table = broker_actor(broker_rx, BrokerConfig::default())
+                    .await
+                    .unwrap();
+                assert_eq!(table, RoomTable::new());
+            });
+            let join_msg = ClientMsg::Join(
+                Join {
+                    name: player_name.clone(),
+                    room: None,
+                    settings: RoomSettings::default(),
+                    password: None,
+                    reconnect_token: None,
+                    requested_code: None,
+                    invite: None,
+                    protocol_version: PROTOCOL_VERSION,
+                    peer: None,
+                },
+                client_tx,
+            );
+            broker_tx.send(join_msg).await.unwrap();
+            let (
+                Connected {
+                    room_id,
+                    players,
+                    id,
+                    ..
+                },
+                _,
+            ) = client_rx.recv().await.unwrap().unwrap();
+            assert_eq!(players, vec![player_name.clone()]);
+            let leave_msg = ClientMsg::Room(
+                id,
+                RoomMsg::Leave {
+                    room: room_id,
+                    name: player_name,
+                },
+            );
+            broker_tx.send(leave_msg).await.unwrap();
+            // drop the sending channel so the broker ends
+            drop(broker_tx);
+
+            broker_task.await;
+        })
+    }
+
+    #[test]
+    fn disconnects_are_announced_apart_from_leaves() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, mut channels) =
+                room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+
+            let disconnect = RoomMsg::Disconnect {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[2].id, disconnect))
+                .await
+                .unwrap();
+            drop(channels.pop());
+            for chan in &channels[..2] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Disconnected(Arc::from("Queequeg"))
+                );
+            }
+            // their seat is held for them to reconnect to, even in the lobby
+            let roster = roster_of(&["Ahab", "Ishmael", "Queequeg"], &["Queequeg"]);
+            for chan in &channels[..2] {
+                assert_eq!(chan.recv().await.unwrap(), roster);
+            }
+            let leave = RoomMsg::Leave {
+                room,
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, leave))
+                .await
+                .unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                left("Ishmael", LeaveReason::Voluntary)
+            );
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                roster_of(&["Ahab", "Queequeg"], &["Queequeg"])
+            );
+
+            drop(broker_tx);
+            assert_eq!(broker_task.await.unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn room_messages_only_speak_for_their_own_seat() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+
+            // Ishmael passing himself off as the host
+            let kick = RoomMsg::Kick {
+                room: room.clone(),
+                by: "Ahab".to_string(),
+                target: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, kick))
+                .await
+                .unwrap();
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, leave.clone()))
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, leave))
+                .await
+                .unwrap();
+            for chan in &channels[1..] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Ahab", LeaveReason::Voluntary)
+                );
+                // then the roster and the new host, hanging up before they are sent would be a dropped connection
+                drain(chan, Duration::from_millis(50)).await;
+            }
+
+            drop(broker_tx);
+            drop(channels);
+            let rooms = broker_task.await.unwrap();
+            let names = rooms.get_room(&room).unwrap().names().collect::<Vec<_>>();
+            assert_eq!(names, ["Ishmael", "Queequeg"]);
+        })
+    }
+
+    #[test]
+    fn seats_are_looked_up_by_name() {
+        let (mut rooms, _, room_id, _channels) =
+            table_with(&["Ahab", "Ishmael", "Queequeg", "Starbuck", "Stubb"]);
+        rooms.try_remove_player(&"Ishmael".to_string(), room_id.clone());
+        let room = rooms.get_room_mut(&room_id).unwrap();
+        room.rename(2, &"Mr Starbuck".to_string());
+        room.remove(&"Ahab".to_string());
+
+        let names = room.names().collect::<Vec<_>>();
+        assert_eq!(names, ["Queequeg", "Mr Starbuck", "Stubb"]);
+        assert_eq!(room.seats.len(), names.len());
+        for (seat, name) in names.iter().enumerate() {
+            assert_eq!(room.seat_of(name), Some(seat));
+            assert_eq!(room.seat_like(&name.to_uppercase()), Some(seat));
+            assert_eq!(room.seat_of(&name.to_uppercase()), None);
+        }
+        for gone in ["Ahab", "Ishmael", "Starbuck"] {
+            assert_eq!(room.seat_like(gone), None);
+        }
+    }
+
+    #[test]
+    fn players_are_kept_in_joining_order() {
+        smol::block_on(async {
+            let (sender, ahab) = channel::unbounded();
+            let mut room = Room::new("Ahab".to_string(), sender, 0, RoomSettings::default());
+            let mut channels = vec![ahab];
+            for (id, name) in ["Ishmael", "Queequeg", "Starbuck"].iter().enumerate() {
+                let (sender, rx) = channel::unbounded();
+                room.add(Player {
+                    id: id as SessionToken + 1,
+                    name: name.to_string(),
+                    sender,
+                });
+                channels.push(rx);
+            }
+            assert_eq!(room.get("Queequeg").map(|player| player.id), Some(2));
+            assert!(room.get("queequeg").is_none());
+            assert!(room.activity.contains_key("Starbuck"));
+
+            room.ready.insert("Ahab".to_string());
+            room.scores.insert("Ahab".to_string(), 2);
+            let removed = room.remove(&"Ahab".to_string()).unwrap();
+            assert_eq!(removed.id, 0);
+            assert!(room.remove(&"Ahab".to_string()).is_none());
+            assert_eq!(*room.host(), "Ishmael");
+            assert!(room.ready.is_empty() && room.scores.is_empty());
+            assert!(!room.activity.contains_key("Ahab"));
+            assert_eq!(
+                room.names().collect::<Vec<_>>(),
+                ["Ishmael", "Queequeg", "Starbuck"]
+            );
+
+            // everyone still seated is reached, in the same order
+            drop(channels.remove(2));
+            let missed = room.broadcast(BrokerMsg::AlreadyStarted);
+            assert_eq!(missed, [1]);
+            for chan in [&channels[1], &channels[2]] {
+                assert_eq!(chan.try_recv().unwrap(), BrokerMsg::AlreadyStarted);
+            }
+            assert!(channels[0].try_recv().is_err());
+        })
+    }
+
+    #[test]
+    fn names_can_be_fixed_in_the_lobby() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmial", "Queequeg"]).await;
+            // Ishmial is the one fixing their name
+            let rename = |from: &str, to: &str| {
+                let rename = RoomMsg::Rename {
+                    room: room.clone(),
+                    from: from.to_string(),
+                    to: to.to_string(),
+                };
+                ClientMsg::Room(channels[1].id, rename)
+            };
+
+            broker_tx.send(rename("Ishmial", "ahab")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::UsernameTaken)
+            );
+            broker_tx.send(rename("Ishmial", " ")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::InvalidUsername(UsernameErr::Empty))
+            );
+
+            broker_tx
+                .send(rename("Ishmial", " Ishmael "))
+                .await
+                .unwrap();
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Renamed {
+                        from: Arc::from("Ishmial"),
+                        to: Arc::from("Ishmael")
+                    }
+                );
+            }
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&names, &[]));
+            }
+
+            // roles are dealt by name, so not mid-round
+            start(&broker_tx, &room, &channels).await;
+            broker_tx.send(rename("Ishmael", "Ishmael2")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::RoundInProgress)
+            );
+
+            drop(broker_tx);
+            let rooms = broker_task.await.unwrap();
+            let room = &rooms.get_room(&room).unwrap();
+            assert_eq!(room.names().collect::<Vec<_>>(), names);
+            assert!(room.activity.contains_key("Ishmael"));
+        })
+    }
+
+    #[test]
+    fn the_host_is_handed_over_once() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let transfer = |from: usize, to: &str| {
+                let transfer = RoomMsg::TransferHost {
+                    room: room.clone(),
+                    from: names[from].to_string(),
+                    to: to.to_string(),
+                };
+                ClientMsg::Room(channels[from].id, transfer)
+            };
+            let leave = |seat: usize| {
+                let leave = RoomMsg::Leave {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                };
+                ClientMsg::Room(channels[seat].id, leave)
+            };
+            let host_changed = |host: &str| BrokerMsg::HostChanged {
+                host: Arc::from(host),
+            };
+
+            broker_tx.send(transfer(1, "Queequeg")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheHost)
+            );
+            broker_tx.send(transfer(0, "Pip")).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoSuchPlayer)
+            );
+            broker_tx.send(transfer(0, "Queequeg")).await.unwrap();
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), host_changed("Queequeg"));
+            }
+
+            // the old host leaving is just a leave now
+            broker_tx.send(leave(0)).await.unwrap();
+            for chan in &channels[1..] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Ahab", LeaveReason::Voluntary)
+                );
+            }
+            let seated = ["Ishmael", "Queequeg", "Starbuck"];
+            let mut roster = roster_of(&seated, &[]);
+            if let BrokerMsg::Roster { host, .. } = &mut roster {
+                *host = "Queequeg".to_string();
+            }
+            for chan in &channels[1..] {
+                assert_eq!(chan.recv().await.unwrap(), roster);
+            }
+
+            // while the new host leaving hands the room to whoever has been in it the longest
+            broker_tx.send(leave(2)).await.unwrap();
+            for chan in [&channels[1], &channels[3]] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Queequeg", LeaveReason::Voluntary)
+                );
+            }
+            for chan in [&channels[1], &channels[3]] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    roster_of(&["Ishmael", "Starbuck"], &[])
+                );
+            }
+            for chan in [&channels[1], &channels[3]] {
+                assert_eq!(chan.recv().await.unwrap(), host_changed("Ishmael"));
+            }
+            for chan in &channels[1..] {
+                assert!(chan.try_recv().is_err());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn the_host_can_kick_players() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let kick = |by: usize, target: &str| {
+                let kick = RoomMsg::Kick {
+                    room: room.clone(),
+                    by: names[by].to_string(),
+                    target: target.to_string(),
+                };
+                ClientMsg::Room(channels[by].id, kick)
+            };
+            broker_tx.send(kick(1, "Starbuck")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheHost)
+            );
+            broker_tx.send(kick(0, "Pip")).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoSuchPlayer)
+            );
+
+            let dealt = start(&broker_tx, &room, &channels).await;
+            let spy = dealt
+                .iter()
+                .position(|start| start.assignment.is_none())
+                .unwrap();
+            // someone other than the host or the spy, so the round carries on without them
+            let target = (1..names.len()).find(|i| *i != spy).unwrap();
+            broker_tx.send(kick(0, names[target])).await.unwrap();
+            let kicked = BrokerMsg::Kicked {
+                target: Arc::from(names[target]),
+                by: Arc::from("Ahab"),
+            };
+            assert_eq!(channels[target].recv().await.unwrap(), kicked);
+            let rest = (0..names.len())
+                .filter(|i| *i != target)
+                .collect::<Vec<_>>();
+            let seated = rest.iter().map(|i| names[*i]).collect::<Vec<_>>();
+            // the rest of the room hears it as a leave
+            for i in &rest {
+                assert_eq!(
+                    channels[*i].recv().await.unwrap(),
+                    left(names[target], LeaveReason::Kicked)
+                );
+            }
+            let roster = mid_round(roster_of(&seated, &[]));
+            for i in &rest {
+                assert_eq!(channels[*i].recv().await.unwrap(), roster);
+            }
+            assert!(channels[target].recv().await.is_err());
+
+            // kicking the spy is the same as them leaving
+            if spy != 0 {
+                broker_tx.send(kick(0, names[spy])).await.unwrap();
+                for i in &rest {
+                    let expected = if *i == spy {
+                        BrokerMsg::Kicked {
+                            target: Arc::from(names[spy]),
+                            by: Arc::from("Ahab"),
+                        }
+                    } else {
+                        left(names[spy], LeaveReason::Kicked)
+                    };
+                    assert_eq!(channels[*i].recv().await.unwrap(), expected);
+                }
+                let rest = rest.iter().filter(|i| **i != spy).collect::<Vec<_>>();
+                for i in &rest {
+                    assert!(matches!(
+                        channels[**i].recv().await.unwrap(),
+                        BrokerMsg::Roster { .. }
+                    ));
+                }
+                for i in &rest {
+                    assert!(matches!(
+                        channels[**i].recv().await.unwrap(),
+                        BrokerMsg::GameAborted {
+                            reason: AbortReason::SpyLeft,
+                            ..
+                        }
+                    ));
+                }
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn late_joiners_watch_until_the_round_ends() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, mut channels) = room_with(&broker_tx, &names).await;
+            let dealt = start(&broker_tx, &room, &channels).await;
+            let spy = dealt
+                .iter()
+                .position(|start| start.assignment.is_none())
+                .unwrap();
+
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (connected, rx, rosters) =
+                join_watched(&broker_tx, "Starbuck", &room, &watchers).await;
+            let everyone = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let mut watching = mid_round(roster_of(&everyone, &[]));
+            if let BrokerMsg::Roster { players, .. } = &mut watching {
+                players[3].watching = true;
+            }
+            assert!(rosters.iter().all(|roster| *roster == watching));
+            assert!(connected.in_game);
+            assert_eq!(connected.watching, vec!["Starbuck".to_string()]);
+            channels.push(Seat {
+                id: connected.id,
+                rx,
+            });
+
+            let guess = RoomMsg::GuessLocation {
+                room: room.clone(),
+                name: names[spy].to_string(),
+                location: String::new(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[spy].id, guess))
+                .await
+                .unwrap();
+            for chan in &channels {
+                match chan.recv().await.unwrap() {
+                    BrokerMsg::Revealed(summary) => {
+                        assert_eq!(summary.winner, Some(Winner::NonSpies));
+                        // there was nothing for them to win
+                        let late = summary.scores.get("Starbuck").copied();
+                        assert_eq!(late.unwrap_or(0), 0);
+                    }
+                    msg => panic!("expected the reveal, got {:?}", msg),
+                }
+                // and now they're a player like everyone else
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&everyone, &[]));
+            }
+
+            let dealt = start(&broker_tx, &room, &channels).await;
+            assert_eq!(dealt[3].round, 2);
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn auto_renaming_rooms_suffix_duplicate_names() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                auto_rename: true,
+                ..RoomSettings::default()
+            };
+            let (host, _host_rx) = join_with(&broker_tx, "Alex", None, settings).await.unwrap();
+            assert!(host.settings.auto_rename);
+            let room = host.room_id.clone();
+            let mut seats = vec![];
+            for expected in ["Alex (2)", "Alex (3)"] {
+                let (connected, rx) = join(&broker_tx, "Alex", Some(room.clone())).await.unwrap();
+                assert_eq!(connected.name, expected);
+                assert_eq!(connected.players.last().unwrap(), expected);
+                seats.push((connected, rx));
+            }
+
+            // the seat is taken back, not renamed, by whoever holds its token
+            let retake = Join {
+                name: "alex (3)".to_string(),
+                room: Some(room.clone()),
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: Some(seats[1].0.reconnect_token.clone()),
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            let (retaken, _rx) = send_join(&broker_tx, retake).await.unwrap();
+            assert_eq!(retaken.name, "Alex (3)");
+
+            // freed suffixes and the original name go to the next to ask
+            for gone in [&seats[0].0, &host] {
+                let leave = RoomMsg::Leave {
+                    room: room.clone(),
+                    name: gone.name.clone(),
+                };
+                broker_tx
+                    .send(ClientMsg::Room(gone.id, leave))
+                    .await
+                    .unwrap();
+                let (connected, _rx) = join(&broker_tx, "Alex", Some(room.clone())).await.unwrap();
+                assert_eq!(connected.name, gone.name);
+            }
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn held_names_are_freed_on_join_once_the_grace_is_up() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                reconnect_grace: Duration::from_millis(200),
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, mut channels) = room_with(&broker_tx, &names).await;
+            let disconnect = RoomMsg::Disconnect {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[2].id, disconnect))
+                .await
+                .unwrap();
+            drop(channels.pop());
+            for chan in &channels {
+                chan.recv().await.unwrap();
+                chan.recv().await.unwrap();
+            }
+
+            // Queequeg is coming back, nobody else should be pointed at another name for them
+            let taken = join(&broker_tx, "Queequeg", Some(room.clone())).await;
+            assert_eq!(
+                taken.unwrap_err(),
+                JoinErr::UsernameTaken { suggested: None }
+            );
+
+            // long before the next sweep
+            Timer::after(Duration::from_millis(250)).await;
+            let joining = join(&broker_tx, "Queequeg", Some(room.clone()));
+            let broadcasts = async {
+                let mut msgs = vec![];
+                for _ in 0..4 {
+                    msgs.push(channels[0].recv().await.unwrap());
+                }
+                msgs
+            };
+            let (joined, msgs) = future::zip(joining, broadcasts).await;
+            assert_eq!(joined.unwrap().0.players, names);
+            assert_eq!(
+                msgs,
+                [
+                    left("Queequeg", LeaveReason::Disconnected),
+                    roster_of(&names[..2], &[]),
+                    BrokerMsg::Join(Arc::from("Queequeg")),
+                    roster_of(&names, &[]),
+                ]
+            );
+
+            drop(broker_tx);
+            drop(channels);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn seats_of_gone_clients_are_held_then_let_go() {
+        smol::block_on(async {
+            let (mut rooms, mut index, _, mut channels) =
+                table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            drop(channels.pop());
+            let mut config = BrokerConfig::default();
+            let mut deadlines = Deadlines::new();
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    BrokerMsg::Disconnected(Arc::from("Queequeg"))
+                );
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    roster_of(&["Ahab", "Ishmael", "Queequeg"], &["Queequeg"])
+                );
+            }
+
+            // the seat is only held once
+            config.reconnect_grace = Duration::ZERO;
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    left("Queequeg", LeaveReason::Disconnected)
+                );
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    roster_of(&["Ahab", "Ishmael"], &[])
+                );
+                assert!(chan.try_recv().is_err());
+            }
+            index.assert_consistent(&rooms);
+        })
+    }
+
+    #[test]
+    fn held_seats_are_let_go_after_the_round() {
+        smol::block_on(async {
+            let (mut rooms, mut index, room_id, channels) =
+                table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let config = BrokerConfig::default();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            let dropped_at = Instant::now() - config.reconnect_grace;
+            room.disconnected.insert("Queequeg".to_string(), dropped_at);
+            // still within the grace period
+            room.disconnected
+                .insert("Ishmael".to_string(), Instant::now());
+            let mut deadlines = Deadlines::new();
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+
+            for chan in &channels[..2] {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    left("Queequeg", LeaveReason::Disconnected)
+                );
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    roster_of(&["Ahab", "Ishmael"], &["Ishmael"])
+                );
+            }
+            index.assert_consistent(&rooms);
+        })
+    }
+
+    #[test]
+    fn seats_are_held_through_a_dropped_connection_mid_round() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, mut channels) = room_with(&broker_tx, &names[..2]).await;
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (connected, rx, _) = join_watched(&broker_tx, names[2], &room, &watchers).await;
+            let reconnect_token = connected.reconnect_token;
+            channels.push(Seat {
+                id: connected.id,
+                rx,
+            });
+            let starts = start(&broker_tx, &room, &channels).await;
+
+            let disconnect = RoomMsg::Disconnect {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(connected.id, disconnect))
+                .await
+                .unwrap();
+            drop(channels.pop());
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Disconnected(Arc::from("Queequeg"))
+                );
+            }
+            let roster = mid_round(roster_of(&names, &["Queequeg"]));
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), roster);
+            }
+
+            // the name alone doesn't get the seat back
+            let rejoin = |reconnect_token: Option<&str>| Join {
+                name: "queequeg".to_string(),
+                room: Some(room.clone()),
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: reconnect_token.map(str::to_string),
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            for token in [None, Some("not it")] {
+                let refused = send_join(&broker_tx, rejoin(token)).await;
+                assert_eq!(
+                    refused.unwrap_err(),
+                    JoinErr::UsernameTaken { suggested: None }
+                );
+            }
+
+            // coming back with the token hands them the role they were dealt
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let joining = send_join(&broker_tx, rejoin(Some(&reconnect_token)));
+            let broadcasts = async {
+                for chan in &watchers {
+                    assert_eq!(
+                        chan.recv().await.unwrap(),
+                        BrokerMsg::Reconnected {
+                            name: Arc::from("Queequeg")
+                        }
+                    );
+                }
+                for chan in &watchers {
+                    assert_eq!(
+                        chan.recv().await.unwrap(),
+                        mid_round(roster_of(&names, &[]))
+                    );
+                }
+            };
+            let (joined, ()) = future::zip(joining, broadcasts).await;
+            let (connected, rx) = joined.unwrap();
+            assert_eq!(connected.name, "Queequeg");
+            assert_eq!(connected.players, names);
+            assert_eq!(connected.reconnect_token, reconnect_token);
+            assert!(connected.in_game);
+            assert_eq!(rx.recv().await.unwrap(), mid_round(roster_of(&names, &[])));
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                BrokerMsg::Started(starts[2].clone())
+            );
+
+            drop(broker_tx);
+            drop(rx);
+            let rooms = HashMap::from(broker_task.await.unwrap());
+            assert!(rooms[&room].disconnected.is_empty());
+        })
+    }
+
+    #[test]
+    fn seats_are_resumed_with_just_the_reconnect_token() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names[..2]).await;
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (connected, rx, _) = join_watched(&broker_tx, names[2], &room, &watchers).await;
+            let disconnect = RoomMsg::Disconnect {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(connected.id, disconnect))
+                .await
+                .unwrap();
+            drop(rx);
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Disconnected(Arc::from("Queequeg"))
+                );
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&names, &["Queequeg"]));
+            }
+
+            assert_eq!(
+                resume(&broker_tx, "not it").await.unwrap_err(),
+                JoinErr::NoSuchSeat
+            );
+            let (resumed, rx) = resume(&broker_tx, &connected.reconnect_token)
+                .await
+                .unwrap();
+            assert_eq!(resumed.room_id, room);
+            assert_eq!(resumed.name, "Queequeg");
+            assert_eq!(resumed.phase, Phase::Lobby);
+            assert_ne!(resumed.id, connected.id);
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Reconnected {
+                        name: Arc::from("Queequeg")
+                    }
+                );
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&names, &[]));
+            }
+            assert_eq!(rx.recv().await.unwrap(), roster_of(&names, &[]));
+
+            // leaving gives the seat up, token and all
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(resumed.id, leave))
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Queequeg", LeaveReason::Voluntary)
+                );
+            }
+            assert_eq!(
+                resume(&broker_tx, &connected.reconnect_token)
+                    .await
+                    .unwrap_err(),
+                JoinErr::NoSuchSeat
+            );
+
+            drop((broker_tx, channels, rx));
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn joins_are_only_announced_once_the_player_is_seated() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, mut channels) =
+                room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            let taken = join(&broker_tx, "ishmael", Some(room.clone())).await;
+            assert!(matches!(taken.unwrap_err(), JoinErr::UsernameTaken { .. }));
+            let invalid = join(&broker_tx, " ", Some(room.clone())).await;
+            assert!(matches!(invalid.unwrap_err(), JoinErr::InvalidUsername(_)));
+            for chan in &channels {
+                assert!(chan.try_recv().is_err());
+            }
+
+            // Queequeg's connection is gone without a word, which doesn't stop anyone joining
+            drop(channels.pop());
+            let (connected, rx) = join(&broker_tx, "Starbuck", Some(room.clone()))
+                .await
+                .unwrap();
+            let everyone = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            assert_eq!(connected.players, everyone);
+            let rest = ["Ahab", "Ishmael", "Starbuck"];
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Join(Arc::from("Starbuck"))
+                );
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&everyone, &[]));
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Queequeg", LeaveReason::Disconnected)
+                );
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&rest, &[]));
+            }
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                left("Queequeg", LeaveReason::Disconnected)
+            );
+            assert_eq!(rx.recv().await.unwrap(), roster_of(&rest, &[]));
+
+            drop((broker_tx, channels, rx));
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn rooms_seat_at_most_twenty() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = (1..=MAX_ROOM_PLAYERS)
+                .map(|n| format!("Sailor {}", n))
+                .collect::<Vec<_>>();
+            let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+            let (room, channels) = room_with(&broker_tx, &names).await;
+
+            let refused = join(&broker_tx, "Stowaway", Some(room.clone())).await;
+            let full = ROOM_CAPACITY.check(21).unwrap_err();
+            assert_eq!(refused.unwrap_err(), JoinErr::RoomFull(full));
+            // nobody heard about the player who wasn't seated
+            for chan in &channels {
+                assert!(chan.try_recv().is_err());
+            }
+            // a freed seat can be taken, by someone who is told everyone in the order they joined
+            let last = MAX_ROOM_PLAYERS - 1;
+            let watchers = channels[..last].iter().collect::<Vec<_>>();
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: names[last].to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[last].id, leave))
+                .await
+                .unwrap();
+            for chan in &watchers {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left(names[last], LeaveReason::Voluntary)
+                );
+                assert!(matches!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Roster { .. }
+                ));
+            }
+            let (connected, _rx, _) = join_watched(&broker_tx, "Stowaway", &room, &watchers).await;
+            let mut expected = names[..last].to_vec();
+            expected.push("Stowaway");
+            assert_eq!(connected.players, expected);
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn rooms_come_back_after_a_restart() {
+        smol::block_on(async {
+            let dir = std::env::temp_dir().join(format!("spyfall-restart-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let config = || BrokerConfig {
+                snapshot_path: Some(dir.join("rooms.json")),
+                snapshot_interval: Duration::from_millis(20),
+                ..BrokerConfig::default()
+            };
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, config()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (host, rx) = join(&broker_tx, names[0], None).await.unwrap();
+            let room = host.room_id.clone();
+            let mut channels = vec![Seat { id: host.id, rx }];
+            let mut seated = vec![host];
+            for name in &names[1..] {
+                let watchers = channels.iter().collect::<Vec<_>>();
+                let (connected, rx, _) = join_watched(&broker_tx, name, &room, &watchers).await;
+                channels.push(Seat {
+                    id: connected.id,
+                    rx,
+                });
+                seated.push(connected);
+            }
+            deal_and_reveal(&broker_tx, &room, &names, &channels).await;
+            // a snapshot or two after the round
+            Timer::after(Duration::from_millis(100)).await;
+            drop((broker_tx, channels));
+            broker_task.await.unwrap();
+
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, config()));
+            let (resumed, rx) = resume(&broker_tx, &seated[1].reconnect_token)
+                .await
+                .unwrap();
+            assert_eq!(resumed.room_id, room);
+            assert_eq!(resumed.name, "Ishmael");
+            assert_eq!(resumed.host, "Ahab");
+            assert_eq!(resumed.players, names);
+            // everyone else's seat is held for them to come back to
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                roster_of(&names, &["Ahab", "Queequeg"])
+            );
+            let taken = join(&broker_tx, "Queequeg", Some(room.clone())).await;
+            assert_eq!(
+                taken.unwrap_err(),
+                JoinErr::UsernameTaken { suggested: None }
+            );
+
+            // along with what was played before the restart
+            let get_history = RoomMsg::GetHistory {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(resumed.id, get_history))
+                .await
+                .unwrap();
+            match rx.recv().await.unwrap() {
+                BrokerMsg::History(history) => assert_eq!(history.len(), 1),
+                other => panic!("expected the history, got {:?}", other),
+            }
+            let get_scores = RoomMsg::GetScores {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(resumed.id, get_scores))
+                .await
+                .unwrap();
+            match rx.recv().await.unwrap() {
+                // the non-spies won a point each
+                BrokerMsg::Scores(scores) => assert_eq!(scores.values().sum::<u32>(), 2),
+                other => panic!("expected the scores, got {:?}", other),
+            }
+
+            drop((broker_tx, rx));
+            broker_task.await.unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+    }
+
+    #[test]
+    fn rooms_can_ask_for_more_players() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                min_players: 4,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let asking = |min_players| RoomSettings {
+                min_players,
+                ..RoomSettings::default()
+            };
+            assert_eq!(
+                join_with(&broker_tx, "Ahab", None, asking(2))
+                    .await
+                    .unwrap_err(),
+                JoinErr::InvalidSettings(SettingsErr::TooFewPlayers { min: 3 })
+            );
+            // the server's minimum can't be asked below either
+            let (connected, _rx) = join_with(&broker_tx, "Ahab", None, asking(3))
+                .await
+                .unwrap();
+            assert_eq!(connected.min_players, 4);
+
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, mut channels) = room_with_settings(&broker_tx, &names, asking(5)).await;
+            let begin = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, begin))
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::NotEnoughPlayers { have: 3, need: 5 }
+                );
+            }
+
+            for name in ["Starbuck", "Stubb"] {
+                let watchers = channels.iter().collect::<Vec<_>>();
+                let (connected, rx, _) = join_watched(&broker_tx, name, &room, &watchers).await;
+                assert_eq!(connected.min_players, 5);
+                channels.push(Seat {
+                    id: connected.id,
+                    rx,
+                });
+            }
+            start(&broker_tx, &room, &channels).await;
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn two_players_in_room_cant_start_game() {
+        smol::block_on(async {
+            let player_one = "Ahab".to_string();
+            let player_two = "Ishmael".to_string();
+            let (client_tx, client_rx) = channel::bounded(1);
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let join_msg = ClientMsg::Join(
+                Join {
+                    name: player_one.clone(),
+                    room: None,
+                    settings: RoomSettings::default(),
+                    password: None,
+                    reconnect_token: None,
+                    requested_code: None,
+                    invite: None,
+                    protocol_version: PROTOCOL_VERSION,
+                    peer: None,
+                },
+                client_tx,
+            );
+            broker_tx.send(join_msg).await.unwrap();
+            let (
+                Connected {
+                    room_id,
+                    players,
+                    id,
+                    ..
+                },
+                player_one_broker_stream,
+            ) = client_rx.recv().await.unwrap().unwrap();
+            assert_eq!(players, vec![player_one.clone()]);
+            let (client_tx, client_rx) = channel::bounded(1);
+            let snd_msg = ClientMsg::Join(
+                Join {
+                    name: player_two.clone(),
+                    room: Some(room_id.clone()),
+                    settings: RoomSettings::default(),
+                    password: None,
+                    reconnect_token: None,
+                    requested_code: None,
+                    invite: None,
+                    protocol_version: PROTOCOL_VERSION,
+                    peer: None,
+                },
+                client_tx,
+            );
+            broker_tx.send(snd_msg).await.unwrap();
+            // the join is only answered once the room has been told about it
+            assert_eq!(
+                player_one_broker_stream.recv().await.unwrap(),
+                BrokerMsg::Join(Arc::from(player_two.clone()))
+            );
+            assert_eq!(
+                player_one_broker_stream.recv().await.unwrap(),
+                roster_of(&[&player_one, &player_two], &[])
+            );
+            let (
+                Connected {
+                    room_id,
+                    players,
+                    host,
+                    ..
+                },
+                player_two_broker_stream,
+            ) = client_rx.recv().await.unwrap().unwrap();
+            assert_eq!(players, vec![player_one.clone(), player_two.clone()]);
+            assert_eq!(host, player_one);
+
+            let start = RoomMsg::Start {
+                room: room_id.clone(),
+                name: player_one.clone(),
+            };
+            broker_tx.send(ClientMsg::Room(id, start)).await.unwrap();
+            for chan in &[player_one_broker_stream, player_two_broker_stream] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::NotEnoughPlayers { have: 2, need: 3 }
+                );
+            }
+
+            // drop the sending channel so the broker ends
+            drop(broker_tx);
+
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn ready_toggles_are_debounced() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+
+            for i in 0..20 {
+                let set_ready = RoomMsg::SetReady {
+                    room: room.clone(),
+                    name: "Ahab".to_string(),
+                    ready: i % 2 == 0,
+                };
+                broker_tx
+                    .send(ClientMsg::Room(channels[0].id, set_ready))
+                    .await
+                    .unwrap();
+            }
+
+            // drain concurrently, a full channel blocks the broadcast to everyone after it
+            let idle = STATE_BROADCAST_DEBOUNCE + Duration::from_millis(500);
+            let drains = channels
+                .into_iter()
+                .map(|chan| smol::spawn(async move { drain(&chan, idle).await }))
+                .collect::<Vec<_>>();
+            for drained in drains {
+                let msgs = drained.await;
+                assert!(msgs.len() == 2 || msgs.len() == 4, "{:?}", msgs);
+                // the last toggle marked Ahab as not ready
+                let last = BrokerMsg::ReadyChanged {
+                    player: Arc::from("Ahab"),
+                    ready: false,
+                };
+                assert_eq!(msgs[msgs.len() - 2..], [last, roster_of(&names, &[])]);
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn rooms_can_wait_on_everyone_being_ready() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                require_ready: true,
+                ..RoomSettings::default()
+            };
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+            let start = ClientMsg::Room(
+                channels[0].id,
+                RoomMsg::Start {
+                    room: room.clone(),
+                    name: "Ahab".to_string(),
+                },
+            );
+
+            for seat in [0, 2] {
+                let set_ready = RoomMsg::SetReady {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                    ready: true,
+                };
+                broker_tx
+                    .send(ClientMsg::Room(channels[seat].id, set_ready))
+                    .await
+                    .unwrap();
+                for chan in &channels {
+                    assert!(matches!(
+                        chan.recv().await.unwrap(),
+                        BrokerMsg::ReadyChanged { .. }
+                    ));
+                    assert!(matches!(
+                        chan.recv().await.unwrap(),
+                        BrokerMsg::Roster { .. }
+                    ));
+                }
+            }
+            broker_tx.send(start.clone()).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotEveryoneReady(vec!["Ishmael".to_string()]))
+            );
+
+            let set_ready = RoomMsg::SetReady {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+                ready: true,
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, set_ready))
+                .await
+                .unwrap();
+            broker_tx.send(start).await.unwrap();
+            for chan in &channels {
+                let msgs = [
+                    chan.recv().await.unwrap(),
+                    chan.recv().await.unwrap(),
+                    chan.recv().await.unwrap(),
+                ];
+                assert!(matches!(msgs[2], BrokerMsg::Started(_)), "{:?}", msgs);
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn joiners_are_told_who_is_ready() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael"]).await;
+            let set_ready = RoomMsg::SetReady {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+                ready: true,
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, set_ready))
+                .await
+                .unwrap();
+            for chan in &channels {
+                chan.recv().await.unwrap();
+                chan.recv().await.unwrap();
+            }
+
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (connected, _rx, rosters) =
+                join_watched(&broker_tx, "Queequeg", &room, &watchers).await;
+            assert_eq!(connected.host, "Ahab");
+            assert_eq!(connected.ready, ["Ishmael"]);
+            assert!(!connected.in_game);
+            match &rosters[0] {
+                BrokerMsg::Roster { players, .. } => {
+                    let ready = players.iter().map(|entry| entry.ready).collect::<Vec<_>>();
+                    assert_eq!(ready, [false, true, false]);
+                }
+                msg => panic!("expected the roster, got {:?}", msg),
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn start_flushes_debounced_state() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+
+            for ready in [true, false] {
+                let set_ready = RoomMsg::SetReady {
+                    room: room.clone(),
+                    name: "Ishmael".to_string(),
+                    ready,
+                };
+                broker_tx
+                    .send(ClientMsg::Room(channels[1].id, set_ready))
+                    .await
+                    .unwrap();
+            }
+            let start = RoomMsg::Start {
+                room,
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, start))
+                .await
+                .unwrap();
+
+            for ready in [true, false] {
+                let mut roster = roster_of(&["Ahab", "Ishmael", "Queequeg"], &[]);
+                if let BrokerMsg::Roster { players, .. } = &mut roster {
+                    players[1].ready = ready;
+                }
+                for chan in &channels {
+                    assert_eq!(
+                        chan.recv().await.unwrap(),
+                        BrokerMsg::ReadyChanged {
+                            player: Arc::from("Ishmael"),
+                            ready
+                        }
+                    );
+                    assert_eq!(chan.recv().await.unwrap(), roster);
+                }
+            }
+            for chan in &channels {
+                assert!(matches!(chan.recv().await.unwrap(), BrokerMsg::Started(_)));
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn history_is_sent_only_to_the_requester() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+
+            let start = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, start))
+                .await
+                .unwrap();
+            let mut spies = vec![];
+            let mut location = None;
+            for (name, chan) in names.iter().zip(&channels) {
+                match chan.recv().await.unwrap() {
+                    BrokerMsg::Started(Start {
+                        assignment: Some(assignment),
+                        ..
+                    }) => location = Some(assignment.location),
+                    BrokerMsg::Started(Start {
+                        assignment: None, ..
+                    }) => spies.push(Arc::from(*name)),
+                    msg => panic!("expected a Started message, got {:?}", msg),
+                }
+            }
+            let spy = names.iter().position(|name| **name == *spies[0]).unwrap();
+            misguess(&broker_tx, &room, &spies[0], spy, &channels).await;
+
+            let get_history = RoomMsg::GetHistory {
+                room,
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, get_history))
+                .await
+                .unwrap();
+            match channels[1].recv().await.unwrap() {
+                BrokerMsg::History(history) => {
+                    assert_eq!(history.len(), 1);
+                    assert_eq!(history[0].spies, spies);
+                    assert_eq!(Some(&history[0].location), location.as_ref());
+                    assert_eq!(history[0].winner, Some(Winner::NonSpies));
+                    assert_eq!(history[0].scores.len(), names.len());
+                }
+                msg => panic!("expected the room history, got {:?}", msg),
+            }
+            for chan in [&channels[0], &channels[2]] {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn sender_for_finds_players_by_name() {
+        let (mut rooms, _, room_id, channels) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+        let room = rooms.get_room_mut(&room_id).unwrap();
+        let sender = room.sender_for(&"Ishmael".to_string()).unwrap();
+        sender
+            .try_send(BrokerMsg::NotEnoughPlayers { have: 2, need: 3 })
+            .unwrap();
+        assert_eq!(
+            channels[1].try_recv().unwrap(),
+            BrokerMsg::NotEnoughPlayers { have: 2, need: 3 }
+        );
+        assert!(room.sender_for(&"Stubb".to_string()).is_none());
+
+        // the right sender is still found once the seats shift
+        rooms.try_remove_player(&"Ahab".to_string(), room_id.clone());
+        let room = rooms.get_room(&room_id).unwrap();
+        let sender = room.sender_for(&"Queequeg".to_string()).unwrap();
+        sender
+            .try_send(BrokerMsg::NotEnoughPlayers { have: 2, need: 3 })
+            .unwrap();
+        assert_eq!(
+            channels[2].try_recv().unwrap(),
+            BrokerMsg::NotEnoughPlayers { have: 2, need: 3 }
+        );
+        assert!(channels[1].try_recv().is_err());
+    }
+
+    #[test]
+    fn scores_are_sent_only_to_the_requester() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+
+            let get_scores = RoomMsg::GetScores {
+                room,
+                name: "Queequeg".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[2].id, get_scores))
+                .await
+                .unwrap();
+            let scores = names.iter().map(|name| (Arc::from(*name), 0)).collect();
+            assert_eq!(channels[2].recv().await.unwrap(), BrokerMsg::Scores(scores));
+            for chan in &channels[..2] {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn clients_that_stop_reading_are_cut_off() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_chats_per_player: CLIENT_BUFFER + 1,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+
+            // Queequeg never reads, which doesn't hold up the others
+            for i in 0..=CLIENT_BUFFER {
+                let chat = RoomMsg::Chat {
+                    room: room.clone(),
+                    name: "Ahab".to_string(),
+                    text: i.to_string(),
+                };
+                broker_tx
+                    .send(ClientMsg::Room(channels[0].id, chat))
+                    .await
+                    .unwrap();
+                let said = BrokerMsg::Chat {
+                    from: Arc::from("Ahab"),
+                    text: Arc::from(i.to_string()),
+                };
+                for chan in &channels[..2] {
+                    assert_eq!(chan.recv().await.unwrap(), said);
+                }
+            }
+            // the chat that didn't fit cut Queequeg off, which the others hear about as a dropped connection
+            for chan in &channels[..2] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Queequeg", LeaveReason::Disconnected)
+                );
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    roster_of(&["Ahab", "Ishmael"], &[])
+                );
+            }
+            let scores = RoomMsg::GetScores {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, scores))
+                .await
+                .unwrap();
+            assert!(matches!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Scores(_)
+            ));
+            assert_eq!(channels[2].len(), CLIENT_BUFFER);
+            assert!(channels[2].is_closed());
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn chat_is_relayed_within_the_limit() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            let chat = |seat: &Seat, name: &str, text: String| {
+                let chat = RoomMsg::Chat {
+                    room: room.clone(),
+                    name: name.to_string(),
+                    text,
+                };
+                ClientMsg::Room(seat.id, chat)
+            };
+
+            broker_tx
+                .send(chat(&channels[0], "Ahab", "Call me Ishmael".to_string()))
+                .await
+                .unwrap();
+            let said = BrokerMsg::Chat {
+                from: Arc::from("Ahab"),
+                text: Arc::from("Call me Ishmael"),
+            };
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), said);
+            }
+
+            // outsiders are ignored, and overlong messages are refused to their sender alone
+            broker_tx
+                .send(chat(&channels[0], "Stubb", "hello?".to_string()))
+                .await
+                .unwrap();
+            broker_tx
+                .send(chat(&channels[1], "Ishmael", "a".repeat(501)))
+                .await
+                .unwrap();
+            let info = CHAT_LENGTH.check(501).unwrap_err();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::ChatTooLong(info))
+            );
+            for chan in [&channels[0], &channels[2]] {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            // control characters are taken out, which can leave nothing worth sending
+            let text = "Thar\u{7} she\r\nblows\u{1b}[2J".to_string();
+            broker_tx
+                .send(chat(&channels[2], "Queequeg", text))
+                .await
+                .unwrap();
+            broker_tx
+                .send(chat(&channels[2], "Queequeg", "\u{0} \u{7f}".to_string()))
+                .await
+                .unwrap();
+            let said = BrokerMsg::Chat {
+                from: Arc::from("Queequeg"),
+                text: Arc::from("Thar she\nblows[2J"),
+            };
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), said);
+                assert!(drain(chan, Duration::from_millis(50)).await.is_empty());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn every_limit_is_reported_as_configured() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_players: 3,
+                max_rooms_per_peer: 1,
+                max_chats_per_player: 1,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config.clone()));
+            let from_peer = |name: &str, room: Option<RoomId>| Join {
+                name: name.to_string(),
+                room,
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: Some(IpAddr::from([10, 0, 0, 1])),
+            };
+            let (Connected { room_id, id, .. }, rx) =
+                send_join(&broker_tx, from_peer("Ahab", None))
+                    .await
+                    .unwrap();
+            let channels = vec![Seat { id, rx }];
+
+            let long_name = "Ishmael".repeat(4);
+            assert_eq!(
+                join(&broker_tx, &long_name, Some(room_id.clone()))
+                    .await
+                    .unwrap_err(),
+                JoinErr::InvalidUsername(UsernameErr::TooLong(
+                    USERNAME_LENGTH.check(28).unwrap_err()
+                ))
+            );
+            assert_eq!(
+                send_join(&broker_tx, from_peer("Ishmael", None))
+                    .await
+                    .unwrap_err(),
+                JoinErr::RateLimited(config.creation_limit().check(2).unwrap_err())
+            );
+
+            let chat = |text: &str| {
+                let chat = RoomMsg::Chat {
+                    room: room_id.clone(),
+                    name: "Ahab".to_string(),
+                    text: text.to_string(),
+                };
+                ClientMsg::Room(channels[0].id, chat)
+            };
+            broker_tx.send(chat(&"a".repeat(501))).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::ChatTooLong(CHAT_LENGTH.check(501).unwrap_err()))
+            );
+            for text in ["Call me Ishmael", "again"] {
+                broker_tx.send(chat(text)).await.unwrap();
+            }
+            assert!(matches!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Chat { .. }
+            ));
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::ChatRateLimited(config.chat_limit().check(2).unwrap_err())
+            );
+
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (_, ishmael_rx, _) = join_watched(&broker_tx, "Ishmael", &room_id, &watchers).await;
+            let (_, queequeg_rx) = join(&broker_tx, "Queequeg", Some(room_id.clone()))
+                .await
+                .unwrap();
+            assert_eq!(
+                join(&broker_tx, "Starbuck", Some(room_id.clone()))
+                    .await
+                    .unwrap_err(),
+                JoinErr::ServerFull(config.player_limit().check(4).unwrap_err())
+            );
+
+            drop((broker_tx, channels, ishmael_rx, queequeg_rx));
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn chat_floods_are_dropped() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_chats_per_player: 2,
+                chat_window: Duration::from_millis(300),
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config.clone()));
+            let names = ["Ahab", "Ishmael"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let chat = |seat: usize, text: &str| {
+                let chat = RoomMsg::Chat {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                    text: text.to_string(),
+                };
+                ClientMsg::Room(channels[seat].id, chat)
+            };
+            let said = |seat: usize, text: &str| BrokerMsg::Chat {
+                from: Arc::from(names[seat]),
+                text: Arc::from(text),
+            };
+
+            for text in ["one", "two", "three"] {
+                broker_tx.send(chat(0, text)).await.unwrap();
+            }
+            let info = config.chat_limit().check(3).unwrap_err();
+            let flood = [said(0, "one"), said(0, "two")];
+            assert_eq!(drain(&channels[1], Duration::from_millis(50)).await, flood);
+            let told = drain(&channels[0], Duration::from_millis(50)).await;
+            assert_eq!(told[..2], flood);
+            assert_eq!(told[2..], [BrokerMsg::ChatRateLimited(info)]);
+
+            // only the flooder is held back, and only until their earlier messages are out of the window
+            broker_tx.send(chat(1, "four")).await.unwrap();
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), said(1, "four"));
+            }
+            Timer::after(config.chat_window).await;
+            broker_tx.send(chat(0, "five")).await.unwrap();
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), said(0, "five"));
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn rounds_end_when_they_cant_go_on() {
+        smol::block_on(async {
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (mut rooms, mut index, room_id, channels) = table_with(&names);
+            let mut deadlines = Deadlines::new();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.settings.round_secs = Some(60);
+            deal_bank(room, "Ahab");
+            let round_over = Deadline::RoundOver(room_id.clone());
+            deadlines.schedule(round_over.clone(), Instant::now() + Duration::from_secs(60));
+
+            // a spy walking out hands the round to everyone else, however many are left
+            remove_player(
+                &mut rooms,
+                &mut index,
+                &mut deadlines,
+                room_id.clone(),
+                "Ahab".to_string(),
+                LeaveReason::Voluntary,
+            )
+            .await
+            .unwrap();
+            assert!(!deadlines.contains(&round_over));
+            for chan in &channels[1..] {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    left("Ahab", LeaveReason::Voluntary)
+                );
+                chan.try_recv().unwrap();
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    BrokerMsg::HostChanged {
+                        host: Arc::from("Ishmael")
+                    }
+                );
+                match chan.try_recv().unwrap() {
+                    BrokerMsg::GameAborted { reason, summary } => {
+                        assert_eq!(reason, AbortReason::SpyLeft);
+                        assert_eq!(summary.winner, Some(Winner::NonSpies));
+                        assert_eq!(summary.spies, vec![Arc::from("Ahab")]);
+                    }
+                    msg => panic!("expected the abort, got {:?}", msg),
+                }
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    roster_of(&["Ishmael", "Queequeg", "Starbuck"], &[])
+                );
+            }
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            assert!(room.round.is_none());
+            assert_eq!(room.scores["Ishmael"], NON_SPY_WIN_POINTS);
+
+            // losing a non-spy only ends the round once there are too few to play
+            deal_bank(room, "Ishmael");
+            remove_player(
+                &mut rooms,
+                &mut index,
+                &mut deadlines,
+                room_id.clone(),
+                "Starbuck".to_string(),
+                LeaveReason::Voluntary,
+            )
+            .await
+            .unwrap();
+            for chan in &channels[1..3] {
+                chan.try_recv().unwrap();
+                chan.try_recv().unwrap();
+                match chan.try_recv().unwrap() {
+                    BrokerMsg::GameAborted { reason, summary } => {
+                        assert_eq!(reason, AbortReason::NotEnoughPlayers);
+                        assert_eq!(summary.winner, None);
+                    }
+                    msg => panic!("expected the abort, got {:?}", msg),
+                }
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    roster_of(&["Ishmael", "Queequeg"], &[])
+                );
+                assert!(chan.try_recv().is_err());
+            }
+            index.assert_consistent(&rooms);
+        })
+    }
+
+    #[test]
+    fn the_host_role_skips_held_seats() {
+        smol::block_on(async {
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (mut rooms, mut index, room_id, _channels) = table_with(&names);
+            let mut deadlines = Deadlines::new();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.disconnected
+                .insert("Ishmael".to_string(), Instant::now());
+
+            remove_player(
+                &mut rooms,
+                &mut index,
+                &mut deadlines,
+                room_id.clone(),
+                "Ahab".to_string(),
+                LeaveReason::Voluntary,
+            )
+            .await
+            .unwrap();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            assert_eq!(room.host(), "Queequeg");
+
+            // with only held seats left, one of them keeps the room
+            room.remove(&"Queequeg".to_string()).unwrap();
+            assert_eq!(room.host(), "Ishmael");
+        })
+    }
+
+    #[test]
+    fn watchers_dont_keep_a_round_going() {
+        smol::block_on(async {
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (mut rooms, mut index, room_id, channels) = table_with(&names);
+            let mut deadlines = Deadlines::new();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            assert_eq!(room.min_players, 3);
+            deal_bank(room, "Ahab");
+            // Starbuck sat down after the deal, so they only watch this round
+            room.round.as_mut().unwrap().dealt.remove("Starbuck");
+
+            remove_player(
+                &mut rooms,
+                &mut index,
+                &mut deadlines,
+                room_id.clone(),
+                "Queequeg".to_string(),
+                LeaveReason::Voluntary,
+            )
+            .await
+            .unwrap();
+            let room = rooms.get_room(&room_id).unwrap();
+            assert_eq!(room.players.len(), 3);
+            assert!(room.round.is_none());
+            for chan in [&channels[0], &channels[1], &channels[3]] {
+                let aborted =
+                    std::iter::from_fn(|| chan.try_recv().ok()).find_map(|msg| match msg {
+                        BrokerMsg::GameAborted { reason, .. } => Some(reason),
+                        _ => None,
+                    });
+                assert_eq!(aborted, Some(AbortReason::NotEnoughPlayers));
+            }
+            index.assert_consistent(&rooms);
+        })
+    }
+
+    #[test]
+    fn join_errors_serialize_with_what_to_try_instead() {
+        let taken = JoinErr::UsernameTaken {
+            suggested: Some("Ahab (2)".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&taken).unwrap(),
+            r#"{"UsernameTaken":{"suggested":"Ahab (2)"}}"#
+        );
+        let held = JoinErr::UsernameTaken { suggested: None };
+        assert_eq!(
+            serde_json::to_string(&held).unwrap(),
+            r#"{"UsernameTaken":{"suggested":null}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&JoinErr::NoSuchRoom).unwrap(),
+            r#""NoSuchRoom""#
+        );
+    }
+
+    #[test]
+    fn room_info_serializes_the_room_overview() {
+        let (mut rooms, _, room_id, _) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+        let room = rooms.get_room_mut(&room_id).unwrap();
+        let json = serde_json::to_value(BrokerMsg::RoomInfo(room.info())).unwrap();
+        let info = &json["RoomInfo"];
+        assert_eq!(
+            info["players"],
+            serde_json::json!(["Ahab", "Ishmael", "Queequeg"])
+        );
+        assert_eq!(info["rounds_played"], 0);
+        assert_eq!(info["created_at_secs_ago"], 0);
+        assert_eq!(info["phase"], "Lobby");
+        assert_eq!(
+            info["settings"],
+            serde_json::to_value(RoomSettings::default()).unwrap()
+        );
+
+        deal_bank(room, "Ahab");
+        let json = serde_json::to_value(room.info()).unwrap();
+        assert_eq!(json["rounds_played"], 1);
+        assert_eq!(json["phase"], "Playing");
+        room.round.as_mut().unwrap().paused_at = Some(Instant::now());
+        assert_eq!(room.info().phase, Phase::Paused);
+    }
+
+    #[test]
+    fn public_rooms_are_listed_without_joining() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let public = RoomSettings {
+                public: true,
+                ..RoomSettings::default()
+            };
+            let (first, first_channels) =
+                room_with_settings(&broker_tx, &["Ahab", "Ishmael", "Queequeg"], public.clone())
+                    .await;
+            let (second, second_channels) =
+                room_with_settings(&broker_tx, &["Starbuck"], public.clone()).await;
+            start(&broker_tx, &first, &first_channels).await;
+            // neither of these is ever listed
+            let (_, unlisted_channels) = room_with(&broker_tx, &["Stubb"]).await;
+            let guarded = Join {
+                name: "Flask".to_string(),
+                room: None,
+                settings: public,
+                password: Some("white whale".to_string()),
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            let (_, guarded_rx) = send_join(&broker_tx, guarded).await.unwrap();
+
+            let (list_tx, list_rx) = channel::bounded(1);
+            broker_tx.send(ClientMsg::ListRooms(list_tx)).await.unwrap();
+            let expected = vec![
+                RoomSummary {
+                    room_id: second,
+                    player_count: 1,
+                    capacity: MAX_ROOM_PLAYERS,
+                    phase: Phase::Lobby,
+                },
+                RoomSummary {
+                    room_id: first,
+                    player_count: 3,
+                    capacity: MAX_ROOM_PLAYERS,
+                    phase: Phase::Playing,
+                },
+            ];
+            assert_eq!(list_rx.recv().await.unwrap(), expected);
+
+            drop((unlisted_channels, guarded_rx));
+            drop((broker_tx, first_channels, second_channels));
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn stats_count_every_room() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let stats = || async {
+                let (stats_tx, stats_rx) = channel::bounded(1);
+                broker_tx.send(ClientMsg::Stats(stats_tx)).await.unwrap();
+                stats_rx.recv().await.unwrap()
+            };
+            let empty = ServerStats {
+                active_rooms: 0,
+                active_players: 0,
+                games_in_progress: 0,
+            };
+            assert_eq!(stats().await, empty);
+
+            let (playing, playing_channels) =
+                room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            // private rooms are counted too, unlike in the listing
+            let (_, lobby_channels) = room_with(&broker_tx, &["Starbuck", "Stubb"]).await;
+            start(&broker_tx, &playing, &playing_channels).await;
+            let expected = ServerStats {
+                active_rooms: 2,
+                active_players: 5,
+                games_in_progress: 1,
+            };
+            assert_eq!(stats().await, expected);
+
+            drop((playing_channels, lobby_channels));
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn the_listing_is_capped_with_lobbies_first() {
+        let mut rooms = RoomTable::new();
+        for i in 0..MAX_LISTED_ROOMS + 10 {
+            let settings = RoomSettings {
+                public: true,
+                ..RoomSettings::default()
+            };
+            let (sender, _) = channel::unbounded();
+            let mut room = Room::new("Ahab".to_string(), sender, i as SessionToken, settings);
+            if i % 2 == 0 {
+                deal_bank(&mut room, "Ahab");
+            }
+            rooms.0.insert(format!("R{:03}", i), room);
+        }
+        let summaries = rooms.summaries();
+        assert_eq!(summaries.len(), MAX_LISTED_ROOMS);
+        let lobbies = summaries
+            .iter()
+            .take_while(|summary| summary.phase == Phase::Lobby)
+            .count();
+        assert_eq!(lobbies, (MAX_LISTED_ROOMS + 10) / 2);
+        assert_eq!(summaries[0].room_id, "R001");
+    }
+
+    fn play_round(room: &mut Room, spy: &str, winner: Winner) {
+        deal_bank(room, spy);
+        room.end_round(Some(winner));
+    }
+
+    fn deal_bank(room: &mut Room, spy: &str) {
+        room.rounds_started += 1;
+        room.round = Some(Round {
+            number: room.rounds_started,
+            location: "Bank".to_string(),
+            spies: vec![spy.to_string()],
+            started_at: Instant::now(),
+            ends_at: None,
+            extension_requested: false,
+            extension_vote: None,
+            paused_at: None,
+            dealt: dealt_in(room),
+        });
+    }
+
+    /// What everyone seated in `room` would be dealt, with the roles left out
+    fn dealt_in(room: &Room) -> HashMap<PlayerId, Start> {
+        room.names()
+            .map(|name| {
+                let start = Start {
+                    round: room.rounds_started,
+                    assignment: None,
+                    first: Arc::from(room.host().as_str()),
+                    remaining_secs: None,
+                    partner: None,
+                };
+                (name.clone(), start)
+            })
+            .collect()
+    }
+
+    fn revealed_winner(chan: &Receiver<BrokerMsg>) -> Option<Winner> {
+        match chan.try_recv().unwrap() {
+            BrokerMsg::Revealed(summary) => {
+                assert_eq!(&*summary.location, "Bank");
+                assert_eq!(summary.spies, vec![Arc::from("Ahab")]);
+                summary.winner
+            }
+            msg => panic!("expected the reveal, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn a_correct_guess_wins_the_round_for_the_spy() {
+        smol::block_on(async {
+            let (mut rooms, _, room_id, channels) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            deal_bank(room, "Ahab");
+
+            // only the spy gets to guess
+            let guess = "Bank".to_string();
+            guess_location(room, "Ishmael".to_string(), guess.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                channels[1].try_recv().unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheSpy)
+            );
+            guess_location(room, "Ahab".to_string(), guess)
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(revealed_winner(chan), Some(Winner::Spy));
+            }
+            assert!(room.round.is_none());
+            assert_eq!(room.scores["Ahab"], SPY_WIN_POINTS);
+        })
+    }
+
+    #[test]
+    fn a_wrong_guess_loses_the_round_and_there_is_no_second_guess() {
+        smol::block_on(async {
+            let (mut rooms, _, room_id, channels) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            deal_bank(room, "Ahab");
+
+            let ahab = "Ahab".to_string();
+            guess_location(room, ahab.clone(), "Casino".to_string())
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(revealed_winner(chan), Some(Winner::NonSpies));
+                assert_eq!(chan.try_recv().unwrap(), room.roster());
+            }
+            assert_eq!(room.scores.get("Ahab"), Some(&0));
+            assert_eq!(room.scores["Ishmael"], NON_SPY_WIN_POINTS);
+
+            guess_location(room, ahab.clone(), "Bank".to_string())
+                .await
+                .unwrap();
+            assert_eq!(
+                channels[0].try_recv().unwrap(),
+                BrokerMsg::Rejected(RoomErr::AlreadyGuessed)
+            );
+            assert!(channels.iter().all(|chan| chan.try_recv().is_err()));
+            assert_eq!(room.history.len(), 1);
+
+            // the next round brings a fresh guess
+            deal_bank(room, "Ahab");
+            guess_location(room, ahab, "Bank".to_string())
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert_eq!(revealed_winner(chan), Some(Winner::Spy));
+            }
+        })
+    }
+
+    #[test]
+    fn first_to_the_target_score_wins_the_match() {
+        smol::block_on(async {
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (mut rooms, _, room_id, channels) = table_with(&names);
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.settings.target_score = Some(3);
+
+            // the non-spies pull level at the target
+            for _ in 0..3 {
+                play_round(room, "Ahab", Winner::NonSpies);
+                settle_match(room).await.unwrap();
+            }
+            assert_eq!(room.tie_break, vec!["Ishmael", "Queequeg"]);
+            assert!(channels.iter().all(|chan| chan.try_recv().is_err()));
+
+            // only the tied players can be the spy in the tie-break
+            let repo = SpyfallRepo::new();
+            let rng = Rng::with_seed(3);
+            let players = room.names().cloned().collect::<Vec<_>>();
+            for _ in 0..100 {
+                let deal = assign_roles(
+                    players.clone(),
+                    Some(&room.tie_break),
+                    repo.locations(),
+                    &room.recent_locations,
+                    &repo,
+                    &rng,
+                    &room.settings,
+                );
+                assert!(room.tie_break.contains(&deal.spies[0]));
+            }
+
+            play_round(room, "Queequeg", Winner::Spy);
+            settle_match(room).await.unwrap();
+            let scores = [("Ahab", 0), ("Ishmael", 3), ("Queequeg", 5)]
+                .iter()
+                .map(|(name, score)| (Arc::from(*name), *score))
+                .collect();
+            let over = BrokerMsg::MatchOver {
+                winner: Arc::from("Queequeg"),
+                scores,
+            };
+            for chan in &channels {
+                assert_eq!(chan.try_recv().unwrap(), over);
+            }
+            assert!(room.scores.is_empty());
+            assert!(room.tie_break.is_empty());
+            assert!(room.round.is_none());
+        })
+    }
+
+    #[test]
+    fn scores_stay_put_without_a_target() {
+        smol::block_on(async {
+            let (mut rooms, _, room_id, channels) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            for _ in 0..10 {
+                play_round(room, "Ahab", Winner::Spy);
+                settle_match(room).await.unwrap();
+            }
+            assert_eq!(room.scores["Ahab"], 10 * SPY_WIN_POINTS);
+            assert!(channels.iter().all(|chan| chan.try_recv().is_err()));
+        })
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let (sender, _rx) = channel::bounded(1);
+        let mut room = Room::new("Ahab".to_string(), sender, 0, RoomSettings::default());
+        for i in 0..MAX_ROUND_HISTORY + 10 {
+            room.round = Some(Round {
+                number: 1,
+                location: i.to_string(),
+                spies: vec!["Ahab".to_string()],
+                started_at: Instant::now(),
+                ends_at: None,
+                extension_requested: false,
+                extension_vote: None,
+                paused_at: None,
+                dealt: HashMap::new(),
+            });
+            room.end_round(None);
+        }
+        assert_eq!(room.history.len(), MAX_ROUND_HISTORY);
+        // the oldest rounds are the ones dropped
+        assert_eq!(&*room.history[0].location, "10");
+    }
+
+    #[test]
+    fn location_marks_are_private_and_survive_a_rejoin() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Queequeg"]).await;
+            let watchers = [&channels[0], &channels[1]];
+            let (ishmael, ishmael_rx, _) =
+                join_watched(&broker_tx, "Ishmael", &room, &watchers).await;
+            let mark = |id, location: &str| {
+                let mark = RoomMsg::MarkLocation {
+                    room: room.clone(),
+                    name: "Ishmael".to_string(),
+                    location: location.to_string(),
+                    crossed: true,
+                };
+                ClientMsg::Room(id, mark)
+            };
+            let marked = BrokerMsg::LocationMarked {
+                location: Arc::from("Bank"),
+                crossed: true,
+            };
+
+            broker_tx.send(mark(ishmael.id, "Bank")).await.unwrap();
+            broker_tx.send(mark(ishmael.id, "Atlantis")).await.unwrap();
+            assert_eq!(ishmael_rx.recv().await.unwrap(), marked);
+            for chan in [&*channels[0], &*channels[1], &ishmael_rx] {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            // refreshing the page takes the seat back with its reconnect token
+            let (resumed, _resumed_rx) =
+                resume(&broker_tx, &ishmael.reconnect_token).await.unwrap();
+            assert_eq!(resumed.crossed_locations, vec!["Bank".to_string()]);
+            for chan in watchers.iter().chain(&watchers) {
+                chan.recv().await.unwrap();
+            }
+
+            // whoever takes the name after the player leaves doesn't get their notes
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(resumed.id, leave))
+                .await
+                .unwrap();
+            for chan in watchers.iter().chain(&watchers) {
+                chan.recv().await.unwrap();
+            }
+            let (newcomer, newcomer_rx, _) =
+                join_watched(&broker_tx, "Ishmael", &room, &watchers).await;
+            assert!(newcomer.crossed_locations.is_empty());
+
+            // and a new round wipes everyone's notes
+            broker_tx.send(mark(newcomer.id, "Bank")).await.unwrap();
+            assert_eq!(newcomer_rx.recv().await.unwrap(), marked);
+            let start = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, start))
+                .await
+                .unwrap();
+            for chan in [&*channels[0], &*channels[1], &newcomer_rx] {
+                chan.recv().await.unwrap();
+            }
+            let (resumed, _) = resume(&broker_tx, &newcomer.reconnect_token).await.unwrap();
+            assert!(resumed.crossed_locations.is_empty());
+
+            drop(broker_tx);
+            drop(channels);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn private_rooms_need_the_password() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let join = |name: &str, room: Option<RoomId>, password: Option<&str>| Join {
+                name: name.to_string(),
+                room,
+                settings: RoomSettings::default(),
+                password: password.map(str::to_string),
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            let (Connected { room_id, .. }, host_rx) =
+                send_join(&broker_tx, join("Ahab", None, Some("white whale")))
+                    .await
+                    .unwrap();
+
+            for password in [None, Some("White Whale"), Some("")] {
+                let res = send_join(&broker_tx, join("Ishmael", Some(room_id.clone()), password));
+                assert_eq!(res.await.unwrap_err(), JoinErr::WrongPassword);
+            }
+            let joining = send_join(
+                &broker_tx,
+                join("Ishmael", Some(room_id.clone()), Some("white whale")),
+            );
+            let (joined, _) = future::zip(joining, async {
+                (host_rx.recv().await.unwrap(), host_rx.recv().await.unwrap())
+            })
+            .await;
+            let (connected, _ishmael_rx) = joined.unwrap();
+            assert_eq!(connected.players, vec!["Ahab", "Ishmael"]);
+
+            // a seat's reconnect token is enough to take it back, a made-up one isn't
+            let rejoin = |reconnect_token: &str| Join {
+                reconnect_token: Some(reconnect_token.to_string()),
+                ..join("Ishmael", Some(room_id.clone()), None)
+            };
+            let res = send_join(&broker_tx, rejoin("bogus"));
+            assert_eq!(res.await.unwrap_err(), JoinErr::WrongPassword);
+            let rejoining = send_join(&broker_tx, rejoin(&connected.reconnect_token));
+            let (rejoined, reconnected) =
+                future::zip(rejoining, async { host_rx.recv().await.unwrap() }).await;
+            let (rejoined, _rejoined_rx) = rejoined.unwrap();
+            assert_eq!(rejoined.reconnect_token, connected.reconnect_token);
+            assert_eq!(
+                reconnected,
+                BrokerMsg::Reconnected {
+                    name: Arc::from("Ishmael")
+                }
+            );
+
+            // rooms without a password don't care if one is given
+            let (Connected { room_id, .. }, open_rx) =
+                send_join(&broker_tx, join("Starbuck", None, None))
+                    .await
+                    .unwrap();
+            let joining = send_join(&broker_tx, join("Stubb", Some(room_id), Some("anything")));
+            let (joined, _) = future::zip(joining, async {
+                (open_rx.recv().await.unwrap(), open_rx.recv().await.unwrap())
+            })
+            .await;
+            assert!(joined.is_ok());
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn invites_stand_in_for_the_password() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let config = BrokerConfig {
+                invite_ttl: Duration::from_millis(200),
+                invite_uses: 2,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let join = |name: &str, room: Option<RoomId>, invite: Option<&str>| Join {
+                name: name.to_string(),
+                room,
+                settings: RoomSettings::default(),
+                password: Some("white whale".to_string()).filter(|_| invite.is_none()),
+                reconnect_token: None,
+                requested_code: None,
+                invite: invite.map(str::to_string),
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            let (host, host_rx) = send_join(&broker_tx, join("Ahab", None, None))
+                .await
+                .unwrap();
+            let room = host.room_id.clone();
+            let create_invite = |id, name: &str| {
+                let msg = RoomMsg::CreateInvite {
+                    room: room.clone(),
+                    name: name.to_string(),
+                };
+                ClientMsg::Room(id, msg)
+            };
+            let invite = || async {
+                broker_tx
+                    .send(create_invite(host.id, "Ahab"))
+                    .await
+                    .unwrap();
+                loop {
+                    if let BrokerMsg::Invite { code, uses, .. } = host_rx.recv().await.unwrap() {
+                        assert_eq!(uses, 2);
+                        break code;
+                    }
+                }
+            };
+
+            let code = invite().await;
+            let (ishmael, ishmael_rx) =
+                send_join(&broker_tx, join("Ishmael", Some(room.clone()), Some(&code)))
+                    .await
+                    .unwrap();
+            // a join that fails anyway doesn't use the invite up
+            let res = send_join(&broker_tx, join("ahab", Some(room.clone()), Some(&code)));
+            let suggested = Some("ahab (2)".to_string());
+            assert_eq!(res.await.unwrap_err(), JoinErr::UsernameTaken { suggested });
+            let joined = send_join(
+                &broker_tx,
+                join("Queequeg", Some(room.clone()), Some(&code)),
+            );
+            assert!(joined.await.is_ok());
+            let res = send_join(
+                &broker_tx,
+                join("Starbuck", Some(room.clone()), Some(&code)),
+            );
+            assert_eq!(res.await.unwrap_err(), JoinErr::InvalidInvite);
+
+            broker_tx
+                .send(create_invite(ishmael.id, "Ishmael"))
+                .await
+                .unwrap();
+            let rejected = loop {
+                match ishmael_rx.recv().await.unwrap() {
+                    BrokerMsg::Rejected(err) => break err,
+                    _ => continue,
+                }
+            };
+            assert_eq!(rejected, RoomErr::NotTheHost);
+
+            let code = invite().await;
+            Timer::after(Duration::from_millis(250)).await;
+            let res = send_join(
+                &broker_tx,
+                join("Starbuck", Some(room.clone()), Some(&code)),
+            );
+            assert_eq!(res.await.unwrap_err(), JoinErr::InvalidInvite);
+
+            // past the cap, each new invite replaces the oldest
+            let mut codes = vec![];
+            for _ in 0..=MAX_OUTSTANDING_INVITES {
+                codes.push(invite().await);
+            }
+            let res = send_join(
+                &broker_tx,
+                join("Starbuck", Some(room.clone()), Some(&codes[0])),
+            );
+            assert_eq!(res.await.unwrap_err(), JoinErr::InvalidInvite);
+            let joined = send_join(
+                &broker_tx,
+                join("Starbuck", Some(room.clone()), Some(&codes[1])),
+            );
+            assert!(joined.await.is_ok());
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn invites_have_to_be_usable() {
+        assert_eq!(BrokerConfig::default().validate(), Ok(()));
+        let config = BrokerConfig {
+            invite_uses: 0,
+            ..BrokerConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rooms_nobody_can_be_reached_in_are_evicted() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            // the creator's connection died before their Connected got to them
+            let (abandoned, rx) = join(&broker_tx, "Ahab", None).await.unwrap();
+            drop(rx);
+            assert_eq!(
+                join(&broker_tx, "Ishmael", Some(abandoned.room_id))
+                    .await
+                    .unwrap_err(),
+                JoinErr::NoSuchRoom
+            );
+
+            // a seat being held is someone coming back
+            let (held, rx) = join(&broker_tx, "Ahab", None).await.unwrap();
+            drop(rx);
+            let disconnect = RoomMsg::Disconnect {
+                room: held.room_id.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(held.id, disconnect))
+                .await
+                .unwrap();
+            let joined = join(&broker_tx, "Ishmael", Some(held.room_id.clone())).await;
+            let (_, _rx) = joined.unwrap();
+            drop(broker_tx);
+            let table = broker_task.await.unwrap();
+            assert_eq!(table.len(), 1);
+        })
+    }
+
+    #[test]
+    fn usernames_are_normalized_on_join() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+
+            assert_eq!(
+                join(&broker_tx, "   ", None).await.unwrap_err(),
+                JoinErr::InvalidUsername(UsernameErr::Empty)
+            );
+            let (connected, _rx) = join(&broker_tx, " Ahab ", None).await.unwrap();
+            assert_eq!(connected.name, "Ahab");
+            assert_eq!(connected.players, vec!["Ahab".to_string()]);
+            // the trimmed name is the one that has to be unique, regardless of case
+            for taken in &["Ahab\n", "ahab", "AHAB"] {
+                // with the name as it was typed
+                let suggested = Some(format!("{} (2)", taken.trim()));
+                assert_eq!(
+                    join(&broker_tx, taken, Some(connected.room_id.clone()))
+                        .await
+                        .unwrap_err(),
+                    JoinErr::UsernameTaken { suggested }
+                );
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn short_room_codes_run_out_gracefully() {
+        let rng = Rng::with_seed(7);
+        let codes = RoomCodes {
+            length: 1,
+            attempts: 1000,
+        };
+        let mut rooms = RoomTable::new();
+        for token in 0..ROOM_CODE_ALPHABET.len() {
+            let (sender, _) = channel::unbounded();
+            let room = Room::new(
+                "Ahab".to_string(),
+                sender,
+                token as SessionToken,
+                RoomSettings::default(),
+            );
+            let code = NewCode::Random(&rng, codes);
+            rooms.try_create_room(code).unwrap().insert(room);
+        }
+        assert_eq!(rooms.len(), ROOM_CODE_ALPHABET.len());
+        assert!(rooms
+            .try_create_room(NewCode::Random(&rng, codes))
+            .is_none());
+
+        // and codes don't repeat a single character
+        let code = create_room_id(&rng, 64);
+        assert_eq!(code.len(), 64);
+        assert!(code.chars().any(|c| c != code.chars().next().unwrap()));
+        assert!(code.bytes().all(|c| ROOM_CODE_ALPHABET.contains(&c)));
+    }
+
+    #[test]
+    fn hosts_can_pick_their_room_code() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                blocked_codes: vec!["admin".to_string()],
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let create = |name: &str, code: &str| Join {
+                name: name.to_string(),
+                room: None,
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: None,
+                requested_code: Some(code.to_string()),
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+
+            let (created, _rx) = send_join(&broker_tx, create("Ahab", "whale-ship"))
+                .await
+                .unwrap();
+            assert_eq!(created.room_id, "WHA1ESH1P");
+            let (joined, _rx) = join(&broker_tx, "Ishmael", Some("WHALESHIP".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(joined.room_id, created.room_id);
+
+            // taken codes are refused rather than swapped for a random one
+            let taken = send_join(&broker_tx, create("Starbuck", "wha1eship")).await;
+            assert_eq!(taken.unwrap_err(), JoinErr::CodeTaken);
+            let length = CodeErr::Length { min: 4, max: 12 };
+            for (code, err) in [
+                ("abc", length.clone()),
+                ("moby-dick-and-the-whale", length),
+                ("UUUU", CodeErr::InvalidCharacter),
+                ("ship!", CodeErr::InvalidCharacter),
+                ("the-adm1n", CodeErr::Blocked),
+            ] {
+                let refused = send_join(&broker_tx, create("Starbuck", code)).await;
+                assert_eq!(refused.unwrap_err(), JoinErr::InvalidCode(err), "{}", code);
+            }
+            drop(broker_tx);
+            assert_eq!(broker_task.await.unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn room_codes_are_read_the_crockford_way() {
+        smol::block_on(async {
+            let (mut rooms, mut index, room_id, _channels) = table_with(&["Ahab"]);
+            assert_eq!(room_id, "R00M");
+            let codes = ["r00m", "R0-0M", "R00 M", "Room", "R0OM"];
+            for (token, (code, name)) in codes.iter().zip(["A", "B", "C", "D", "E"]).enumerate() {
+                let token = 1 + token as SessionToken;
+                let joined = add_player(
+                    &mut rooms,
+                    &mut index,
+                    code.to_string(),
+                    name.to_string(),
+                    Credentials::default(),
+                    token,
+                    String::new(),
+                )
+                .await
+                .unwrap();
+                assert_eq!(joined.unwrap().0.room_id, room_id, "{}", code);
+            }
+            let missing = add_player(
+                &mut rooms,
+                &mut index,
+                "R00N".to_string(),
+                "F".to_string(),
+                Credentials::default(),
+                9,
+                String::new(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(missing.unwrap_err(), JoinErr::NoSuchRoom);
+            assert_eq!(normalize_room_id("il-Lo"), "1110");
+            assert_eq!(rooms.get_room(&room_id).unwrap().players.len(), 6);
+        })
+    }
+
+    #[test]
+    fn room_creation_stops_at_the_cap() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_rooms: 3,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+
+            let mut rooms = vec![];
+            for name in &["Ahab", "Ishmael", "Queequeg"] {
+                rooms.push(join(&broker_tx, name, None).await.unwrap());
+            }
+            match join(&broker_tx, "Starbuck", None).await.unwrap_err() {
+                JoinErr::ServerFull(info) => assert_eq!(
+                    serde_json::to_string(&info).unwrap(),
+                    r#"{"name":"max_rooms","max":3,"got":4}"#
+                ),
+                err => panic!("unexpected {:?}", err),
+            }
+            // existing rooms can still be joined
+            let (Connected { room_id, id, .. }, rx) = &rooms[0];
+            let host = Seat {
+                id: *id,
+                rx: rx.clone(),
+            };
+            join_watched(&broker_tx, "Starbuck", room_id, &[&host]).await;
+
+            drop(broker_tx);
+            drop(rooms);
+            assert_eq!(broker_task.await.unwrap().len(), 3);
+        })
+    }
+
+    #[test]
+    fn players_are_capped_across_rooms() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_players: 3,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+
+            let full = |res: JoinResult| match res {
+                Err(JoinErr::ServerFull(info)) => assert_eq!(
+                    serde_json::to_string(&info).unwrap(),
+                    r#"{"name":"max_players","max":3,"got":4}"#
+                ),
+                res => panic!("unexpected {:?}", res.map(|(connected, _)| connected)),
+            };
+            full(join(&broker_tx, "Starbuck", Some(room.clone())).await);
+            full(join(&broker_tx, "Starbuck", None).await);
+            // the names in the room are still told apart from the room being full
+            let taken = join(&broker_tx, "Ishmael", Some(room.clone())).await;
+            let suggested = Some("Ishmael (2)".to_string());
+            assert_eq!(taken.unwrap_err(), JoinErr::UsernameTaken { suggested });
+
+            drop(broker_tx);
+            drop(channels);
+            assert_eq!(broker_task.await.unwrap().player_count(), 3);
+        })
+    }
+
+    #[test]
+    fn addresses_can_only_create_so_many_rooms() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig {
+                max_rooms_per_peer: 2,
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let from = |name: &str, room: Option<RoomId>, peer: [u8; 4]| Join {
+                name: name.to_string(),
+                room,
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: Some(IpAddr::from(peer)),
+            };
+
+            let mut rooms = vec![];
+            for name in ["Ahab", "Ishmael"] {
+                rooms.push(
+                    send_join(&broker_tx, from(name, None, [10, 0, 0, 1]))
+                        .await
+                        .unwrap(),
+                );
+            }
+            match send_join(&broker_tx, from("Queequeg", None, [10, 0, 0, 1])).await {
+                Err(JoinErr::RateLimited(info)) => assert_eq!(
+                    serde_json::to_string(&info).unwrap(),
+                    r#"{"name":"max_rooms_per_peer","max":2,"got":3}"#
+                ),
+                res => panic!("unexpected {:?}", res.map(|(connected, _)| connected)),
+            }
+            // other addresses, and joining rooms that exist, are unaffected
+            let other = send_join(&broker_tx, from("Queequeg", None, [10, 0, 0, 2])).await;
+            rooms.push(other.unwrap());
+            let room_id = rooms[0].0.room_id.clone();
+            let joining = send_join(&broker_tx, from("Starbuck", Some(room_id), [10, 0, 0, 1]));
+            let (joined, _) = future::zip(joining, async {
+                rooms[0].1.recv().await.unwrap();
+                rooms[0].1.recv().await.unwrap();
+            })
+            .await;
+            assert!(joined.is_ok());
+
+            drop(broker_tx);
+            drop(rooms);
+            assert_eq!(broker_task.await.unwrap().len(), 3);
+        })
+    }
+
+    #[test]
+    fn player_index_follows_churn() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            // the broker checks its index against the room table after every message
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (first, first_channels) = room_with(&broker_tx, &names).await;
+            // the same names can be seated in another room at the same time
+            let (second, second_channels) = room_with(&broker_tx, &names).await;
+
+            let leave = |room: &RoomId, seat: &Seat, name: &str| {
+                let leave = RoomMsg::Leave {
+                    room: room.clone(),
+                    name: name.to_string(),
+                };
+                ClientMsg::Room(seat.id, leave)
+            };
+            broker_tx
+                .send(leave(&first, &first_channels[1], "Ishmael"))
+                .await
+                .unwrap();
+            let watchers = [&first_channels[0], &first_channels[2]];
+            for chan in watchers.iter().chain(&watchers) {
+                chan.recv().await.unwrap();
+            }
+            let (_, rejoined, _) = join_watched(&broker_tx, "Ishmael", &first, &watchers).await;
+            // empty the second room entirely so it gets evicted, the host going first each time
+            for (i, name) in names.iter().enumerate() {
+                broker_tx
+                    .send(leave(&second, &second_channels[i], name))
+                    .await
+                    .unwrap();
+                let rest = &second_channels[i + 1..];
+                for chan in rest.iter().chain(rest).chain(rest) {
+                    chan.recv().await.unwrap();
+                }
+            }
+
+            drop(broker_tx);
+            drop(rejoined);
+            let table = broker_task.await.unwrap();
+            assert_eq!(table.len(), 1);
+            assert!(table.get_room(&first).is_some());
+        })
+    }
+
+    /// Starts a round in `room`, returning what each player was dealt
+    async fn start(broker_tx: &Sender<ClientMsg>, room: &RoomId, channels: &[Seat]) -> Vec<Start> {
+        let start = RoomMsg::Start {
+            room: room.clone(),
+            name: "Ahab".to_string(),
+        };
+        broker_tx
+            .send(ClientMsg::Room(channels[0].id, start))
+            .await
+            .unwrap();
+        let mut dealt = vec![];
+        for chan in channels {
+            match chan.recv().await.unwrap() {
+                BrokerMsg::Started(start) => dealt.push(start),
+                msg => panic!("expected a Started message, got {:?}", msg),
+            }
+        }
+        dealt
+    }
+
+    /// Has the spy (seated at `channels[seat]`) guess a location there isn't, ending the round
+    async fn misguess(
+        broker_tx: &Sender<ClientMsg>,
+        room: &RoomId,
+        spy: &str,
+        seat: usize,
+        channels: &[Seat],
+    ) {
+        let guess = RoomMsg::GuessLocation {
+            room: room.clone(),
+            name: spy.to_string(),
+            location: String::new(),
+        };
+        let id = channels[seat].id;
+        broker_tx.send(ClientMsg::Room(id, guess)).await.unwrap();
+        for chan in channels {
+            assert!(matches!(chan.recv().await.unwrap(), BrokerMsg::Revealed(_)));
+            assert!(matches!(
+                chan.recv().await.unwrap(),
+                BrokerMsg::Roster { .. }
+            ));
+        }
+    }
+
+    /// Plays a whole round in `room`, returning what each of `names` was dealt
+    async fn deal_and_reveal(
+        broker_tx: &Sender<ClientMsg>,
+        room: &RoomId,
+        names: &[&str],
+        channels: &[Seat],
+    ) -> Vec<Start> {
+        let dealt = start(broker_tx, room, channels).await;
+        let spy = dealt
+            .iter()
+            .position(|start| start.assignment.is_none())
+            .unwrap();
+        misguess(broker_tx, room, names[spy], spy, channels).await;
+        dealt
+    }
+
+    #[test]
+    fn rooms_can_refuse_to_repeat_roles() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let roles =
+                r#"{"Bank": ["Teller", "Guard"], "Casino": ["Dealer", "Bouncer", "Gambler"]}"#;
+            let config = BrokerConfig {
+                repo: SpyfallRepo::from_json(roles).unwrap(),
+                ..BrokerConfig::default()
+            };
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let roles_dealt = |dealt: &[Start]| {
+                dealt
+                    .iter()
+                    .filter_map(|start| start.assignment.as_ref())
+                    .map(|assignment| assignment.role.clone())
+                    .collect::<Vec<_>>()
+            };
+
+            // by default the bank's two roles go around four players
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck", "Stubb"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let roles = roles_dealt(&deal_and_reveal(&broker_tx, &room, &names, &channels).await);
+            assert!(roles.iter().collect::<HashSet<_>>().len() < roles.len());
+
+            // only the casino has a role for each of three players
+            let distinct = RoomSettings {
+                distinct_roles: true,
+                ..RoomSettings::default()
+            };
+            let (room, mut channels) = room_with_settings(&broker_tx, &names[..4], distinct).await;
+            for _ in 0..3 {
+                let dealt = deal_and_reveal(&broker_tx, &room, &names, &channels).await;
+                assert!(dealt
+                    .iter()
+                    .filter_map(|start| start.assignment.as_ref())
+                    .all(|assignment| &*assignment.location == "Casino"));
+                let roles = roles_dealt(&dealt);
+                assert_eq!(roles.iter().collect::<HashSet<_>>().len(), roles.len());
+            }
+
+            // and none has enough for four
+            let watchers = channels.iter().collect::<Vec<_>>();
+            let (Connected { id, .. }, rx, _) =
+                join_watched(&broker_tx, names[4], &room, &watchers).await;
+            channels.push(Seat { id, rx });
+            let start = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, start))
+                .await
+                .unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::NotEnoughRoles { need: 4 }
+            );
+            for chan in &channels[1..] {
+                assert!(chan.try_recv().is_err());
+            }
+
+            drop(broker_tx);
+            let rooms = HashMap::from(broker_task.await.unwrap());
+            assert!(rooms[&room].round.is_none());
+        })
+    }
+
+    #[cfg(feature = "debug-controls")]
+    #[test]
+    fn debug_start_deals_the_forced_spy_and_location() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            let location = SpyfallRepo::new().locations().last().unwrap().clone();
+            for _ in 0..3 {
+                let msg = RoomMsg::DebugStart {
+                    room: room.clone(),
+                    name: "Ahab".to_string(),
+                    spy: Some("Ishmael".to_string()),
+                    location: Some(location.clone()),
+                };
+                broker_tx
+                    .send(ClientMsg::Room(channels[0].id, msg))
+                    .await
+                    .unwrap();
+                for (i, chan) in channels.iter().enumerate() {
+                    let start = match chan.recv().await.unwrap() {
+                        BrokerMsg::Started(start) => start,
+                        msg => panic!("expected a Started message, got {:?}", msg),
+                    };
+                    match start.assignment {
+                        Some(assignment) => assert_eq!(&*assignment.location, location),
+                        None => assert_eq!(i, 1, "only Ishmael should be the spy"),
+                    }
+                }
+                misguess(&broker_tx, &room, "Ishmael", 1, &channels).await;
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn rounds_are_dealt_from_the_hosts_category() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let set = |seat: usize, category: &str| {
+                let set = RoomMsg::SetCategory {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                    category: Some(category.to_string()),
+                };
+                ClientMsg::Room(channels[seat].id, set)
+            };
+
+            broker_tx.send(set(1, "Spyfall 2")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheHost)
+            );
+            broker_tx.send(set(0, "Office")).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoSuchCategory)
+            );
+            broker_tx.send(set(0, "Spyfall 2")).await.unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::CategoryChanged {
+                        category: Some(Arc::from("Spyfall 2"))
+                    }
+                );
+            }
+
+            let pack = SpyfallRepo::new()
+                .locations_in("Spyfall 2")
+                .unwrap()
+                .to_vec();
+            for _ in 0..5 {
+                for start in deal_and_reveal(&broker_tx, &room, &names, &channels).await {
+                    if let Some(assignment) = start.assignment {
+                        assert!(pack
+                            .iter()
+                            .any(|location| **location == *assignment.location));
+                    }
+                }
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn the_board_can_be_looked_at_before_starting() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let repo = SpyfallRepo::new();
+            let board = |locations: &[String]| {
+                locations
+                    .iter()
+                    .map(|location| Arc::from(location.as_str()))
+                    .collect::<Vec<_>>()
+            };
+            let (locations_tx, locations_rx) = channel::bounded(1);
+            broker_tx
+                .send(ClientMsg::GetLocations(locations_tx))
+                .await
+                .unwrap();
+            assert_eq!(locations_rx.recv().await.unwrap(), board(repo.locations()));
+
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let get = |seat: usize| {
+                let get = RoomMsg::GetLocations {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                };
+                ClientMsg::Room(channels[seat].id, get)
+            };
+            broker_tx.send(get(1)).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Locations(board(repo.locations()))
+            );
+            let set = RoomMsg::SetCategory {
+                room: room.clone(),
+                name: names[0].to_string(),
+                category: Some("Spyfall 2".to_string()),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, set))
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert!(matches!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::CategoryChanged { .. }
+                ));
+            }
+            // only the asker is answered, with the host's pack
+            broker_tx.send(get(2)).await.unwrap();
+            let pack = board(repo.locations_in("Spyfall 2").unwrap());
+            assert_eq!(
+                channels[2].recv().await.unwrap(),
+                BrokerMsg::Locations(pack)
+            );
+            assert!(channels[1].try_recv().is_err());
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn assignments_are_repeated_only_to_the_requester() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            let get = |seat: usize| {
+                let get = RoomMsg::GetAssignment {
+                    room: room.clone(),
+                    name: names[seat].to_string(),
+                };
+                ClientMsg::Room(channels[seat].id, get)
+            };
+
+            broker_tx.send(get(1)).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoRoundInProgress)
+            );
+            let starts = start(&broker_tx, &room, &channels).await;
+            for i in 0..names.len() {
+                broker_tx.send(get(i)).await.unwrap();
+                assert_eq!(
+                    channels[i].recv().await.unwrap(),
+                    BrokerMsg::Started(starts[i].clone())
+                );
+            }
+            for chan in &channels {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn a_leavers_assignment_isnt_handed_to_the_next_with_their_name() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (room, mut channels) = room_with(&broker_tx, &names).await;
+            let starts = start(&broker_tx, &room, &channels).await;
+            // a non-spy other than the host, so the round goes on without them
+            let leaver = (1..names.len())
+                .find(|seat| starts[*seat].assignment.is_some())
+                .unwrap();
+            let name = names[leaver];
+            let leave = RoomMsg::Leave {
+                name: name.to_string(),
+                room: room.clone(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[leaver].id, leave))
+                .await
+                .unwrap();
+            channels.remove(leaver);
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left(name, LeaveReason::Voluntary)
+                );
+                chan.recv().await.unwrap();
+            }
+
+            let seats = channels.iter().collect::<Vec<_>>();
+            let (connected, rx, _) = join_watched(&broker_tx, name, &room, &seats).await;
+            // nobody dealt them in, so they sit the round out like any other latecomer
+            assert!(connected.in_game);
+            assert_eq!(connected.watching, vec![name.to_string()]);
+            let get = RoomMsg::GetAssignment {
+                room: room.clone(),
+                name: name.to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(connected.id, get))
+                .await
+                .unwrap();
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotDealtIn)
+            );
+
+            drop((broker_tx, channels, rx));
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn starting_again_leaves_the_round_alone() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            let dealt = start(&broker_tx, &room, &channels).await;
+
+            let again = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, again))
+                .await
+                .unwrap();
+            assert_eq!(channels[1].recv().await.unwrap(), BrokerMsg::AlreadyStarted);
+            for chan in &channels {
+                assert!(chan.try_recv().is_err());
+            }
+
+            drop(broker_tx);
+            let rooms = HashMap::from(broker_task.await.unwrap());
+            let round = rooms[&room].round.as_ref().unwrap();
+            assert_eq!(round.number, 1);
+            assert_eq!(
+                round.dealt_to(&"Ahab".to_string()).unwrap().assignment,
+                dealt[0].assignment
+            );
+        })
+    }
+
+    #[test]
+    fn rooms_games_and_join_errors_are_counted() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let config = BrokerConfig::default();
+            let metrics = Arc::clone(&config.metrics);
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            start(&broker_tx, &room, &channels).await;
+            let taken = join(&broker_tx, "ahab", Some(room.clone())).await;
+            assert!(matches!(taken, Err(JoinErr::UsernameTaken { .. })));
+            let missing = join(&broker_tx, "Pip", Some("NOPE".to_string())).await;
+            assert_eq!(missing.unwrap_err(), JoinErr::NoSuchRoom);
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+            let rendered = metrics.render();
+            for line in [
+                "rooms_total 1",
+                "players_total 3",
+                "games_started_total 1",
+                "join_errors_total{kind=\"NoSuchRoom\"} 1",
+                "join_errors_total{kind=\"UsernameTaken\"} 1",
+            ] {
+                assert!(rendered.lines().any(|l| l == line), "{}", rendered);
+            }
+        })
+    }
+
+    #[test]
+    fn rounds_are_numbered() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with(&broker_tx, &names).await;
+            for round in 1..=3 {
+                let starts = deal_and_reveal(&broker_tx, &room, &names, &channels).await;
+                assert!(starts.iter().all(|start| start.round == round));
+            }
+
+            drop(broker_tx);
+            let rooms = HashMap::from(broker_task.await.unwrap());
+            let rounds = rooms[&room].history.iter().map(|summary| summary.round);
+            assert_eq!(rounds.collect::<Vec<_>>(), vec![1, 2, 3]);
+        })
+    }
+
+    #[test]
+    fn seeded_brokers_deal_the_same_rounds() {
+        let deal = |seed| {
+            smol::block_on(async move {
+                let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+                let config = BrokerConfig {
+                    seed: Some(seed),
+                    ..BrokerConfig::default()
+                };
+                let broker_task = smol::spawn(broker_actor(broker_rx, config));
+                let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+                let (room, channels) = room_with(&broker_tx, &names).await;
+                let mut starts = vec![];
+                for _ in 0..5 {
+                    starts.push(deal_and_reveal(&broker_tx, &room, &names, &channels).await);
+                }
+                drop(broker_tx);
+                broker_task.await.unwrap();
+                (room, starts)
+            })
+        };
+
+        let (room, starts) = deal(42);
+        assert_eq!(deal(42), (room.clone(), starts.clone()));
+        assert_ne!(deal(43).1, starts);
+    }
+
+    #[test]
+    fn extension_vote_extends_the_timer_once() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                round_secs: Some(60),
+                ..RoomSettings::default()
+            };
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+            let dealt = start(&broker_tx, &room, &channels).await;
+            assert!(dealt.iter().all(|start| start.remaining_secs <= Some(60)));
+
+            let request = RoomMsg::RequestExtension {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            let request = ClientMsg::Room(channels[0].id, request);
+            broker_tx.send(request.clone()).await.unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::ExtensionRequested {
+                        by: Arc::from("Ahab")
+                    }
+                );
+            }
+            let vote = RoomMsg::VoteExtension {
+                room: room.clone(),
+                name: "Queequeg".to_string(),
+                approve: true,
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[2].id, vote))
+                .await
+                .unwrap();
+            for chan in &channels {
+                match chan.recv().await.unwrap() {
+                    BrokerMsg::RoundExtended { remaining_secs } => {
+                        assert!(remaining_secs > 60 && remaining_secs <= 180)
+                    }
+                    msg => panic!("expected the round to be extended, got {:?}", msg),
                 }
-            },
-        }
+            }
+
+            // only one extension per round
+            broker_tx.send(request).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::ExtensionAlreadyRequested)
+            );
+            for chan in &channels[1..] {
+                assert!(drain(chan, Duration::from_millis(100)).await.is_empty());
+            }
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
     }
 
-    Ok(rooms)
-}
+    #[test]
+    fn held_seats_dont_vote_on_extensions() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                round_secs: Some(60),
+                ..RoomSettings::default()
+            };
+            let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck"];
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+            start(&broker_tx, &room, &channels).await;
+            let send = |seat: usize, msg| broker_tx.send(ClientMsg::Room(channels[seat].id, msg));
 
-/// attempts to add a player
-/// the outermost error is a programatic error (unexpected)
-/// the inner result is what to send back to the client (errors of usage, and are expected)
-async fn add_player(
-    rooms: &mut RoomTable,
-    room_id: RoomId,
-    name: PlayerId,
-) -> Result<JoinResult, AsyncErr> {
-    let mut room_entry = match rooms.get_room_entry(room_id.clone()) {
-        Ok(room_entry) => room_entry,
-        Err(e) => return Ok(Err(e)),
-    };
+            let disconnect = RoomMsg::Disconnect {
+                name: "Starbuck".to_string(),
+                room: room.clone(),
+            };
+            send(3, disconnect).await.unwrap();
+            for chan in &channels[..3] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::Disconnected(Arc::from("Starbuck"))
+                );
+                chan.recv().await.unwrap();
+            }
 
-    if find_index(&room_entry.get().names, &name).is_none() {
-        // message other players a new player is joining
-        send_room(
-            &room_entry.get().senders,
-            BrokerMsg::Join(Arc::from(name.clone())),
-        )
-        .await?;
+            // two of the three still at the table are a majority
+            let request = RoomMsg::RequestExtension {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            send(0, request).await.unwrap();
+            for chan in &channels[..3] {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::ExtensionRequested {
+                        by: Arc::from("Ahab")
+                    }
+                );
+            }
+            let vote = RoomMsg::VoteExtension {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+                approve: true,
+            };
+            send(1, vote).await.unwrap();
+            for chan in &channels[..3] {
+                match chan.recv().await.unwrap() {
+                    BrokerMsg::RoundExtended { remaining_secs } => {
+                        assert!(remaining_secs > 60 && remaining_secs <= 180)
+                    }
+                    msg => panic!("expected the round to be extended, got {:?}", msg),
+                }
+            }
 
-        let (sender, rx) = channel::bounded(1);
-        // insert new player
-        let room = room_entry.get_mut();
-        room.names.push(name);
-        room.senders.push(sender);
-        let players = room.names.clone();
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
 
-        Ok(Ok((Connected { players, room_id }, rx)))
-    } else {
-        Ok(Err(JoinErr::UsernameTaken))
+    #[test]
+    fn the_host_can_pause_and_resume_the_round() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                round_secs: Some(60),
+                ..RoomSettings::default()
+            };
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+            let send = |seat: usize, msg| broker_tx.send(ClientMsg::Room(channels[seat].id, msg));
+            let pause = |name: &str| RoomMsg::Pause {
+                room: room.clone(),
+                name: name.to_string(),
+            };
+            let resume = |name: &str| RoomMsg::Resume {
+                room: room.clone(),
+                name: name.to_string(),
+            };
+
+            send(0, pause("Ahab")).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoRoundInProgress)
+            );
+            let starts = start(&broker_tx, &room, &channels).await;
+            send(1, pause("Ishmael")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheHost)
+            );
+            send(0, pause("Ahab")).await.unwrap();
+            let paused = BrokerMsg::Paused {
+                by: Arc::from("Ahab"),
+            };
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), paused);
+            }
+
+            // the spy has to wait for the round to resume before guessing
+            let spy = starts.iter().position(|s| s.assignment.is_none()).unwrap();
+            let guess = RoomMsg::GuessLocation {
+                room: room.clone(),
+                name: names[spy].to_string(),
+                location: "Bank".to_string(),
+            };
+            send(spy, guess).await.unwrap();
+            assert_eq!(
+                channels[spy].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::RoundPaused)
+            );
+
+            Timer::after(Duration::from_millis(1100)).await;
+            send(0, resume("Ahab")).await.unwrap();
+            for chan in &channels {
+                match chan.recv().await.unwrap() {
+                    // the second spent paused is not counted against the round
+                    BrokerMsg::Resumed {
+                        remaining_secs: Some(secs),
+                    } => assert_eq!(secs, 59),
+                    msg => panic!("expected the round to resume, got {:?}", msg),
+                }
+            }
+            send(0, resume("Ahab")).await.unwrap();
+            assert_eq!(
+                channels[0].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::RoundNotPaused)
+            );
+
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn extension_needs_a_running_timer() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let settings = RoomSettings {
+                round_secs: Some(0),
+                ..RoomSettings::default()
+            };
+            let names = ["Ahab", "Ishmael", "Queequeg"];
+            let (room, channels) = room_with_settings(&broker_tx, &names, settings).await;
+            start(&broker_tx, &room, &channels).await;
+            for chan in &channels {
+                assert_eq!(chan.recv().await.unwrap(), BrokerMsg::TimeUp { round: 1 });
+                assert_eq!(chan.recv().await.unwrap(), roster_of(&names, &[]));
+            }
+
+            let request = RoomMsg::RequestExtension {
+                room: room.clone(),
+                name: "Ishmael".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[1].id, request))
+                .await
+                .unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NoTimerRunning)
+            );
+
+            drop(broker_tx);
+            let table = broker_task.await.unwrap();
+            // running out of time ends the round
+            assert_eq!(table.get_room(&room).unwrap().history.len(), 1);
+        })
     }
-}
 
-async fn send_room(senders: &[Sender<BrokerMsg>], msg: BrokerMsg) -> AsyncResult<()> {
-    // split to avoid extra clone call
-    if let Some((first, rest)) = senders.split_first() {
-        for sender in rest {
-            let clone = msg.clone();
-            sender.send(clone).await?;
+    /// A room table holding one room with `names` in it, but no broker, using unbounded channels
+    /// so that functions can be driven without draining every message in between
+    fn table_with(names: &[&str]) -> (RoomTable, PlayerIndex, RoomId, Vec<Receiver<BrokerMsg>>) {
+        let room_id = "R00M".to_string();
+        let mut index = PlayerIndex::new();
+        let mut channels = vec![];
+        let mut room: Option<Room> = None;
+        for (token, name) in names.iter().enumerate() {
+            let (sender, rx) = channel::unbounded();
+            let token = token as SessionToken;
+            let name = name.to_string();
+            let reconnect_token = format!("{} again", name);
+            index.insert(token, &name, &room_id, &reconnect_token);
+            let seated = name.clone();
+            let room = match room.as_mut() {
+                None => room.insert(Room::new(name, sender, token, RoomSettings::default())),
+                Some(room) => {
+                    room.add(Player {
+                        id: token,
+                        name,
+                        sender,
+                    });
+                    room
+                }
+            };
+            room.reconnect_tokens.insert(seated, reconnect_token);
+            channels.push(rx);
         }
-        first.send(msg).await?;
+        let mut rooms = RoomTable::new();
+        rooms.0.insert(room_id.clone(), room.unwrap());
+        (rooms, index, room_id, channels)
     }
-    Ok(())
-}
 
-fn assign_roles(mut players: Vec<String>, repo: &SpyfallRepo, rng: &Rng) -> GameInfo {
-    let locations = repo.locations();
-    let (first_player_index, spy_index) = (rng.usize(..players.len()), rng.usize(..players.len()));
-    let location = &locations[rng.usize(..locations.len())];
-    let mut roles = repo.roles(location).to_vec();
-    rng.shuffle(&mut roles);
-    let first = players[first_player_index].clone();
-    let spy = players.remove(spy_index);
-    let player_roles = roles
-        .into_iter()
-        .cycle()
-        .zip(players.into_iter())
-        .map(|(role, player)| (player, role))
-        .collect::<HashMap<_, _>>();
-    GameInfo {
-        player_roles,
-        first,
-        spy,
-        location: location.clone(),
+    fn quiet_for(rooms: &mut RoomTable, room_id: &RoomId, name: &str, quiet: Duration) {
+        let room = rooms.get_room_mut(room_id).unwrap();
+        room.activity.get_mut(name).unwrap().last_seen = Instant::now() - quiet;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use smol;
-    use std::{collections::HashSet, hash::Hash};
+    #[test]
+    fn the_last_seconds_are_counted_down_without_waiting_on_clients() {
+        smol::block_on(async {
+            let (mut rooms, _, room_id, channels) = table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let mut deadlines = Deadlines::new();
+            let now = Instant::now();
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.round = Some(Round {
+                number: 1,
+                location: "Bank".to_string(),
+                spies: vec!["Ahab".to_string()],
+                started_at: now,
+                ends_at: Some(now + Duration::from_secs(60)),
+                extension_requested: false,
+                extension_vote: None,
+                paused_at: None,
+                dealt: dealt_in(room),
+            });
+            tick_timer(&room_id, room, &mut deadlines).await.unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::TimerTick { remaining_secs: 60 }
+                );
+            }
+            assert!(deadlines.contains(&Deadline::TimerTick(room_id.clone())));
 
-    fn to_set<T: Eq + Hash>(vec: Vec<T>) -> HashSet<T> {
-        vec.into_iter().collect()
+            // Ishmael's client stopped reading, which can't hold up the rest of the room
+            let (stuck, stuck_rx) = channel::bounded(1);
+            stuck
+                .try_send(BrokerMsg::TimerTick { remaining_secs: 60 })
+                .unwrap();
+            room.players[1].sender = stuck;
+            room.round.as_mut().unwrap().ends_at = Some(now + Duration::from_secs(3));
+            tick_timer(&room_id, room, &mut deadlines).await.unwrap();
+            for chan in [&channels[0], &channels[2]] {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    BrokerMsg::FinalCountdown { remaining_secs: 3 }
+                );
+            }
+            assert_eq!(
+                stuck_rx.try_recv().unwrap(),
+                BrokerMsg::TimerTick { remaining_secs: 60 }
+            );
+            assert!(stuck_rx.try_recv().is_err());
+
+            // the next tick falls on a whole second left
+            let ends_at = now + Duration::from_millis(2500);
+            assert_eq!(
+                next_tick(ends_at, now),
+                Some(ends_at - Duration::from_secs(2))
+            );
+            assert_eq!(next_tick(ends_at, now + Duration::from_secs(2)), None);
+            assert_eq!(
+                next_tick(now + Duration::from_secs(60), now),
+                Some(now + TIMER_TICK_INTERVAL)
+            );
+            assert_eq!(
+                next_tick(now + Duration::from_secs(20), now),
+                Some(now + Duration::from_secs(10))
+            );
+        })
     }
 
     #[test]
-    fn assign_roles_properties() {
-        let repo = SpyfallRepo::new();
-        let players = (b'a'..=b'z')
-            .into_iter()
-            .map(char::from)
-            .map(String::from)
-            .collect::<Vec<_>>();
-        let rng = Rng::new();
+    fn idle_players_are_flagged_then_removed() {
+        smol::block_on(async {
+            let (mut rooms, mut index, room_id, channels) =
+                table_with(&["Ahab", "Ishmael", "Queequeg", "Starbuck"]);
+            let config = BrokerConfig::default();
+            let mut deadlines = Deadlines::new();
+            quiet_for(&mut rooms, &room_id, "Queequeg", config.idle_after);
+            quiet_for(&mut rooms, &room_id, "Ishmael", config.remove_idle_after);
+            // removal mid-round must leave the round intact while it can still be played
+            deal_bank(rooms.get_room_mut(&room_id).unwrap(), "Ahab");
 
-        let game_info = assign_roles(players.clone(), &repo, &rng);
-        assert!(game_info
-            .player_roles
-            .keys()
-            .find(|non_spy| **non_spy == game_info.spy)
-            .is_none());
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+            // flagging only happens once
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+
+            let idle = BrokerMsg::PlayerIdle {
+                name: Arc::from("Queequeg"),
+            };
+            let left = left("Ishmael", LeaveReason::Idle);
+            let roster = mid_round(roster_of(&["Ahab", "Queequeg", "Starbuck"], &[]));
+            for chan in [&channels[0], &channels[2]] {
+                assert_eq!(chan.try_recv().unwrap(), idle);
+                assert_eq!(chan.try_recv().unwrap(), left);
+                assert_eq!(chan.try_recv().unwrap(), roster);
+                assert!(chan.try_recv().is_err());
+            }
+            index.assert_consistent(&rooms);
+            let room = rooms.get_room(&room_id).unwrap();
+            let seated = ["Ahab", "Queequeg", "Starbuck"].map(String::from);
+            assert_eq!(room, &seated.to_vec());
+            assert_eq!(room.round.as_ref().unwrap().spies, vec!["Ahab".to_string()]);
+
+            // doing anything clears the idle flag
+            rooms
+                .get_room_mut(&room_id)
+                .unwrap()
+                .touch(&"Queequeg".to_string());
+            assert!(!rooms.get_room(&room_id).unwrap().activity["Queequeg"].idle);
+        })
     }
 
     #[test]
-    fn enter_leave_room_deletes_room() {
+    fn lobby_idling_can_be_disabled() {
+        smol::block_on(async {
+            let (mut rooms, mut index, room_id, channels) =
+                table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let config = BrokerConfig::default();
+            let mut deadlines = Deadlines::new();
+            rooms.get_room_mut(&room_id).unwrap().settings.afk_in_lobby = false;
+            quiet_for(&mut rooms, &room_id, "Ishmael", config.remove_idle_after);
+
+            sweep_idle_players(&mut rooms, &mut index, &config, &mut deadlines)
+                .await
+                .unwrap();
+            assert_eq!(rooms.get_room(&room_id).unwrap().players.len(), 3);
+            assert!(channels.iter().all(|chan| chan.try_recv().is_err()));
+        })
+    }
+
+    #[test]
+    fn broadcasts_to_a_departed_client_are_dropped() {
         smol::block_on(async {
-            let player_name = "Ahab".to_string();
-            let (client_tx, client_rx) = channel::bounded(1);
             let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
-            let broker_task = smol::spawn(async {
-                // at the end, the table should be empty
-                let table = broker_actor(broker_rx).await.unwrap();
-                assert_eq!(table, RoomTable::new());
-            });
-            let join_msg = ClientMsg::Join(
-                Join {
-                    name: player_name.clone(),
-                    room: None,
-                },
-                client_tx,
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, mut channels) =
+                room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            // Ishmael's client actor is gone without a word, the leave it would have sent never comes
+            let ishmael = channels.remove(1);
+            drop(ishmael.rx);
+            let start = RoomMsg::Start {
+                room: room.clone(),
+                name: "Ahab".to_string(),
+            };
+            broker_tx
+                .send(ClientMsg::Room(channels[0].id, start))
+                .await
+                .unwrap();
+            for chan in &channels {
+                assert!(matches!(chan.recv().await.unwrap(), BrokerMsg::Started(_)));
+            }
+            // which doesn't hold up anyone else, and lets go of the seat nothing reaches
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    left("Ishmael", LeaveReason::Disconnected)
+                );
+            }
+            // two players can't go on with the round, which is only called off after the roster
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    mid_round(roster_of(&["Ahab", "Queequeg"], &[]))
+                );
+            }
+            for chan in &channels {
+                assert!(matches!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::GameAborted { .. }
+                ));
+            }
+            drop(broker_tx);
+            let table = broker_task.await.unwrap();
+            let seated = table.get_room(&room).unwrap().names().collect::<Vec<_>>();
+            assert_eq!(seated, ["Ahab", "Queequeg"]);
+        })
+    }
+
+    #[test]
+    fn leave_reasons_are_tagged() {
+        let json = serde_json::to_string(&left("Ahab", LeaveReason::Disconnected)).unwrap();
+        assert_eq!(json, r#"{"Left":{"name":"Ahab","reason":"Disconnected"}}"#);
+    }
+
+    #[test]
+    fn hosts_can_close_the_room_mid_round() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            start(&broker_tx, &room, &channels).await;
+            let close = |seat: usize, name: &str| {
+                let close = RoomMsg::CloseRoom {
+                    room: room.clone(),
+                    name: name.to_string(),
+                };
+                ClientMsg::Room(channels[seat].id, close)
+            };
+
+            broker_tx.send(close(1, "Ishmael")).await.unwrap();
+            assert_eq!(
+                channels[1].recv().await.unwrap(),
+                BrokerMsg::Rejected(RoomErr::NotTheHost)
             );
-            broker_tx.send(join_msg).await.unwrap();
-            let (Connected { room_id, players }, _) = client_rx.recv().await.unwrap().unwrap();
-            assert_eq!(players, vec![player_name.clone()]);
-            let leave_msg = ClientMsg::Room(RoomMsg::Leave {
-                room: room_id,
-                name: player_name,
-            });
-            broker_tx.send(leave_msg).await.unwrap();
-            // drop the sending channel so the broker ends
+
+            broker_tx.send(close(0, "Ahab")).await.unwrap();
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::RoomClosed {
+                        reason: CloseReason::ClosedByHost
+                    }
+                );
+                assert!(chan.recv().await.is_err());
+            }
             drop(broker_tx);
+            let table = broker_task.await.unwrap();
+            assert!(table.is_empty());
+        })
+    }
 
-            broker_task.await;
+    #[test]
+    fn operators_can_force_close_rooms() {
+        smol::block_on(async {
+            let force_close = |broker_tx: Sender<ClientMsg>, admin_token: &str, room: &str| {
+                let admin = AdminMsg {
+                    admin_token: admin_token.to_string(),
+                    cmd: AdminCmd::ForceClose {
+                        room: room.to_string(),
+                    },
+                };
+                async move {
+                    let (tx, rx) = channel::bounded(1);
+                    broker_tx.send(ClientMsg::Admin(admin, tx)).await.unwrap();
+                    rx.recv().await.unwrap()
+                }
+            };
+
+            // a server with no admin token takes no admin commands
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            assert_eq!(
+                force_close(broker_tx.clone(), "", &room).await,
+                Err(AdminErr::Unauthorized)
+            );
+            drop((broker_tx, channels));
+            broker_task.await.unwrap();
+
+            let config = BrokerConfig {
+                admin_token: Some("Pequod".to_string()),
+                ..BrokerConfig::default()
+            };
+            let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (room, channels) = room_with(&broker_tx, &["Ahab", "Ishmael", "Queequeg"]).await;
+            start(&broker_tx, &room, &channels).await;
+            assert_eq!(
+                force_close(broker_tx.clone(), "pequod", &room).await,
+                Err(AdminErr::Unauthorized)
+            );
+            assert_eq!(
+                force_close(broker_tx.clone(), "Pequod", "NONE").await,
+                Err(AdminErr::NoSuchRoom)
+            );
+            for chan in &channels {
+                assert!(chan.try_recv().is_err());
+            }
+
+            let lowercase = room.to_lowercase();
+            assert_eq!(
+                force_close(broker_tx.clone(), "Pequod", &lowercase).await,
+                Ok(AdminReply::Closed)
+            );
+            for chan in &channels {
+                assert_eq!(
+                    chan.recv().await.unwrap(),
+                    BrokerMsg::RoomClosed {
+                        reason: CloseReason::ClosedByAdmin
+                    }
+                );
+                assert!(chan.recv().await.is_err());
+            }
+            drop(broker_tx);
+            let table = broker_task.await.unwrap();
+            assert!(table.is_empty());
         })
     }
 
     #[test]
-    fn two_players_in_room_cant_start_game() {
+    fn operators_can_find_players() {
         smol::block_on(async {
-            let player_one = "Ahab".to_string();
-            let player_two = "Ishmael".to_string();
-            let (client_tx, client_rx) = channel::bounded(1);
+            let find = |broker_tx: Sender<ClientMsg>, admin_token: &str, name: &str| {
+                let admin = AdminMsg {
+                    admin_token: admin_token.to_string(),
+                    cmd: AdminCmd::FindPlayer { name: name.into() },
+                };
+                async move {
+                    let (tx, rx) = channel::bounded(1);
+                    broker_tx.send(ClientMsg::Admin(admin, tx)).await.unwrap();
+                    rx.recv().await.unwrap()
+                }
+            };
+            let config = BrokerConfig {
+                admin_token: Some("Pequod".to_string()),
+                ..BrokerConfig::default()
+            };
             let (broker_tx, broker_rx) = channel::unbounded::<ClientMsg>();
-            let broker_task = smol::spawn(broker_actor(broker_rx));
-            let join_msg = ClientMsg::Join(
-                Join {
-                    name: player_one.clone(),
-                    room: None,
-                },
-                client_tx,
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (first, first_channels) = room_with(&broker_tx, &["Ahab", "Ishmael"]).await;
+            let (second, second_channels) = room_with(&broker_tx, &["Starbuck", "Ahab"]).await;
+
+            assert_eq!(
+                find(broker_tx.clone(), "pequod", "Ahab").await,
+                Err(AdminErr::Unauthorized)
             );
-            broker_tx.send(join_msg).await.unwrap();
-            let (Connected { room_id, players }, player_one_broker_stream) =
-                client_rx.recv().await.unwrap().unwrap();
-            assert_eq!(players, vec![player_one.clone()]);
-            let (client_tx, client_rx) = channel::bounded(1);
-            let snd_msg = ClientMsg::Join(
-                Join {
-                    name: player_two.clone(),
-                    room: Some(room_id.clone()),
-                },
-                client_tx,
+            let mut both = vec![first.clone(), second];
+            both.sort();
+            assert_eq!(
+                find(broker_tx.clone(), "Pequod", "Ahab").await,
+                Ok(AdminReply::Found { rooms: both })
             );
-            broker_tx.send(snd_msg).await.unwrap();
-            let (Connected { room_id, players }, player_two_broker_stream) =
-                client_rx.recv().await.unwrap().unwrap();
             assert_eq!(
-                to_set(players),
-                to_set(vec![player_one.clone(), player_two.clone()])
+                find(broker_tx.clone(), "Pequod", "Ishmael").await,
+                Ok(AdminReply::Found { rooms: vec![first] })
             );
             assert_eq!(
-                player_one_broker_stream.recv().await.unwrap(),
-                BrokerMsg::Join(Arc::from(player_two.clone()))
+                find(broker_tx.clone(), "Pequod", "Queequeg").await,
+                Ok(AdminReply::Found { rooms: vec![] })
             );
+            drop((broker_tx, first_channels, second_channels));
+            broker_task.await.unwrap();
+        })
+    }
 
-            broker_tx
-                .send(ClientMsg::Room(RoomMsg::Start {
-                    room: room_id.clone(),
-                }))
+    #[test]
+    fn stale_rooms_are_closed() {
+        smol::block_on(async {
+            let (mut rooms, mut index, room_id, channels) =
+                table_with(&["Ahab", "Ishmael", "Queequeg"]);
+            let config = BrokerConfig::default();
+
+            sweep_stale_rooms(&mut rooms, &mut index, &config, &mut Deadlines::new())
                 .await
                 .unwrap();
-            for chan in &[player_one_broker_stream, player_two_broker_stream] {
-                assert_eq!(chan.recv().await.unwrap(), BrokerMsg::NotEnoughPlayers);
-            }
+            assert_eq!(rooms.len(), 1);
 
-            // drop the sending channel so the broker ends
-            drop(broker_tx);
+            // a round being played is given much longer
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            deal_bank(room, "Ahab");
+            room.last_activity = Instant::now() - config.room_ttl;
+            sweep_stale_rooms(&mut rooms, &mut index, &config, &mut Deadlines::new())
+                .await
+                .unwrap();
+            assert_eq!(rooms.len(), 1);
 
-            broker_task.await.unwrap();
+            // while anyone doing anything keeps the room open
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.last_activity = Instant::now() - config.playing_room_ttl;
+            room.touch(&"Queequeg".to_string());
+            sweep_stale_rooms(&mut rooms, &mut index, &config, &mut Deadlines::new())
+                .await
+                .unwrap();
+            assert_eq!(rooms.len(), 1);
+
+            let room = rooms.get_room_mut(&room_id).unwrap();
+            room.last_activity = Instant::now() - config.playing_room_ttl;
+            sweep_stale_rooms(&mut rooms, &mut index, &config, &mut Deadlines::new())
+                .await
+                .unwrap();
+            assert!(rooms.is_empty());
+            index.assert_consistent(&rooms);
+            for chan in &channels {
+                assert_eq!(
+                    chan.try_recv().unwrap(),
+                    BrokerMsg::RoomClosed {
+                        reason: CloseReason::Idle
+                    }
+                );
+                assert!(chan.try_recv().unwrap_err().is_closed());
+            }
         })
     }
 }
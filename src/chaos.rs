@@ -0,0 +1,318 @@
+//! Test-only fault injection for the channels between client actors and the broker.
+//! Messages are held back for a random delay, which reorders messages from different lanes (e.g. players)
+//! within the delay bound while keeping each lane in order, the way real sockets would.
+//! Everything is driven by a seeded RNG so a failing seed can be replayed.
+//! Messages are never dropped, since there is no way yet to resync a client that missed one.
+//! Run with `cargo test --features chaos`.
+
+use fastrand::Rng;
+use smol::channel::{self, Receiver};
+use smol::{future, Timer};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    pub seed: u64,
+    pub max_delay: Duration,
+}
+
+impl Chaos {
+    /// Relays everything from `rx` into the returned receiver with random delays.
+    /// `lane` groups messages that must stay in order relative to each other
+    pub fn relay<T, K, L>(&self, rx: Receiver<T>, lane: L) -> Receiver<T>
+    where
+        T: Send + 'static,
+        K: Hash + Eq + Send + 'static,
+        L: Fn(&T) -> K + Send + 'static,
+    {
+        let (tx, out) = channel::unbounded();
+        let rng = Rng::with_seed(self.seed);
+        let max_delay_micros = self.max_delay.as_micros() as u64;
+        smol::spawn(async move {
+            // keyed by release time, with a sequence number to break ties
+            let mut pending = BTreeMap::<(Instant, u64), T>::new();
+            let mut lane_release = HashMap::<K, Instant>::new();
+            let mut seq = 0;
+            let mut open = true;
+            while open || !pending.is_empty() {
+                let next_release = pending.keys().next().map(|(at, _)| *at);
+                let recv = async { Some(rx.recv().await) };
+                let received = match (open, next_release) {
+                    (true, Some(at)) => {
+                        let release = async {
+                            Timer::at(at).await;
+                            None
+                        };
+                        future::or(recv, release).await
+                    }
+                    (true, None) => recv.await,
+                    (false, Some(at)) => {
+                        Timer::at(at).await;
+                        None
+                    }
+                    (false, None) => break,
+                };
+
+                match received {
+                    Some(Ok(msg)) => {
+                        let delay = Duration::from_micros(rng.u64(..=max_delay_micros));
+                        let earliest = lane_release.entry(lane(&msg)).or_insert(Instant::now());
+                        // never overtake an earlier message of the same lane
+                        let at = (Instant::now() + delay).max(*earliest);
+                        *earliest = at;
+                        pending.insert((at, seq), msg);
+                        seq += 1;
+                    }
+                    Some(Err(_)) => open = false,
+                    None => {
+                        let now = Instant::now();
+                        while let Some(entry) = pending.first_entry() {
+                            if entry.key().0 > now {
+                                break;
+                            }
+                            if tx.send(entry.remove()).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .detach();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{broker_actor, BrokerConfig, BrokerMsg, Connected, JoinErr};
+    use crate::client::{ClientMsg, Join, RoomMsg, PROTOCOL_VERSION};
+    use crate::settings::RoomSettings;
+    use crate::{PlayerId, RoomId, SessionToken};
+    use smol::channel::Sender;
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+
+    const SEEDS: u64 = 32;
+    const ROUNDS: usize = 3;
+
+    /// One websocket's view of the room
+    struct Connection {
+        name: PlayerId,
+        room: RoomId,
+        id: SessionToken,
+        // every connection is its own client actor, so its messages only stay in order among themselves
+        tx: Sender<ClientMsg>,
+        roster: Vec<PlayerId>,
+        // the broker's end, kept to drop out of the room the way a closed websocket does
+        room_rx: Receiver<BrokerMsg>,
+        received: Arc<Mutex<Vec<BrokerMsg>>>,
+    }
+
+    impl Connection {
+        async fn send(&self, msg: RoomMsg) {
+            self.tx.send(ClientMsg::Room(self.id, msg)).await.unwrap();
+        }
+
+        fn started(&self) -> usize {
+            let received = self.received.lock().unwrap();
+            received
+                .iter()
+                .filter(|msg| matches!(msg, BrokerMsg::Started(_)))
+                .count()
+        }
+
+        /// The roster this connection was last told about
+        fn believed_roster(&self) -> BTreeSet<PlayerId> {
+            let received = self.received.lock().unwrap();
+            let latest = received.iter().rev().find_map(|msg| match msg {
+                BrokerMsg::Roster { players, .. } => {
+                    Some(players.iter().map(|entry| entry.name.clone()).collect())
+                }
+                _ => None,
+            });
+            latest.unwrap_or_else(|| self.roster.iter().cloned().collect())
+        }
+    }
+
+    /// Opens a chaotic connection to the broker and joins (or creates) a room,
+    /// retrying while the name is still held by a connection whose leave is in flight
+    async fn connect(
+        chaos: &mut Chaos,
+        broker_tx: &Sender<ClientMsg>,
+        name: &str,
+        room: Option<RoomId>,
+    ) -> Connection {
+        let (tx, rx) = channel::unbounded();
+        chaos.seed += 1;
+        let rx = chaos.relay(rx, |_| ());
+        let inbox = broker_tx.clone();
+        smol::spawn(async move {
+            while let Ok(msg) = rx.recv().await {
+                if inbox.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+        let (
+            Connected {
+                room_id,
+                players,
+                id,
+                ..
+            },
+            room_rx,
+        ) = loop {
+            let (join_tx, join_rx) = channel::bounded(1);
+            let join = Join {
+                name: name.to_string(),
+                room: room.clone(),
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            tx.send(ClientMsg::Join(join, join_tx)).await.unwrap();
+            match join_rx.recv().await.unwrap() {
+                Ok(joined) => break joined,
+                Err(JoinErr::UsernameTaken { .. }) => Timer::after(chaos.max_delay).await,
+                Err(err) => panic!("{} could not join: {:?}", name, err),
+            };
+        };
+
+        chaos.seed += 1;
+        let relayed = chaos.relay(room_rx.clone(), |_| ());
+        let received = Arc::new(Mutex::new(vec![]));
+        let sink = Arc::clone(&received);
+        smol::spawn(async move {
+            while let Ok(msg) = relayed.recv().await {
+                sink.lock().unwrap().push(msg);
+            }
+        })
+        .detach();
+        Connection {
+            name: name.to_string(),
+            room: room_id,
+            id,
+            tx,
+            roster: players,
+            room_rx,
+            received,
+        }
+    }
+
+    /// Waits long enough for every delayed message of the scenario so far to be delivered
+    async fn settle(chaos: &Chaos) {
+        Timer::after(chaos.max_delay * 4 + Duration::from_millis(20)).await;
+    }
+
+    async fn scenario(seed: u64) {
+        let rng = Rng::with_seed(seed);
+        let mut chaos = Chaos {
+            seed: rng.u64(..),
+            max_delay: Duration::from_millis(5),
+        };
+        let (broker_tx, broker_rx) = channel::unbounded();
+        let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+        let names = ["Ahab", "Ishmael", "Queequeg", "Starbuck", "Stubb"];
+
+        let host = connect(&mut chaos, &broker_tx, names[0], None).await;
+        let room = host.room.clone();
+        // indexed like names, the connections that have since been replaced are kept in `gone`
+        let mut seated = vec![host];
+        for name in &names[1..] {
+            seated.push(connect(&mut chaos, &broker_tx, name, Some(room.clone())).await);
+        }
+        let mut gone = vec![];
+        let mut starts = vec![0; names.len()];
+
+        for _ in 0..ROUNDS {
+            // someone refreshes their page, and their old connection is gone before its leave arrives
+            let leaver = rng.usize(1..names.len());
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: names[leaver].to_string(),
+            };
+            seated[leaver].send(leave).await;
+            seated[leaver].room_rx.close();
+
+            // while the rest of the room keeps going
+            for connection in seated.iter().filter(|c| !c.room_rx.is_closed()) {
+                for _ in 0..rng.usize(..4) {
+                    let msg = RoomMsg::SetReady {
+                        room: room.clone(),
+                        name: connection.name.clone(),
+                        ready: rng.bool(),
+                    };
+                    connection.send(msg).await;
+                }
+            }
+
+            let rejoined = connect(&mut chaos, &broker_tx, names[leaver], Some(room.clone())).await;
+            gone.push((
+                std::mem::replace(&mut seated[leaver], rejoined),
+                starts[leaver],
+            ));
+            starts[leaver] = 0;
+
+            seated[0]
+                .send(RoomMsg::Start {
+                    room: room.clone(),
+                    name: names[0].to_string(),
+                })
+                .await;
+            starts.iter_mut().for_each(|count| *count += 1);
+            settle(&chaos).await;
+
+            // everyone takes a wild guess, the spy's ends the round so the next one can start
+            for connection in &seated {
+                let guess = RoomMsg::GuessLocation {
+                    room: room.clone(),
+                    name: connection.name.clone(),
+                    location: String::new(),
+                };
+                connection.send(guess).await;
+            }
+            settle(&chaos).await;
+        }
+        // let the last debounced state broadcasts go out
+        Timer::after(Duration::from_millis(1100)).await;
+        settle(&chaos).await;
+
+        let everyone = names.iter().map(|name| name.to_string()).collect();
+        for (connection, expected) in &gone {
+            assert_eq!(connection.started(), *expected, "seed {}", seed);
+        }
+        for (connection, expected) in seated.iter().zip(&starts) {
+            assert_eq!(connection.started(), *expected, "seed {}", seed);
+            assert_eq!(connection.believed_roster(), everyone, "seed {}", seed);
+        }
+
+        for connection in &seated {
+            let leave = RoomMsg::Leave {
+                room: room.clone(),
+                name: connection.name.clone(),
+            };
+            connection.send(leave).await;
+        }
+        settle(&chaos).await;
+        drop((seated, gone, broker_tx));
+        let table = broker_task.await.expect("the broker failed");
+        assert!(table.is_empty(), "seed {}", seed);
+    }
+
+    #[test]
+    fn rounds_survive_chaotic_delivery() {
+        for seed in 0..SEEDS {
+            smol::block_on(scenario(seed));
+        }
+    }
+}
@@ -1,46 +1,342 @@
-use crate::broker::{BrokerMsg, Connected, JoinErr, JoinResult};
-use async_tungstenite::tungstenite::{error::Error as WsErr, Message as WsMsg};
+//! The client actor serving one websocket: it parses what the browser sends into messages for the broker,
+//! and passes on what the broker sends the player
+
+use crate::broker::{
+    AdminResult, BrokerMsg, Connected, JoinErr, JoinResult, RoomSummary, ServerStats,
+};
+use crate::codec::Codec;
+use crate::limits::Limit;
+use crate::settings::RoomSettings;
+use crate::throttle::TokenBucket;
+use crate::{PlayerId, RoomId, SessionToken};
+use async_tungstenite::tungstenite::{
+    error::{CapacityError, Error as WsErr},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message as WsMsg,
+};
 use futures_util::{
     sink::{Sink, SinkExt},
     stream::{self, Stream, StreamExt},
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json;
 use smol::{
     channel::{self, Receiver, Sender},
-    pin,
+    future, pin, Timer,
 };
-use spyfall::{PlayerId, RoomId};
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// how often the client is pinged while in a room
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// a client that sends nothing (not even a pong) for this long is treated as dropped
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(40);
+// how many messages a client can send the broker in a burst, and per second after that
+const MSG_BURST: Limit = Limit::new("max_message_burst", 20);
+const MSGS_PER_SEC: u32 = 5;
+// a socket that goes this long without a word before joining a room is closed, instead of tying up its task forever
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(10);
+// the message schema spoken here, and the oldest one still understood.
+// Bump PROTOCOL_VERSION whenever messages change in a way older clients would misread
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
 
 /// What the client actor receives from the browser
 #[derive(Debug, Clone)]
 pub enum ClientMsg {
     Join(Join, Sender<JoinResult>),
-    Room(RoomMsg),
+    // a reconnect token from an earlier Connected, to take that seat back without knowing the room or name
+    Resume(String, Sender<JoinResult>),
+    // a look at the public rooms, for a "join a random game" screen
+    ListRooms(Sender<Vec<RoomSummary>>),
+    // what the broker enforces, for the protocol manifest
+    GetLimits(Sender<Vec<Limit>>),
+    // how many rooms and players there are, for an "N players online" widget
+    Stats(Sender<ServerStats>),
+    // every location a round can be dealt at, to show the board before joining a room
+    GetLocations(Sender<Vec<Arc<str>>>),
+    // along with the id of the seat that sent it
+    Room(SessionToken, RoomMsg),
+    // from an operator, who doesn't join a room to send it
+    Admin(AdminMsg, Sender<AdminResult>),
+}
+
+/// An operator's command, only carried out when the token is the server's admin token
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminMsg {
+    pub admin_token: String,
+    pub cmd: AdminCmd,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum AdminCmd {
+    // closes a room for everyone in it, the way the host closing it would
+    ForceClose { room: RoomId },
+    // which rooms a player by this name is seated in, held seats included
+    FindPlayer { name: PlayerId },
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Join {
     pub room: Option<RoomId>,
     pub name: PlayerId,
+    // only used when creating a new room
+    #[serde(default)]
+    pub settings: RoomSettings,
+    // makes a new room private, and is needed to join one
+    #[serde(default)]
+    pub password: Option<String>,
+    // from the Connected of an earlier connection, to take the same seat back
+    #[serde(default)]
+    pub reconnect_token: Option<String>,
+    // the code a new room should have instead of a random one
+    #[serde(default)]
+    pub requested_code: Option<String>,
+    // from the host's Invite, gets into a private room without the password
+    #[serde(default)]
+    pub invite: Option<String>,
+    // the schema the client was written against, clients from before versioning speak the first
+    #[serde(default = "first_protocol_version")]
+    pub protocol_version: u16,
+    // where the connection comes from, filled in by the client actor rather than the browser
+    #[serde(skip)]
+    pub peer: Option<IpAddr>,
+}
+
+fn first_protocol_version() -> u16 {
+    1
+}
+
+/// What the browser can send before it is in a room
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum LobbyMsg {
+    // boxed, Join is far larger than any command
+    Join(Box<Join>),
+    Cmd(LobbyCmd),
+    Admin(AdminMsg),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+enum LobbyCmd {
+    ListRooms,
+    GetManifest,
+    GetStats,
+    GetLocations,
+    Resume { reconnect_token: String },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RoomMsg {
-    Leave { room: RoomId, name: PlayerId },
-    Start { room: RoomId },
+    Leave {
+        room: RoomId,
+        name: PlayerId,
+    },
+    // the client's connection failed rather than them leaving
+    Disconnect {
+        room: RoomId,
+        name: PlayerId,
+    },
+    Start {
+        room: RoomId,
+        name: PlayerId,
+    },
+    // a start that overrides the random deal, only accepted by debug builds
+    #[cfg(feature = "debug-controls")]
+    DebugStart {
+        room: RoomId,
+        name: PlayerId,
+        spy: Option<PlayerId>,
+        location: Option<String>,
+    },
+    SetReady {
+        room: RoomId,
+        name: PlayerId,
+        ready: bool,
+    },
+    GetHistory {
+        room: RoomId,
+        name: PlayerId,
+    },
+    GetScores {
+        room: RoomId,
+        name: PlayerId,
+    },
+    GetRoomInfo {
+        room: RoomId,
+        name: PlayerId,
+    },
+    // the locations of the room's category, which rounds are dealt from
+    GetLocations {
+        room: RoomId,
+        name: PlayerId,
+    },
+    // what the player was dealt this round, for when they forget
+    GetAssignment {
+        room: RoomId,
+        name: PlayerId,
+    },
+    Chat {
+        room: RoomId,
+        name: PlayerId,
+        text: String,
+    },
+    MarkLocation {
+        room: RoomId,
+        name: PlayerId,
+        location: String,
+        crossed: bool,
+    },
+    GuessLocation {
+        room: RoomId,
+        name: PlayerId,
+        location: String,
+    },
+    // None deals from every category
+    SetCategory {
+        room: RoomId,
+        name: PlayerId,
+        category: Option<String>,
+    },
+    Pause {
+        room: RoomId,
+        name: PlayerId,
+    },
+    Resume {
+        room: RoomId,
+        name: PlayerId,
+    },
+    RequestExtension {
+        room: RoomId,
+        name: PlayerId,
+    },
+    VoteExtension {
+        room: RoomId,
+        name: PlayerId,
+        approve: bool,
+    },
+    // the host handing the room over to someone else
+    TransferHost {
+        room: RoomId,
+        from: PlayerId,
+        to: PlayerId,
+    },
+    // the host removing someone from the room
+    Kick {
+        room: RoomId,
+        by: PlayerId,
+        target: PlayerId,
+    },
+    // fixing a typo in your name without leaving
+    Rename {
+        room: RoomId,
+        from: PlayerId,
+        to: String,
+    },
+    // the host ending the room for everyone in it
+    CloseRoom {
+        room: RoomId,
+        name: PlayerId,
+    },
+    // the host asking for a code that lets someone in without the password
+    CreateInvite {
+        room: RoomId,
+        name: PlayerId,
+    },
+}
+
+impl RoomMsg {
+    /// The room and player a message was sent on behalf of, if it names one
+    pub fn sender(&self) -> Option<(&RoomId, &PlayerId)> {
+        match self {
+            #[cfg(feature = "debug-controls")]
+            Self::DebugStart { room, name, .. } => Some((room, name)),
+            Self::Leave { room, name }
+            | Self::Start { room, name }
+            | Self::Disconnect { room, name }
+            | Self::SetReady { room, name, .. }
+            | Self::GetHistory { room, name }
+            | Self::GetScores { room, name }
+            | Self::GetRoomInfo { room, name }
+            | Self::GetLocations { room, name }
+            | Self::GetAssignment { room, name }
+            | Self::Chat { room, name, .. }
+            | Self::MarkLocation { room, name, .. }
+            | Self::GuessLocation { room, name, .. }
+            | Self::SetCategory { room, name, .. }
+            | Self::Pause { room, name }
+            | Self::Resume { room, name }
+            | Self::CloseRoom { room, name }
+            | Self::CreateInvite { room, name }
+            | Self::RequestExtension { room, name }
+            | Self::VoteExtension { room, name, .. }
+            | Self::TransferHost {
+                room, from: name, ..
+            }
+            | Self::Kick { room, by: name, .. }
+            | Self::Rename {
+                room, from: name, ..
+            } => Some((room, name)),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub enum RoomCmd {
     Leave,
     Start,
+    #[cfg(feature = "debug-controls")]
+    DebugStart {
+        #[serde(default)]
+        spy: Option<PlayerId>,
+        #[serde(default)]
+        location: Option<String>,
+    },
+    SetReady {
+        ready: bool,
+    },
+    GetHistory,
+    GetScores,
+    GetRoomInfo,
+    GetLocations,
+    GetAssignment,
+    Chat {
+        text: String,
+    },
+    MarkLocation {
+        location: String,
+        crossed: bool,
+    },
+    GuessLocation {
+        location: String,
+    },
+    SetCategory {
+        category: Option<String>,
+    },
+    Pause,
+    Resume,
+    RequestExtension,
+    VoteExtension {
+        approve: bool,
+    },
+    TransferHost {
+        to: PlayerId,
+    },
+    Kick {
+        target: PlayerId,
+    },
+    Rename {
+        to: String,
+    },
+    CloseRoom,
+    CreateInvite,
 }
 
 #[derive(Debug)]
 pub enum ParseErr {
     MalformedMsg(serde_json::Error),
+    MalformedBinaryMsg(rmp_serde::decode::Error),
     NonTextMsg,
     ClientDisconnected,
 }
@@ -53,6 +349,11 @@ impl std::fmt::Display for ParseErr {
                 "message sent could not be parsed as a valid type, more details below:\n{}",
                 serde_err
             ),
+            Self::MalformedBinaryMsg(rmp_err) => write!(
+                f,
+                "binary message sent could not be parsed as a valid type, more details below:\n{}",
+                rmp_err
+            ),
             Self::NonTextMsg => write!(f, "message was not of supported type"),
             Self::ClientDisconnected => write!(
                 f,
@@ -64,45 +365,263 @@ impl std::fmt::Display for ParseErr {
 
 impl std::error::Error for ParseErr {}
 
-// general control flow ADT
-enum Either<A, B> {
-    Left(A),
-    Right(B),
+/// Why a session ended that was no fault of the client
+#[derive(Debug)]
+pub enum ClientErr {
+    // the broker's channel closed under the session, so no room can be reached anymore
+    BrokerGone,
+}
+
+impl std::fmt::Display for ClientErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BrokerGone => write!(f, "the broker stopped while the client was connected"),
+        }
+    }
+}
+
+impl std::error::Error for ClientErr {}
+
+fn broker_gone<E>(_: E) -> ClientErr {
+    ClientErr::BrokerGone
+}
+
+/// The part of a join result that is sent back to the browser
+type JoinReply = Result<Connected, JoinErr>;
+
+/// What a client can find out about the protocol before joining, so it doesn't have to guess at limits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Manifest {
+    protocol_versions: (u16, u16),
+    limits: Vec<Limit>,
+}
+
+/// The largest websocket message a client can send, as the socket is configured with and clients are told
+pub fn message_limit(max_bytes: usize) -> Limit {
+    Limit::new("max_message_bytes", max_bytes as u64)
+}
+
+/// How a connection's messages go over the socket, and how many it can still send
+struct Wire {
+    codec: Codec,
+    // one socket flooding the broker would hold up every room
+    bucket: TokenBucket,
+    max_message: Limit,
+}
+
+/// The room a joined client sits in, and the id its room messages are stamped with
+struct Seat {
+    room: RoomId,
+    id: SessionToken,
 }
 
+// everything the room loop can be woken up by
+enum RoomEvent {
+    Broker(BrokerMsg),
+    // the broker dropped this player from the room
+    RemovedFromRoom,
+    Ws(Result<WsMsg, WsErr>),
+    // the socket ended without a close frame
+    WsEnded,
+    Heartbeat,
+}
+
+/// Serves one websocket, which can't be sent messages over `max_message` (the socket is configured to refuse them)
 pub async fn client_actor(
     websocket: impl Stream<Item = Result<WsMsg, WsErr>> + Sink<WsMsg, Error = WsErr> + Unpin + Send,
     broker_tx: Sender<ClientMsg>,
+    peer: Option<IpAddr>,
+    codec: Codec,
+    max_message: Limit,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let wire = Wire {
+        codec,
+        bucket: TokenBucket::new(MSG_BURST, MSGS_PER_SEC, Instant::now()),
+        max_message,
+    };
+    serve_client(websocket, broker_tx, peer, wire, LOBBY_TIMEOUT).await
+}
+
+async fn serve_client(
+    websocket: impl Stream<Item = Result<WsMsg, WsErr>> + Sink<WsMsg, Error = WsErr> + Unpin + Send,
+    broker_tx: Sender<ClientMsg>,
+    peer: Option<IpAddr>,
+    wire: Wire,
+    lobby_timeout: Duration,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let (ws_sink, ws_stream) = websocket.split();
     // pin these to the stack and make them mutable
     pin!(ws_sink, ws_stream);
+    let (codec, max_message) = (wire.codec, wire.max_message);
+    let session = client_session(
+        &mut ws_stream,
+        &mut ws_sink,
+        &broker_tx,
+        peer,
+        wire,
+        lobby_timeout,
+    )
+    .await;
+    if let Some(WsErr::Capacity(CapacityError::MessageTooLong { size, .. })) =
+        session.as_ref().err().and_then(|err| err.downcast_ref())
+    {
+        if let Err(info) = max_message.check(*size as u64) {
+            log::debug!("Closing a connection that sent a {} byte message", size);
+            // the socket may be beyond telling, the oversized message is what gets reported
+            let _ = send_back_msg(&BrokerMsg::MessageTooLarge(info), codec, &mut ws_sink).await;
+            let frame = CloseFrame {
+                code: CloseCode::Size,
+                reason: "the message was too large".into(),
+            };
+            let _ = ws_sink.send(WsMsg::Close(Some(frame))).await;
+        }
+    }
+    if let Err(err) = &session {
+        if let Some(ClientErr::BrokerGone) = err.downcast_ref() {
+            log::error!("Closing a connection since the broker is gone");
+            let frame = CloseFrame {
+                code: CloseCode::Again,
+                reason: "the server is restarting, try again shortly".into(),
+            };
+            // the client is told why if it is still listening, the broker error is what gets reported
+            let _ = ws_sink.send(WsMsg::Close(Some(frame))).await;
+        }
+    }
+    session
+}
+
+/// Everything from the first message to leaving the room, with broker channel failures reported as `ClientErr::BrokerGone`
+/// so the caller can tell the client before the socket goes away
+async fn client_session<R, W>(
+    ws_stream: &mut Pin<&mut R>,
+    ws_sink: &mut Pin<&mut W>,
+    broker_tx: &Sender<ClientMsg>,
+    peer: Option<IpAddr>,
+    mut wire: Wire,
+    lobby_timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: Stream<Item = Result<WsMsg, WsErr>>,
+    W: Sink<WsMsg, Error = WsErr>,
+{
+    let codec = wire.codec;
 
-    let first_msg = ws_stream
-        .next()
-        .await
-        .ok_or_else(|| err_msg("A general Websocket Error"))??;
-    let join_msg = parse_msg::<Join>(first_msg)?;
-    let name = join_msg.name.clone();
+    // rooms can be looked at any number of times before joining one, as long as the client keeps talking
     let (join_tx, join_rx) = channel::bounded(1);
-    broker_tx.send(ClientMsg::Join(join_msg, join_tx)).await?;
-    let (room_rx_opt, join_res) = transpose_join_res(join_rx.recv().await?);
-    send_back_msg(&join_res, &mut ws_sink).await?;
+    let join_msg = loop {
+        let lobby_msg = future::or(async { Some(ws_stream.next().await) }, async {
+            Timer::after(lobby_timeout).await;
+            None
+        })
+        .await;
+        let lobby_msg = match lobby_msg {
+            Some(lobby_msg) => lobby_msg,
+            None => {
+                log::debug!("Closing a connection that went quiet before joining a room");
+                let frame = CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "nothing was sent in time".into(),
+                };
+                ws_sink.send(WsMsg::Close(Some(frame))).await?;
+                return Ok(());
+            }
+        };
+        let lobby_msg = lobby_msg.ok_or_else(|| err_msg("A general Websocket Error"))??;
+        let lobby_msg = parse_msg::<LobbyMsg>(lobby_msg, codec)?;
+        if let Err(info) = wire.bucket.try_take(Instant::now()) {
+            log::warn!("Throttled a client sending too many messages before joining");
+            send_back_msg(&BrokerMsg::RateLimited(info), wire.codec, ws_sink).await?;
+            continue;
+        }
+        match lobby_msg {
+            LobbyMsg::Join(join_msg) => {
+                let version = join_msg.protocol_version;
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                    break ClientMsg::Join(Join { peer, ..*join_msg }, join_tx);
+                }
+                log::debug!("Closing a connection speaking protocol version {}", version);
+                let msg = BrokerMsg::UnsupportedVersion {
+                    server_supports: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+                };
+                send_back_msg(&msg, codec, ws_sink).await?;
+                let frame = CloseFrame {
+                    code: CloseCode::Protocol,
+                    reason: "unsupported protocol version".into(),
+                };
+                ws_sink.send(WsMsg::Close(Some(frame))).await?;
+                return Ok(());
+            }
+            LobbyMsg::Cmd(LobbyCmd::ListRooms) => {
+                let (list_tx, list_rx) = channel::bounded(1);
+                broker_tx
+                    .send(ClientMsg::ListRooms(list_tx))
+                    .await
+                    .map_err(broker_gone)?;
+                let rooms = list_rx.recv().await.map_err(broker_gone)?;
+                send_back_msg(&rooms, codec, ws_sink).await?;
+            }
+            LobbyMsg::Cmd(LobbyCmd::GetManifest) => {
+                let (limits_tx, limits_rx) = channel::bounded(1);
+                broker_tx
+                    .send(ClientMsg::GetLimits(limits_tx))
+                    .await
+                    .map_err(broker_gone)?;
+                let mut limits = limits_rx.recv().await.map_err(broker_gone)?;
+                limits.extend([MSG_BURST, wire.max_message]);
+                let manifest = Manifest {
+                    protocol_versions: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+                    limits,
+                };
+                send_back_msg(&manifest, codec, ws_sink).await?;
+            }
+            LobbyMsg::Cmd(LobbyCmd::GetStats) => {
+                let (stats_tx, stats_rx) = channel::bounded(1);
+                broker_tx
+                    .send(ClientMsg::Stats(stats_tx))
+                    .await
+                    .map_err(broker_gone)?;
+                let stats = stats_rx.recv().await.map_err(broker_gone)?;
+                send_back_msg(&stats, codec, ws_sink).await?;
+            }
+            LobbyMsg::Cmd(LobbyCmd::GetLocations) => {
+                let (locations_tx, locations_rx) = channel::bounded(1);
+                broker_tx
+                    .send(ClientMsg::GetLocations(locations_tx))
+                    .await
+                    .map_err(broker_gone)?;
+                let locations = locations_rx.recv().await.map_err(broker_gone)?;
+                send_back_msg(&locations, codec, ws_sink).await?;
+            }
+            LobbyMsg::Cmd(LobbyCmd::Resume { reconnect_token }) => {
+                break ClientMsg::Resume(reconnect_token, join_tx);
+            }
+            LobbyMsg::Admin(admin_msg) => {
+                let (admin_tx, admin_rx) = channel::bounded(1);
+                broker_tx
+                    .send(ClientMsg::Admin(admin_msg, admin_tx))
+                    .await
+                    .map_err(broker_gone)?;
+                let res = admin_rx.recv().await.map_err(broker_gone)?;
+                send_back_msg(&res, codec, ws_sink).await?;
+            }
+        }
+    };
+    broker_tx.send(join_msg).await.map_err(broker_gone)?;
+    let join_res = join_rx.recv().await.map_err(broker_gone)?;
+    let (room_rx_opt, join_res) = transpose_join_res(join_res);
+    send_back_msg(&join_res, codec, ws_sink).await?;
 
-    if let Some((room_rx, room)) = room_rx_opt {
+    if let Some((room_rx, seat, mut name)) = room_rx_opt {
         let dropped = client_room_state(
-            room_rx,
-            &broker_tx,
-            &mut ws_stream,
-            &mut ws_sink,
-            &name,
-            &room,
+            room_rx, broker_tx, ws_stream, ws_sink, &mut name, &seat, &mut wire,
         )
         .await;
-        if let Err(_) = dropped {
+        if dropped.is_err() && !broker_tx.is_closed() {
+            let Seat { room, id } = seat;
             broker_tx
-                .send(ClientMsg::Room(RoomMsg::Leave { room, name }))
-                .await?;
+                .send(ClientMsg::Room(id, RoomMsg::Disconnect { room, name }))
+                .await
+                .map_err(broker_gone)?;
         }
         dropped?;
     }
@@ -119,42 +638,221 @@ async fn client_room_state<R, W>(
     broker_tx: &Sender<ClientMsg>,
     ws_stream: &mut Pin<&mut R>,
     ws_sink: &mut Pin<&mut W>,
-    player: &PlayerId,
-    room: &RoomId,
+    // kept up to date with renames, so the caller knows who to report dropped
+    player: &mut PlayerId,
+    seat: &Seat,
+    wire: &mut Wire,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
 where
     R: Stream<Item = Result<WsMsg, WsErr>>,
     W: Sink<WsMsg, Error = WsErr>,
 {
-    let room_rx = room_rx.map(|msg| Either::Left(msg));
-    let ws_stream = ws_stream.map(|msg| Either::Right(msg));
-    let mut select_stream = stream::select(ws_stream, room_rx);
-    while let Some(either) = select_stream.next().await {
-        match either {
-            Either::Left(broker_msg) => {
-                println!(
+    let Seat { room, id } = seat;
+    let room_rx = room_rx
+        .map(RoomEvent::Broker)
+        .chain(stream::iter(Some(RoomEvent::RemovedFromRoom)));
+    let ws_stream = ws_stream
+        .map(RoomEvent::Ws)
+        .chain(stream::iter(Some(RoomEvent::WsEnded)));
+    let heartbeat = Timer::interval(HEARTBEAT_INTERVAL).map(|_| RoomEvent::Heartbeat);
+    let mut select_stream = stream::select(stream::select(ws_stream, room_rx), heartbeat);
+    // any frame from the client proves the connection is still alive
+    let mut last_seen = Instant::now();
+    while let Some(event) = select_stream.next().await {
+        match event {
+            // every room is dropped along with the broker
+            RoomEvent::RemovedFromRoom if broker_tx.is_closed() => {
+                return Err(ClientErr::BrokerGone.into())
+            }
+            // there is nothing to tell the broker, it already let go of this player
+            RoomEvent::RemovedFromRoom => break,
+            // the connection dropped, rather than waiting out the heartbeat the broker is told right away
+            RoomEvent::WsEnded => return Err(err_msg("the websocket ended without a close frame")),
+            RoomEvent::Heartbeat => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    return Err(err_msg("the client stopped answering heartbeats"));
+                }
+                ws_sink.send(WsMsg::Ping(Vec::new())).await?;
+            }
+            RoomEvent::Broker(broker_msg) => {
+                log::debug!(
                     "(Player {}) Dealing with broker message {:?}",
-                    player, broker_msg
+                    player,
+                    broker_msg
                 );
-                send_back_msg(&broker_msg, ws_sink).await?;
+                send_back_msg(&broker_msg, wire.codec, ws_sink).await?;
+                if matches!(&broker_msg, BrokerMsg::Kicked { target, .. } if **target == **player) {
+                    let frame = CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "kicked from the room by the host".into(),
+                    };
+                    ws_sink.send(WsMsg::Close(Some(frame))).await?;
+                    break;
+                }
+                // the browser can go back to the home screen, there is no room left to be in
+                if let BrokerMsg::RoomClosed { .. } = &broker_msg {
+                    let frame = CloseFrame {
+                        code: CloseCode::Normal,
+                        reason: "the room was closed".into(),
+                    };
+                    ws_sink.send(WsMsg::Close(Some(frame))).await?;
+                    break;
+                }
+                if let BrokerMsg::Renamed { from, to } = &broker_msg {
+                    if **from == **player {
+                        *player = to.to_string();
+                    }
+                }
             }
-            Either::Right(ws_msg_res) => {
+            RoomEvent::Ws(ws_msg_res) => {
                 let ws_msg = ws_msg_res?;
-                println!(
+                last_seen = Instant::now();
+                // pongs answer our heartbeat and pings are answered by tungstenite itself
+                if matches!(ws_msg, WsMsg::Ping(_) | WsMsg::Pong(_)) {
+                    continue;
+                }
+                log::debug!(
                     "(Player {}) Dealing with room message from the websocket {}",
-                    player, ws_msg
+                    player,
+                    ws_msg
                 );
-                let cmd = parse_msg::<RoomCmd>(ws_msg)?;
+                // closing the socket cleanly is the same as leaving
+                let client_closed = matches!(ws_msg, WsMsg::Close(_));
+                let cmd = match ws_msg {
+                    WsMsg::Close(_) => RoomCmd::Leave,
+                    ws_msg => parse_msg::<RoomCmd>(ws_msg, wire.codec)?,
+                };
                 let exit = matches!(cmd, RoomCmd::Leave);
+                // leaving always goes through, so the room isn't left with a ghost
+                let throttled = if exit {
+                    None
+                } else {
+                    wire.bucket.try_take(Instant::now()).err()
+                };
+                if let Some(info) = throttled {
+                    log::warn!(
+                        "(Player {}) Throttled for sending too many messages",
+                        player
+                    );
+                    send_back_msg(&BrokerMsg::RateLimited(info), wire.codec, ws_sink).await?;
+                    continue;
+                }
                 let msg = match cmd {
                     RoomCmd::Leave => RoomMsg::Leave {
                         room: room.clone(),
                         name: player.clone(),
                     },
-                    RoomCmd::Start => RoomMsg::Start { room: room.clone() },
+                    RoomCmd::Start => RoomMsg::Start {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    #[cfg(feature = "debug-controls")]
+                    RoomCmd::DebugStart { spy, location } => RoomMsg::DebugStart {
+                        room: room.clone(),
+                        name: player.clone(),
+                        spy,
+                        location,
+                    },
+                    RoomCmd::SetReady { ready } => RoomMsg::SetReady {
+                        room: room.clone(),
+                        name: player.clone(),
+                        ready,
+                    },
+                    RoomCmd::GetHistory => RoomMsg::GetHistory {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::GetScores => RoomMsg::GetScores {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::GetRoomInfo => RoomMsg::GetRoomInfo {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::GetLocations => RoomMsg::GetLocations {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::GetAssignment => RoomMsg::GetAssignment {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::Chat { text } => RoomMsg::Chat {
+                        room: room.clone(),
+                        name: player.clone(),
+                        text,
+                    },
+                    RoomCmd::MarkLocation { location, crossed } => RoomMsg::MarkLocation {
+                        room: room.clone(),
+                        name: player.clone(),
+                        location,
+                        crossed,
+                    },
+                    RoomCmd::GuessLocation { location } => RoomMsg::GuessLocation {
+                        room: room.clone(),
+                        name: player.clone(),
+                        location,
+                    },
+                    RoomCmd::SetCategory { category } => RoomMsg::SetCategory {
+                        room: room.clone(),
+                        name: player.clone(),
+                        category,
+                    },
+                    RoomCmd::Pause => RoomMsg::Pause {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::Resume => RoomMsg::Resume {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::RequestExtension => RoomMsg::RequestExtension {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::VoteExtension { approve } => RoomMsg::VoteExtension {
+                        room: room.clone(),
+                        name: player.clone(),
+                        approve,
+                    },
+                    RoomCmd::TransferHost { to } => RoomMsg::TransferHost {
+                        room: room.clone(),
+                        from: player.clone(),
+                        to,
+                    },
+                    RoomCmd::Kick { target } => RoomMsg::Kick {
+                        room: room.clone(),
+                        by: player.clone(),
+                        target,
+                    },
+                    RoomCmd::Rename { to } => RoomMsg::Rename {
+                        room: room.clone(),
+                        from: player.clone(),
+                        to,
+                    },
+                    RoomCmd::CloseRoom => RoomMsg::CloseRoom {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
+                    RoomCmd::CreateInvite => RoomMsg::CreateInvite {
+                        room: room.clone(),
+                        name: player.clone(),
+                    },
                 };
-                broker_tx.send(ClientMsg::Room(msg)).await?;
+                broker_tx
+                    .send(ClientMsg::Room(*id, msg))
+                    .await
+                    .map_err(broker_gone)?;
                 if exit {
+                    // a close from the client is already answered by tungstenite
+                    if !client_closed {
+                        let frame = CloseFrame {
+                            code: CloseCode::Normal,
+                            reason: "left room".into(),
+                        };
+                        ws_sink.send(WsMsg::Close(Some(frame))).await?;
+                    }
                     break;
                 }
             }
@@ -164,25 +862,34 @@ where
     Ok(())
 }
 
-/// Send a serialize-able message back to the websocket
+/// Send a serialize-able message back to the websocket, in the codec the connection agreed on
 async fn send_back_msg<W, S>(
     msg: &S,
+    codec: Codec,
     ws_write: &mut Pin<&mut W>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>>
 where
     W: Sink<WsMsg, Error = WsErr>,
     S: Serialize,
 {
-    let serialized_msg = serde_json::to_string(msg).expect("Failed to serialize msg back");
-    ws_write.send(WsMsg::text(serialized_msg)).await?;
+    let ws_msg = match codec {
+        Codec::Json => WsMsg::text(serde_json::to_string(msg)?),
+        // with field names, so messages are shaped the same as their JSON
+        Codec::MessagePack => WsMsg::binary(rmp_serde::to_vec_named(msg)?),
+    };
+    ws_write.send(ws_msg).await?;
     Ok(())
 }
 
 /// retreieve a deserialize-able message from the websocket
-/// (and deal with the many errors the websocket can present)
-pub fn parse_msg<D: DeserializeOwned>(ws_msg: WsMsg) -> Result<D, ParseErr> {
+/// (and deal with the many errors the websocket can present).
+/// Text frames are always JSON, binary frames are only understood once MessagePack was agreed on
+pub fn parse_msg<D: DeserializeOwned>(ws_msg: WsMsg, codec: Codec) -> Result<D, ParseErr> {
     match ws_msg {
-        WsMsg::Text(txt) => serde_json::from_str::<D>(&txt).map_err(|e| ParseErr::MalformedMsg(e)),
+        WsMsg::Text(txt) => serde_json::from_str::<D>(&txt).map_err(ParseErr::MalformedMsg),
+        WsMsg::Binary(bytes) if codec == Codec::MessagePack => {
+            rmp_serde::from_slice::<D>(&bytes).map_err(ParseErr::MalformedBinaryMsg)
+        }
         WsMsg::Close(_) => Err(ParseErr::ClientDisconnected),
         _ => Err(ParseErr::NonTextMsg),
     }
@@ -192,12 +899,15 @@ pub fn parse_msg<D: DeserializeOwned>(ws_msg: WsMsg) -> Result<D, ParseErr> {
 /// and a serialize-able message that can be sent back to the client
 fn transpose_join_res(
     join_res: JoinResult,
-) -> (
-    Option<(Receiver<BrokerMsg>, String)>,
-    Result<Connected, JoinErr>,
-) {
+) -> (Option<(Receiver<BrokerMsg>, Seat, PlayerId)>, JoinReply) {
     match join_res {
-        Ok((conn, rx)) => (Some((rx, conn.room_id.clone())), Ok(conn)),
+        Ok((conn, rx)) => {
+            let seat = Seat {
+                room: conn.room_id.clone(),
+                id: conn.id,
+            };
+            (Some((rx, seat, conn.name.clone())), Ok(conn))
+        }
         Err(err) => (None, Err(err)),
     }
 }
@@ -206,3 +916,401 @@ fn transpose_join_res(
 fn err_msg(err_description: &'static str) -> Box<dyn std::error::Error + Sync + Send> {
     Box::from(String::from(err_description))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{broker_actor, BrokerConfig, LeaveReason};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    /// A websocket whose client sends `incoming` and then either goes quiet, fails or drops, keeping what it is sent
+    #[derive(Default)]
+    struct MockSocket {
+        incoming: VecDeque<WsMsg>,
+        fails: Option<WsErr>,
+        ends: bool,
+        sent: Arc<Mutex<Vec<WsMsg>>>,
+    }
+
+    impl Stream for MockSocket {
+        type Item = Result<WsMsg, WsErr>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<Self::Item>> {
+            match self.incoming.pop_front() {
+                Some(msg) => Poll::Ready(Some(Ok(msg))),
+                None if self.fails.is_some() => Poll::Ready(self.fails.take().map(Err)),
+                None if self.ends => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl Sink<WsMsg> for MockSocket {
+        type Error = WsErr;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), WsErr>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: WsMsg) -> Result<(), WsErr> {
+            self.sent.lock().unwrap().push(msg);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), WsErr>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), WsErr>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn wire(codec: Codec) -> Wire {
+        Wire {
+            codec,
+            bucket: TokenBucket::new(MSG_BURST, MSGS_PER_SEC, Instant::now()),
+            max_message: message_limit(1024),
+        }
+    }
+
+    #[test]
+    fn unserializable_messages_are_an_error() {
+        smol::block_on(async {
+            let socket = MockSocket::default();
+            let sent = Arc::clone(&socket.sent);
+            pin!(socket);
+            // JSON object keys have to be strings
+            let msg = std::collections::HashMap::from([((1, 2), "position")]);
+            assert!(send_back_msg(&msg, Codec::Json, &mut socket).await.is_err());
+            assert!(sent.lock().unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn msgpack_clients_are_answered_in_binary() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let join = rmp_serde::to_vec_named(&serde_json::json!({ "name": "Ahab" })).unwrap();
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![WsMsg::binary(join), WsMsg::text("{}")]),
+                ends: true,
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            let timeout = Duration::from_secs(1);
+            let res = serve_client(
+                socket,
+                broker_tx.clone(),
+                None,
+                wire(Codec::MessagePack),
+                timeout,
+            );
+            // the text frame still had to be JSON, which `{}` is not a room command in
+            assert!(res.await.is_err());
+
+            match sent.lock().unwrap().as_slice() {
+                [WsMsg::Binary(reply), ..] => {
+                    let reply: serde_json::Value = rmp_serde::from_slice(reply).unwrap();
+                    assert_eq!(reply["Ok"]["name"], "Ahab");
+                }
+                other => panic!("expected a binary join reply, got {:?}", other),
+            }
+            // while JSON clients can't send binary frames
+            let join = WsMsg::binary(rmp_serde::to_vec_named(&"Ahab").unwrap());
+            assert!(matches!(
+                parse_msg::<LobbyMsg>(join, Codec::Json),
+                Err(ParseErr::NonTextMsg)
+            ));
+            drop(broker_tx);
+            broker_task.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn clients_are_told_the_limits_they_hit() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            // the manifest, then a burst one message over the limit, then a message the socket refused
+            let mut incoming = vec![WsMsg::text(r#""GetManifest""#)];
+            incoming.extend((0..MSG_BURST.max()).map(|_| WsMsg::text(r#""GetStats""#)));
+            let too_long = CapacityError::MessageTooLong {
+                size: 2000,
+                max_size: 1024,
+            };
+            let socket = MockSocket {
+                incoming: VecDeque::from(incoming),
+                fails: Some(WsErr::Capacity(too_long)),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            let res = serve_client(socket, broker_tx, None, wire(Codec::Json), LOBBY_TIMEOUT);
+            assert!(res.await.is_err());
+            broker_task.await.unwrap();
+
+            let sent = sent.lock().unwrap();
+            let text = |msg: &WsMsg| match msg {
+                WsMsg::Text(text) => serde_json::from_str::<serde_json::Value>(text).unwrap(),
+                other => panic!("expected a text message, got {:?}", other),
+            };
+            let manifest = text(&sent[0]);
+            assert_eq!(manifest["protocol_versions"], serde_json::json!([1, 1]));
+            let limits = manifest["limits"].as_array().unwrap();
+            for limit in [
+                MSG_BURST,
+                message_limit(1024),
+                BrokerConfig::default().limits()[0],
+            ] {
+                assert!(limits.contains(&serde_json::to_value(limit).unwrap()));
+            }
+            let burst = MSG_BURST.max() as usize;
+            assert_eq!(
+                text(&sent[burst]),
+                serde_json::json!({"RateLimited": {"name": "max_message_burst", "max": 20, "got": 21}})
+            );
+            assert_eq!(
+                text(&sent[burst + 1]),
+                serde_json::json!({"MessageTooLarge": {"name": "max_message_bytes", "max": 1024, "got": 2000}})
+            );
+            match &sent[burst + 2] {
+                WsMsg::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Size),
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn clients_on_other_protocol_versions_are_turned_away() {
+        smol::block_on(async {
+            let join = r#"{"name":"Ahab","protocol_version":2}"#;
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![WsMsg::text(join)]),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            let (broker_tx, broker_rx) = channel::unbounded();
+            serve_client(socket, broker_tx, None, wire(Codec::Json), LOBBY_TIMEOUT)
+                .await
+                .unwrap();
+
+            match sent.lock().unwrap().as_slice() {
+                [WsMsg::Text(reply), WsMsg::Close(Some(frame))] => {
+                    assert_eq!(reply, r#"{"UnsupportedVersion":{"server_supports":[1,1]}}"#);
+                    assert_eq!(frame.code, CloseCode::Protocol);
+                }
+                other => panic!("expected a refusal and a close frame, got {:?}", other),
+            }
+            assert!(broker_rx.is_empty());
+
+            // clients from before versioning are on the first version
+            let join: Join = serde_json::from_str(r#"{"name":"Ahab"}"#).unwrap();
+            assert_eq!(join.protocol_version, 1);
+        })
+    }
+
+    #[test]
+    fn silent_connections_are_closed() {
+        smol::block_on(async {
+            let socket = MockSocket::default();
+            let sent = Arc::clone(&socket.sent);
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let timeout = Duration::from_millis(50);
+            let started = Instant::now();
+            serve_client(socket, broker_tx, None, wire(Codec::Json), timeout)
+                .await
+                .unwrap();
+            assert!(started.elapsed() < timeout * 10);
+
+            match sent.lock().unwrap().as_slice() {
+                [WsMsg::Close(Some(frame))] => assert_eq!(frame.code, CloseCode::Policy),
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+            assert!(broker_rx.is_empty());
+
+            // saying something once doesn't keep the connection open forever
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![WsMsg::text(r#""GetStats""#)]),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            serve_client(socket, broker_tx, None, wire(Codec::Json), timeout)
+                .await
+                .unwrap();
+            broker_task.await.unwrap();
+            match sent.lock().unwrap().as_slice() {
+                [WsMsg::Text(_), WsMsg::Close(Some(frame))] => {
+                    assert_eq!(frame.code, CloseCode::Policy)
+                }
+                other => panic!("expected the stats and a close frame, got {:?}", other),
+            };
+        })
+    }
+
+    #[test]
+    fn clients_are_told_when_the_broker_is_gone() {
+        smol::block_on(async {
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![WsMsg::text(r#"{"name":"Ahab"}"#)]),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            let (broker_tx, broker_rx) = channel::unbounded();
+            drop(broker_rx);
+            let err = serve_client(
+                socket,
+                broker_tx,
+                None,
+                wire(Codec::Json),
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(ClientErr::BrokerGone)));
+
+            let sent = sent.lock().unwrap();
+            match sent.as_slice() {
+                [WsMsg::Close(Some(frame))] => assert_eq!(frame.code, CloseCode::Again),
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn leaving_closes_the_socket() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![
+                    WsMsg::text(r#"{"name":"Ahab"}"#),
+                    WsMsg::text(r#""Leave""#),
+                ]),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            client_actor(
+                socket,
+                broker_tx.clone(),
+                None,
+                Codec::Json,
+                message_limit(1024),
+            )
+            .await
+            .unwrap();
+
+            match sent.lock().unwrap().as_slice() {
+                [WsMsg::Text(_), WsMsg::Close(Some(frame))] => {
+                    assert_eq!(frame.code, CloseCode::Normal);
+                    assert_eq!(frame.reason, "left room");
+                }
+                other => panic!("expected the join reply and a close frame, got {:?}", other),
+            }
+            drop(broker_tx);
+            // the broker was still told, so the room is gone with its only player
+            assert!(broker_task.await.unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn closing_the_socket_is_leaving() {
+        smol::block_on(async {
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![
+                    WsMsg::text(r#"{"name":"Ahab"}"#),
+                    WsMsg::Close(None),
+                ]),
+                ..MockSocket::default()
+            };
+            let sent = Arc::clone(&socket.sent);
+            // a tab closed the polite way isn't a failed connection
+            client_actor(
+                socket,
+                broker_tx.clone(),
+                None,
+                Codec::Json,
+                message_limit(1024),
+            )
+            .await
+            .unwrap();
+
+            // tungstenite answers the close frame, so only the join reply was sent
+            assert!(matches!(sent.lock().unwrap().as_slice(), [WsMsg::Text(_)]));
+            drop(broker_tx);
+            assert!(broker_task.await.unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn dropped_sockets_dont_leave_ghosts() {
+        smol::block_on(async {
+            let config = BrokerConfig {
+                reconnect_grace: Duration::ZERO,
+                idle_sweep_interval: Duration::from_millis(20),
+                ..BrokerConfig::default()
+            };
+            let (broker_tx, broker_rx) = channel::unbounded();
+            let broker_task = smol::spawn(broker_actor(broker_rx, config));
+            let (join_tx, join_rx) = channel::bounded(1);
+            let join = Join {
+                room: None,
+                name: "Ahab".to_string(),
+                settings: RoomSettings::default(),
+                password: None,
+                reconnect_token: None,
+                requested_code: None,
+                invite: None,
+                protocol_version: PROTOCOL_VERSION,
+                peer: None,
+            };
+            broker_tx
+                .send(ClientMsg::Join(join, join_tx))
+                .await
+                .unwrap();
+            let (connected, host_rx) = join_rx.recv().await.unwrap().unwrap();
+
+            // the tab is closed without a close frame making it through
+            let join = format!(r#"{{"room":"{}","name":"Ishmael"}}"#, connected.room_id);
+            let socket = MockSocket {
+                incoming: VecDeque::from(vec![WsMsg::text(join)]),
+                ends: true,
+                ..MockSocket::default()
+            };
+            let client = smol::spawn(client_actor(
+                socket,
+                broker_tx.clone(),
+                None,
+                Codec::Json,
+                message_limit(1024),
+            ));
+            let mut last_roster = vec![];
+            loop {
+                match host_rx.recv().await.unwrap() {
+                    BrokerMsg::Left { name, reason } => {
+                        assert_eq!(reason, LeaveReason::Disconnected);
+                        assert_eq!(&*name, "Ishmael");
+                        break;
+                    }
+                    BrokerMsg::Roster { players, .. } => last_roster = players,
+                    _ => {}
+                }
+            }
+            assert!(last_roster.iter().any(|entry| entry.name == "Ishmael"));
+            match host_rx.recv().await.unwrap() {
+                BrokerMsg::Roster { players, .. } => assert_eq!(players.len(), 1),
+                other => panic!("expected the roster, got {:?}", other),
+            }
+            assert!(client.await.is_err());
+
+            drop(broker_tx);
+            assert_eq!(broker_task.await.unwrap().player_count(), 1);
+        })
+    }
+}
@@ -0,0 +1,75 @@
+//! How messages are encoded on a websocket, agreed on during the handshake
+
+use async_tungstenite::tungstenite::handshake::server::{Request, Response};
+use async_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+
+// the subprotocol a client offers to be sent (and to send) MessagePack in binary frames
+pub const MSGPACK_PROTOCOL: &str = "spyfall.msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    // text frames, what browsers get unless they ask for something else
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Codec {
+    /// The codec a handshake offers through its subprotocols, agreeing to it on the response.
+    /// Clients that offer none of ours are spoken to in JSON
+    pub fn negotiate(request: &Request, response: &mut Response) -> Self {
+        let offered = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|protocols| protocols.to_str().ok())
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == MSGPACK_PROTOCOL);
+        if !offered {
+            return Codec::Json;
+        }
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(MSGPACK_PROTOCOL),
+        );
+        Codec::MessagePack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(protocols: &[&str]) -> Request {
+        let mut request = Request::builder().uri("/");
+        for protocols in protocols {
+            request = request.header(SEC_WEBSOCKET_PROTOCOL, *protocols);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn msgpack_is_only_spoken_when_offered() {
+        let mut response = Response::default();
+        assert_eq!(
+            Codec::negotiate(&handshake(&[]), &mut response),
+            Codec::Json
+        );
+        let offers = handshake(&["chat, superchat"]);
+        assert_eq!(Codec::negotiate(&offers, &mut response), Codec::Json);
+        assert!(response.headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
+
+        for offers in [
+            &["chat,spyfall.msgpack"][..],
+            &["chat", " spyfall.msgpack "],
+        ] {
+            let mut response = Response::default();
+            let codec = Codec::negotiate(&handshake(offers), &mut response);
+            assert_eq!(codec, Codec::MessagePack);
+            assert_eq!(
+                response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+                MSGPACK_PROTOCOL
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Keeps track of when timed broker events are due.
+/// The broker races the nearest deadline against its inbox, so timed work runs inside the broker loop
+/// and can touch the `RoomTable` without any locking.
+#[derive(Debug)]
+pub struct Deadlines<K> {
+    due: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq + Clone> Deadlines<K> {
+    pub fn new() -> Self {
+        Self {
+            due: HashMap::new(),
+        }
+    }
+
+    /// Schedules `key` to fire at `at`, replacing any deadline already set for that key
+    pub fn schedule(&mut self, key: K, at: Instant) {
+        self.due.insert(key, at);
+    }
+
+    pub fn cancel(&mut self, key: &K) {
+        self.due.remove(key);
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.due.contains_key(key)
+    }
+
+    /// The earliest pending deadline, if any
+    pub fn next(&self) -> Option<Instant> {
+        self.due.values().min().copied()
+    }
+
+    /// Removes and returns every key that is due at `now`, earliest first
+    pub fn expired(&mut self, now: Instant) -> Vec<K> {
+        let mut expired = self
+            .due
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, at)| (*at, key.clone()))
+            .collect::<Vec<_>>();
+        expired.sort_by_key(|(at, _)| *at);
+        for (_, key) in &expired {
+            self.due.remove(key);
+        }
+        expired.into_iter().map(|(_, key)| key).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn expired_returns_due_keys_in_order() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::new();
+        deadlines.schedule("late", now + Duration::from_secs(2));
+        deadlines.schedule("early", now + Duration::from_secs(1));
+        deadlines.schedule("future", now + Duration::from_secs(10));
+        assert_eq!(deadlines.next(), Some(now + Duration::from_secs(1)));
+
+        let expired = deadlines.expired(now + Duration::from_secs(5));
+        assert_eq!(expired, vec!["early", "late"]);
+        assert!(deadlines.contains(&"future"));
+        assert_eq!(deadlines.next(), Some(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rescheduling_replaces_the_deadline() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::new();
+        deadlines.schedule(1, now);
+        deadlines.schedule(1, now + Duration::from_secs(3));
+        assert!(deadlines.expired(now).is_empty());
+        assert_eq!(deadlines.expired(now + Duration::from_secs(3)), vec![1]);
+        assert_eq!(deadlines.next(), None);
+    }
+}
@@ -0,0 +1,71 @@
+//! A plain HTTP health check and metrics served on the websocket port, for container orchestrators
+
+use crate::metrics::Metrics;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::TcpStream;
+
+const HEALTH_CHECK_REQUEST: &[u8] = b"GET /healthz ";
+const METRICS_REQUEST: &[u8] = b"GET /metrics ";
+// more than enough to hold the request line of a health check
+const PEEK_BYTES: usize = 64;
+
+fn is_health_check(request: &[u8]) -> bool {
+    request.starts_with(HEALTH_CHECK_REQUEST)
+}
+
+fn is_metrics_request(request: &[u8]) -> bool {
+    request.starts_with(METRICS_REQUEST)
+}
+
+/// Answers the connection if it is a health check or a metrics scrape rather than a websocket handshake,
+/// returning whether it was one. Nothing is consumed from websocket connections
+pub async fn try_answer_http(
+    stream: &mut TcpStream,
+    broker_alive: bool,
+    metrics: &Metrics,
+) -> std::io::Result<bool> {
+    let mut request = [0; PEEK_BYTES];
+    // the request line of a health check arrives in one piece, a partial one is left to the handshake
+    let peeked = stream.peek(&mut request).await?;
+    let response = if is_health_check(&request[..peeked]) {
+        if broker_alive {
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string()
+        } else {
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        }
+    } else if is_metrics_request(&request[..peeked]) {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        return Ok(false);
+    };
+    // take what was peeked, so closing doesn't reset the connection under the response
+    stream.read(&mut request).await?;
+    stream.write_all(response.as_bytes()).await?;
+    stream.close().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_served_paths_are_answered() {
+        assert!(is_health_check(
+            b"GET /healthz HTTP/1.1\r\nHost: spyfall\r\n\r\n"
+        ));
+        assert!(!is_health_check(
+            b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n"
+        ));
+        assert!(!is_health_check(b"GET /healthzz HTTP/1.1\r\n"));
+        assert!(!is_health_check(b"GET /heal"));
+        assert!(is_metrics_request(b"GET /metrics HTTP/1.1\r\n"));
+        assert!(!is_metrics_request(b"GET /healthz HTTP/1.1\r\n"));
+    }
+}
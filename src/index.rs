@@ -0,0 +1,117 @@
+use crate::{PlayerId, RoomId, SessionToken};
+use std::collections::{HashMap, HashSet};
+
+/// Reverse lookups from a player to the room they are in, so cleanup paths don't have to scan every room.
+/// Updated wherever room membership changes
+#[derive(Debug, Default)]
+pub struct PlayerIndex {
+    by_token: HashMap<SessionToken, RoomId>,
+    // names are only unique within a room, so one name can map to several rooms
+    by_name: HashMap<PlayerId, HashSet<RoomId>>,
+    // a seat's reconnect token outlives its connections, so it points at whichever connection holds the seat
+    by_reconnect_token: HashMap<String, SessionToken>,
+    reconnect_tokens: HashMap<SessionToken, String>,
+}
+
+impl PlayerIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(
+        &mut self,
+        token: SessionToken,
+        name: &PlayerId,
+        room: &RoomId,
+        reconnect_token: &str,
+    ) {
+        self.by_token.insert(token, room.clone());
+        self.by_reconnect_token
+            .insert(reconnect_token.to_string(), token);
+        self.reconnect_tokens
+            .insert(token, reconnect_token.to_string());
+        self.by_name
+            .entry(name.clone())
+            .or_default()
+            .insert(room.clone());
+    }
+
+    pub fn remove(&mut self, token: SessionToken, name: &PlayerId) {
+        if let Some(reconnect_token) = self.reconnect_tokens.remove(&token) {
+            self.by_reconnect_token.remove(&reconnect_token);
+        }
+        if let Some(room) = self.by_token.remove(&token) {
+            if let Some(rooms) = self.by_name.get_mut(name) {
+                rooms.remove(&room);
+                if rooms.is_empty() {
+                    self.by_name.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Everyone seated in any room, held seats included
+    pub fn player_count(&self) -> usize {
+        self.by_token.len()
+    }
+
+    pub fn contains_token(&self, token: SessionToken) -> bool {
+        self.by_token.contains_key(&token)
+    }
+
+    pub fn room_of(&self, token: SessionToken) -> Option<&RoomId> {
+        self.by_token.get(&token)
+    }
+
+    /// The room and current connection of the seat a reconnect token was handed out for,
+    /// None once the player is gone from the room
+    pub fn seat_of(&self, reconnect_token: &str) -> Option<(&RoomId, SessionToken)> {
+        let token = *self.by_reconnect_token.get(reconnect_token)?;
+        Some((&self.by_token[&token], token))
+    }
+
+    pub fn rooms_with_name(&self, name: &PlayerId) -> impl Iterator<Item = &RoomId> {
+        self.by_name.get(name).into_iter().flatten()
+    }
+
+    #[cfg(debug_assertions)]
+    /// Panics if the index disagrees with the room table in any way
+    pub fn assert_consistent(&self, rooms: &crate::broker::RoomTable) {
+        let mut players = 0;
+        for (room_id, room) in rooms.iter() {
+            for (name, token) in room.members() {
+                players += 1;
+                let reconnect_token = room.reconnect_token_of(name);
+                assert_eq!(
+                    reconnect_token.and_then(|reconnect| self.seat_of(reconnect)),
+                    Some((room_id, token)),
+                    "reconnect token of {} is not indexed to its seat",
+                    name
+                );
+                assert_eq!(
+                    self.by_token.get(&token),
+                    Some(room_id),
+                    "token of {} is not indexed to room {}",
+                    name,
+                    room_id
+                );
+                assert!(
+                    self.by_name
+                        .get(name)
+                        .is_some_and(|rooms| rooms.contains(room_id)),
+                    "{} is not indexed to room {}",
+                    name,
+                    room_id
+                );
+            }
+        }
+        assert_eq!(self.by_token.len(), players, "stale tokens in the index");
+        assert_eq!(
+            self.by_reconnect_token.len(),
+            players,
+            "stale reconnect tokens in the index"
+        );
+        let named = self.by_name.values().map(HashSet::len).sum::<usize>();
+        assert_eq!(named, players, "stale names in the index");
+    }
+}
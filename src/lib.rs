@@ -1,14 +1,35 @@
+//! The Spyfall game server as a library, so it can be embedded or tested from outside.
+//!
+//! `broker::broker_actor` owns every room and is driven by the `client::ClientMsg`s sent to it.
+//! `client::client_actor` serves one websocket, translating between the browser and the broker.
+//! `server::deploy` accepts connections and hands each one to a client actor, the binary only reads its config.
+
+pub mod broker;
+#[cfg(all(test, feature = "chaos"))]
+mod chaos;
+pub mod client;
+pub mod codec;
+mod deadlines;
+pub mod health;
+mod index;
+pub mod limits;
+pub mod logger;
+pub mod metrics;
+pub mod names;
+pub mod origin;
+mod password;
+pub mod repo;
+pub mod server;
+pub mod settings;
+mod snapshot;
+mod throttle;
+mod vote;
+
 pub type PlayerId = String;
 pub type RoomId = String;
 pub type Role = String;
-
-pub fn find_index<T: PartialEq<O>, O>(slice: &[T], elem: &O) -> Option<usize> {
-    slice
-        .iter()
-        .enumerate()
-        .find(|(_, x)| *x == elem)
-        .map(|(i, _)| i)
-}
+// identifies a single player's seat, unlike names which are only unique within a room
+pub type SessionToken = u64;
 
 pub type AsyncResult<T> = std::result::Result<T, AsyncErr>;
 pub type AsyncErr = Box<dyn std::error::Error + Send + Sync>;
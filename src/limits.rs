@@ -0,0 +1,57 @@
+use serde::Serialize;
+
+/// A numeric limit the server enforces.
+/// Enforcement sites go through `check`, so the limit a client is told about is always the one applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Limit {
+    name: &'static str,
+    max: u64,
+}
+
+/// Sent along with an error so clients can see the actual limit they hit.
+/// Only `Limit::check` can make one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LimitInfo {
+    name: &'static str,
+    max: u64,
+    got: u64,
+}
+
+impl Limit {
+    pub const fn new(name: &'static str, max: u64) -> Self {
+        Self { name, max }
+    }
+
+    pub const fn max(self) -> u64 {
+        self.max
+    }
+
+    pub fn check(self, got: u64) -> Result<(), LimitInfo> {
+        if got > self.max {
+            Err(LimitInfo {
+                name: self.name,
+                max: self.max,
+                got,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_reports_the_limit() {
+        let limit = Limit::new("max_things", 3);
+        assert_eq!(limit.check(0), Ok(()));
+        assert_eq!(limit.check(3), Ok(()));
+        let info = limit.check(4).unwrap_err();
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            r#"{"name":"max_things","max":3,"got":4}"#
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Logs to stderr, filtered by `RUST_LOG` the way env_logger reads it,
+//! e.g. `RUST_LOG=spyfall=info` or `RUST_LOG=debug,async_tungstenite=warn`
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::OnceLock;
+
+const FILTER_VAR: &str = "RUST_LOG";
+// what the server logs when RUST_LOG isn't set
+const DEFAULT_FILTER: &str = "spyfall=info";
+
+/// A level for every target starting with the module path, or for every target when None
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Directive {
+    target: Option<String>,
+    level: LevelFilter,
+}
+
+struct Logger {
+    directives: OnceLock<Vec<Directive>>,
+}
+
+static LOGGER: Logger = Logger {
+    directives: OnceLock::new(),
+};
+
+/// Reads a comma separated list of `target=level` or bare `level` directives, skipping any it can't make sense of
+fn parse_filter(filter: &str) -> Vec<Directive> {
+    filter
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| match part.split_once('=') {
+            Some((target, level)) => Some(Directive {
+                target: Some(target.to_string()),
+                level: level.parse().ok()?,
+            }),
+            None => match part.parse() {
+                Ok(level) => Some(Directive {
+                    target: None,
+                    level,
+                }),
+                // a bare module name turns on everything it logs
+                Err(_) => Some(Directive {
+                    target: Some(part.to_string()),
+                    level: LevelFilter::Trace,
+                }),
+            },
+        })
+        .collect()
+}
+
+/// The level of the most specific directive covering `target`
+fn level_for(directives: &[Directive], target: &str) -> LevelFilter {
+    directives
+        .iter()
+        .filter(|directive| match &directive.target {
+            Some(prefix) => target == prefix || target.starts_with(&format!("{}::", prefix)),
+            None => true,
+        })
+        .max_by_key(|directive| directive.target.as_ref().map_or(0, String::len))
+        .map_or(LevelFilter::Off, |directive| directive.level)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let directives = self.directives.get().map_or(&[][..], Vec::as_slice);
+        metadata.level() <= level_for(directives, metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, panicking if one already was
+pub fn init() {
+    let filter = std::env::var(FILTER_VAR).unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let directives = LOGGER.directives.get_or_init(|| parse_filter(&filter));
+    let max = directives.iter().map(|directive| directive.level).max();
+    log::set_max_level(max.unwrap_or(LevelFilter::Off));
+    log::set_logger(&LOGGER).expect("a logger was already installed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_directive_wins() {
+        let directives = parse_filter("warn, spyfall=info,spyfall::client=debug,,bogus=loud");
+        assert_eq!(directives.len(), 3);
+        assert_eq!(level_for(&directives, "spyfall::broker"), LevelFilter::Info);
+        assert_eq!(
+            level_for(&directives, "spyfall::client"),
+            LevelFilter::Debug
+        );
+        assert_eq!(level_for(&directives, "spyfallen"), LevelFilter::Warn);
+        assert_eq!(
+            level_for(&directives, "async_tungstenite"),
+            LevelFilter::Warn
+        );
+        assert_eq!(
+            level_for(&parse_filter(DEFAULT_FILTER), "polling"),
+            LevelFilter::Off
+        );
+        assert_eq!(
+            level_for(&parse_filter("spyfall"), "spyfall::main"),
+            LevelFilter::Trace
+        );
+    }
+}
@@ -1,34 +1,86 @@
-mod broker;
-mod client;
+use spyfall::broker::{BrokerConfig, RoomCodes};
+use spyfall::logger;
+use spyfall::origin::AllowedOrigins;
+use spyfall::server::{self, ServerConfig};
+use std::path::PathBuf;
 
-use crate::broker::broker_actor;
-use crate::client::client_actor;
-use async_tungstenite;
-use smol::{self, channel, net::TcpListener, stream::StreamExt};
-
-const HOST: &str = "localhost:4212";
+// where websockets are accepted, e.g. 0.0.0.0:4212
+const ADDRESS_VAR: &str = "SPYFALL_ADDRESS";
+// where to read the locations and roles from, instead of the roles.json built in
+const ROLES_PATH_VAR: &str = "SPYFALL_ROLES";
+// the largest websocket message a client can send, in bytes
+const MAX_MESSAGE_BYTES_VAR: &str = "SPYFALL_MAX_MESSAGE_BYTES";
+// the comma separated origins browsers may connect from, any origin can when unset
+const ALLOWED_ORIGINS_VAR: &str = "SPYFALL_ALLOWED_ORIGINS";
+// how many characters room codes are, and how many codes are tried for each new room
+const ROOM_CODE_LENGTH_VAR: &str = "SPYFALL_ROOM_CODE_LENGTH";
+const ROOM_CODE_ATTEMPTS_VAR: &str = "SPYFALL_ROOM_CODE_ATTEMPTS";
+// how many rooms, and players across them, the server takes before refusing joins
+const MAX_ROOMS_VAR: &str = "SPYFALL_MAX_ROOMS";
+const MAX_PLAYERS_VAR: &str = "SPYFALL_MAX_PLAYERS";
+// how many connected players a room needs to start a round
+const MIN_PLAYERS_VAR: &str = "SPYFALL_MIN_PLAYERS";
+// the comma separated words no room code a host picks may contain
+const BLOCKED_CODES_VAR: &str = "SPYFALL_BLOCKED_CODES";
+// where rooms are written down to survive a restart, they are only kept in memory when unset
+const SNAPSHOT_PATH_VAR: &str = "SPYFALL_SNAPSHOT";
+// the secret operators send with admin commands, nobody can send them when unset
+const ADMIN_TOKEN_VAR: &str = "SPYFALL_ADMIN_TOKEN";
 
 fn main() {
-    println!("Server hosted on {}", HOST);
-    smol::block_on(deploy());
+    logger::init();
+    let defaults = ServerConfig::default();
+    let default_codes = RoomCodes::default();
+    let room_codes = RoomCodes {
+        length: number_from_env(ROOM_CODE_LENGTH_VAR, default_codes.length),
+        attempts: number_from_env(ROOM_CODE_ATTEMPTS_VAR, default_codes.attempts),
+    };
+    let blocked_codes = std::env::var(BLOCKED_CODES_VAR)
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let broker = BrokerConfig {
+        room_codes,
+        blocked_codes,
+        max_rooms: number_from_env(MAX_ROOMS_VAR, defaults.broker.max_rooms),
+        max_players: number_from_env(MAX_PLAYERS_VAR, defaults.broker.max_players),
+        min_players: number_from_env(MIN_PLAYERS_VAR, defaults.broker.min_players),
+        snapshot_path: std::env::var_os(SNAPSHOT_PATH_VAR).map(PathBuf::from),
+        admin_token: std::env::var(ADMIN_TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty()),
+        ..defaults.broker
+    };
+    let config = ServerConfig {
+        address: std::env::var(ADDRESS_VAR).unwrap_or(defaults.address),
+        max_message_bytes: number_from_env(MAX_MESSAGE_BYTES_VAR, defaults.max_message_bytes),
+        allowed_origins: std::env::var(ALLOWED_ORIGINS_VAR)
+            .map(|list| AllowedOrigins::parse(&list))
+            .unwrap_or_default(),
+        roles_path: std::env::var_os(ROLES_PATH_VAR).map(PathBuf::from),
+        broker,
+    };
+    if let Err(err) = smol::block_on(server::deploy(config)) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
 }
 
-async fn deploy() {
-    let listener = TcpListener::bind(HOST).await.expect("Failed to bind");
-    let mut incoming_conns = listener.incoming();
-    let (broker_tx, broker_rx) = channel::unbounded();
-    smol::spawn(broker_actor(broker_rx)).detach();
-
-    println!("listening for new connections...");
-    while let Some(tcp_stream) = incoming_conns.next().await {
-        if let Ok(tcp_stream) = tcp_stream {
-            println!(
-                "Handling connection from: {}",
-                tcp_stream.peer_addr().unwrap()
-            );
-            if let Ok(websocket) = async_tungstenite::accept_async(tcp_stream).await {
-                smol::spawn(client_actor(websocket, broker_tx.clone())).detach();
+/// The number `var` is set to, or `default` when it isn't set. Exits when it is set to something else
+fn number_from_env(var: &str, default: usize) -> usize {
+    match std::env::var(var) {
+        Ok(number) => match number.parse() {
+            Ok(number) => number,
+            Err(err) => {
+                log::error!("{} must be a number: {}", var, err);
+                std::process::exit(1);
             }
-        }
+        },
+        Err(_) => default,
     }
 }
@@ -0,0 +1,78 @@
+//! Counters operators can graph, served as Prometheus text next to the health check
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Updated by the broker as things happen, and read by whoever serves them
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // how many rooms and players there are right now
+    rooms: AtomicU64,
+    players: AtomicU64,
+    games_started: AtomicU64,
+    // by the name of the JoinErr variant
+    join_errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    pub fn set_population(&self, rooms: usize, players: usize) {
+        self.rooms.store(rooms as u64, Ordering::Relaxed);
+        self.players.store(players as u64, Ordering::Relaxed);
+    }
+
+    pub fn game_started(&self) {
+        self.games_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn join_failed(&self, kind: &'static str) {
+        *self.join_errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let values = [
+            ("rooms_total", "gauge", &self.rooms),
+            ("players_total", "gauge", &self.players),
+            ("games_started_total", "counter", &self.games_started),
+        ];
+        for (name, kind, value) in values {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "# TYPE join_errors_total counter");
+        for (kind, count) in self.join_errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "join_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_as_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.set_population(2, 7);
+        metrics.game_started();
+        metrics.join_failed("UsernameTaken");
+        metrics.join_failed("NoSuchRoom");
+        metrics.join_failed("UsernameTaken");
+        assert_eq!(
+            metrics.render(),
+            "# TYPE rooms_total gauge\n\
+             rooms_total 2\n\
+             # TYPE players_total gauge\n\
+             players_total 7\n\
+             # TYPE games_started_total counter\n\
+             games_started_total 1\n\
+             # TYPE join_errors_total counter\n\
+             join_errors_total{kind=\"NoSuchRoom\"} 1\n\
+             join_errors_total{kind=\"UsernameTaken\"} 2\n"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::limits::{Limit, LimitInfo};
+use crate::PlayerId;
+use serde::Serialize;
+
+const MAX_USERNAME_CHARS: usize = 24;
+pub const USERNAME_LENGTH: Limit = Limit::new("max_username_chars", MAX_USERNAME_CHARS as u64);
+
+/// Why a name was refused, so the client can tell the player what to change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum UsernameErr {
+    Empty,
+    TooLong(LimitInfo),
+    ControlCharacter,
+}
+
+/// The name a player will be known by, with surrounding whitespace trimmed
+pub fn normalize_username(name: &str) -> Result<PlayerId, UsernameErr> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(UsernameErr::Empty);
+    }
+    USERNAME_LENGTH
+        .check(name.chars().count() as u64)
+        .map_err(UsernameErr::TooLong)?;
+    if name.chars().any(char::is_control) {
+        return Err(UsernameErr::ControlCharacter);
+    }
+    Ok(name.to_string())
+}
+
+/// What names are told apart by, two names with the same key would be confused for each other in a room.
+/// Names keep the casing they were typed with, but only one casing can be in a room
+pub fn username_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// The name a second `name` is given in a room that renames duplicates instead of refusing them:
+/// the lowest numbered suffix ("Alex (2)") whose name isn't `taken`, cutting the name short to fit one
+pub fn suffixed_username(name: &str, taken: impl Fn(&str) -> bool) -> PlayerId {
+    (2..)
+        .map(|n| {
+            let suffix = format!(" ({})", n);
+            let keep = MAX_USERNAME_CHARS.saturating_sub(suffix.chars().count());
+            let base = name.chars().take(keep).collect::<String>();
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|suffixed| !taken(suffixed))
+        .expect("a room can't take every suffix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_trimmed_and_checked() {
+        assert_eq!(normalize_username("  Ahab\t").unwrap(), "Ahab");
+        assert_eq!(normalize_username(" \n "), Err(UsernameErr::Empty));
+        assert_eq!(normalize_username(&"é".repeat(24)).unwrap(), "é".repeat(24));
+        let info = USERNAME_LENGTH.check(25).unwrap_err();
+        assert_eq!(
+            normalize_username(&"a".repeat(25)),
+            Err(UsernameErr::TooLong(info))
+        );
+        assert_eq!(
+            normalize_username("Ah\u{7}ab"),
+            Err(UsernameErr::ControlCharacter)
+        );
+    }
+
+    #[test]
+    fn duplicates_get_the_lowest_free_suffix() {
+        let taken = |names: &'static [&'static str]| {
+            move |name: &str| {
+                names
+                    .iter()
+                    .any(|taken| username_key(taken) == username_key(name))
+            }
+        };
+        assert_eq!(suffixed_username("Alex", taken(&["Alex"])), "Alex (2)");
+        assert_eq!(
+            suffixed_username("Alex", taken(&["Alex", "alex (2)", "Alex (4)"])),
+            "Alex (3)"
+        );
+        // the second Alex leaving frees their suffix for the next one
+        assert_eq!(
+            suffixed_username("Alex", taken(&["Alex", "Alex (3)"])),
+            "Alex (2)"
+        );
+
+        let long = "Ishmael of the Pequod Jr.";
+        let suffixed = suffixed_username(&long[..24], taken(&[]));
+        assert_eq!(suffixed, "Ishmael of the Pequo (2)");
+        assert_eq!(normalize_username(&suffixed).unwrap(), suffixed);
+        // without leaving a gap where the name was cut
+        assert_eq!(
+            suffixed_username("Call me Ishmael, to sea", taken(&[])),
+            "Call me Ishmael, to (2)"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Which websites may open a websocket to the server, checked during the handshake
+
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use async_tungstenite::tungstenite::http::{header::ORIGIN, StatusCode};
+
+/// The origins (e.g. `https://spyfall.example`) browsers may connect from. An empty list allows any
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedOrigins(Vec<String>);
+
+// origins are compared without case or a trailing slash, the way they get written in config
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+impl AllowedOrigins {
+    /// Reads a comma separated list of origins
+    pub fn parse(list: &str) -> Self {
+        let origins = list
+            .split(',')
+            .map(normalize)
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        Self(origins)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether a handshake with this Origin header can go ahead.
+    /// Browsers always send one, so a missing header is only fine when any origin is
+    fn allows(&self, origin: Option<&str>) -> bool {
+        self.is_empty() || origin.is_some_and(|origin| self.0.contains(&normalize(origin)))
+    }
+
+    /// Lets the handshake through, or refuses it with a 403 if it comes from a site that isn't allowed
+    // shaped like the handshake callback tungstenite takes
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let origin = request
+            .headers()
+            .get(ORIGIN)
+            .and_then(|origin| origin.to_str().ok());
+        if self.allows(origin) {
+            return Ok(response);
+        }
+        log::warn!("Refused a websocket from the origin {:?}", origin);
+        let mut refusal = ErrorResponse::new(Some("origin not allowed".to_string()));
+        *refusal.status_mut() = StatusCode::FORBIDDEN;
+        Err(refusal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(origin: Option<&str>) -> Request {
+        let mut request = Request::builder().uri("/");
+        if let Some(origin) = origin {
+            request = request.header(ORIGIN, origin);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn only_listed_origins_can_connect() {
+        let allowed = AllowedOrigins::parse(" https://spyfall.example/, http://localhost:8080,");
+        for origin in [
+            "https://spyfall.example",
+            "HTTPS://Spyfall.Example",
+            "http://localhost:8080",
+        ] {
+            let res = allowed.check(&handshake(Some(origin)), Response::default());
+            assert!(res.is_ok(), "{}", origin);
+        }
+        for origin in [
+            Some("https://evil.example"),
+            Some("http://localhost:8081"),
+            None,
+        ] {
+            let refusal = allowed
+                .check(&handshake(origin), Response::default())
+                .unwrap_err();
+            assert_eq!(refusal.status(), StatusCode::FORBIDDEN);
+        }
+
+        let anyone = AllowedOrigins::parse("");
+        assert!(anyone.is_empty());
+        assert!(anyone.check(&handshake(None), Response::default()).is_ok());
+        assert!(anyone
+            .check(
+                &handshake(Some("https://evil.example")),
+                Response::default()
+            )
+            .is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+use fastrand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+/// A salted hash of the secret needed to join a private room, the secret itself is never kept.
+/// Only snapshots of the room write the hash down
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomPassword {
+    salt: [u8; 16],
+    hash: [u8; 20],
+}
+
+impl RoomPassword {
+    pub fn new(password: &str, rng: &Rng) -> Self {
+        let mut salt = [0; 16];
+        salt.iter_mut().for_each(|byte| *byte = rng.u8(..));
+        Self {
+            hash: hash(&salt, password),
+            salt,
+        }
+    }
+
+    pub fn matches(&self, password: &str) -> bool {
+        same_hash(&hash(&self.salt, password), &self.hash)
+    }
+}
+
+/// Whether `presented` is the secret the server was configured with, e.g. the admin token.
+/// Hashed first, so neither how long the comparison takes nor the lengths give the secret away
+pub fn same_secret(expected: &str, presented: &str) -> bool {
+    same_hash(&hash(&[], expected), &hash(&[], presented))
+}
+
+// compared in full, so how long it takes doesn't tell how much of the guess was right
+fn same_hash(a: &[u8; 20], b: &[u8; 20]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// rooms get logged, the hash stays out of the logs too
+impl fmt::Debug for RoomPassword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RoomPassword(..)")
+    }
+}
+
+fn hash(salt: &[u8], password: &str) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(salt);
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_same_password_matches() {
+        let rng = Rng::with_seed(7);
+        let password = RoomPassword::new("hunter2", &rng);
+        assert!(password.matches("hunter2"));
+        assert!(!password.matches("hunter3"));
+        assert!(!password.matches(""));
+        // rooms with the same password don't share a hash
+        assert_ne!(RoomPassword::new("hunter2", &rng), password);
+        assert_eq!(format!("{:?}", password), "RoomPassword(..)");
+    }
+
+    #[test]
+    fn secrets_match_only_themselves() {
+        assert!(same_secret("open sesame", "open sesame"));
+        assert!(!same_secret("open sesame", "open sesame "));
+        assert!(!same_secret("open sesame", ""));
+    }
+}
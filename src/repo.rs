@@ -0,0 +1,198 @@
+use crate::Role;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+// what the locations of a roles file without categories are filed under
+const UNCATEGORIZED: &str = "Base";
+
+/// The locations rounds are dealt from, each with the roles players there can be given
+#[derive(Debug, Clone)]
+pub struct SpyfallRepo {
+    // mapping of locations and their associated roles
+    roles: HashMap<String, Vec<Role>>,
+    locations: Vec<String>,
+    // the themed packs the locations come in, each with its locations in order
+    categories: BTreeMap<String, Vec<String>>,
+}
+
+/// A roles file either groups its locations into categories or lists them directly
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RolesFile {
+    Categorized(HashMap<String, HashMap<String, Vec<String>>>),
+    Flat(HashMap<String, Vec<String>>),
+}
+
+#[derive(Debug)]
+pub enum RepoErr {
+    Unreadable(std::io::Error),
+    Malformed(serde_json::Error),
+    // every location needs a role to deal to the players there
+    NoRoles(Vec<String>),
+    // a location can only be listed once, in one category
+    DuplicateLocation(String),
+}
+
+impl std::fmt::Display for RepoErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreadable(io_err) => write!(f, "the roles file could not be read: {}", io_err),
+            Self::Malformed(serde_err) => write!(
+                f,
+                "the roles file should map each location to a list of roles, optionally grouped by category: {}",
+                serde_err
+            ),
+            Self::DuplicateLocation(location) => {
+                write!(f, "{} is listed more than once", location)
+            }
+            Self::NoRoles(locations) => write!(
+                f,
+                "these locations have no roles to deal: {}",
+                locations.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RepoErr {}
+
+impl Default for SpyfallRepo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpyfallRepo {
+    /// The roles.json the server was built with
+    pub fn new() -> Self {
+        Self::from_json(include_str!("../roles.json"))
+            .expect("Failed to parse the roles dataset, check roles.json")
+    }
+
+    /// Reads a roles file in the same format as roles.json
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, RepoErr> {
+        let roles_json = std::fs::read_to_string(path).map_err(RepoErr::Unreadable)?;
+        Self::from_json(&roles_json)
+    }
+
+    pub(crate) fn from_json(roles_json: &str) -> Result<Self, RepoErr> {
+        let file = serde_json::from_str::<RolesFile>(roles_json).map_err(RepoErr::Malformed)?;
+        let grouped = match file {
+            RolesFile::Categorized(grouped) => grouped,
+            RolesFile::Flat(roles) => HashMap::from([(UNCATEGORIZED.to_string(), roles)]),
+        };
+        let mut roles = HashMap::new();
+        let mut categories = BTreeMap::new();
+        for (category, category_roles) in grouped {
+            let mut locations = Vec::with_capacity(category_roles.len());
+            for (location, location_roles) in category_roles {
+                // a stray space would make a second location nobody could tell from the first
+                let location = location.trim().to_string();
+                if roles.contains_key(&location) {
+                    return Err(RepoErr::DuplicateLocation(location));
+                }
+                locations.push(location.clone());
+                roles.insert(location, location_roles);
+            }
+            // keep the order independent of the hasher, so a seeded rng deals the same locations
+            locations.sort();
+            categories.insert(category, locations);
+        }
+        let mut locations = roles.keys().cloned().collect::<Vec<_>>();
+        locations.sort();
+        let roleless = locations
+            .iter()
+            .filter(|location| roles[*location].is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !roleless.is_empty() {
+            return Err(RepoErr::NoRoles(roleless));
+        }
+        Ok(Self {
+            roles,
+            locations,
+            categories,
+        })
+    }
+
+    pub fn locations(&self) -> &[String] {
+        &self.locations
+    }
+
+    /// The locations of a single category
+    pub fn locations_in(&self, category: &str) -> Option<&[String]> {
+        self.categories.get(category).map(Vec::as_slice)
+    }
+
+    pub fn has_location(&self, location: &str) -> bool {
+        self.roles.contains_key(location)
+    }
+
+    pub fn roles(&self, location: &str) -> &[Role] {
+        &self.roles[location]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_files_are_read_or_refused() {
+        let dir = std::env::temp_dir().join(format!("spyfall-repo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("roles.json");
+
+        std::fs::write(
+            &path,
+            r#"{"Bank": ["Teller", "Guard"], "Atlantis": ["Mermaid"]}"#,
+        )
+        .unwrap();
+        let repo = SpyfallRepo::from_path(&path).unwrap();
+        assert_eq!(repo.locations(), ["Atlantis", "Bank"]);
+        assert_eq!(repo.roles("Bank"), ["Teller", "Guard"]);
+        assert_eq!(repo.locations_in("Base").unwrap(), repo.locations());
+
+        let categorized = r#"{"Base": {"Bank": ["Teller"]}, "Fantasy": {"Atlantis": ["Mermaid"]}}"#;
+        std::fs::write(&path, categorized).unwrap();
+        let repo = SpyfallRepo::from_path(&path).unwrap();
+        assert_eq!(repo.locations(), ["Atlantis", "Bank"]);
+        assert_eq!(repo.locations_in("Fantasy").unwrap(), ["Atlantis"]);
+        assert_eq!(repo.locations_in("Office"), None);
+
+        let duplicated = r#"{"Base": {"Bank": ["Teller"]}, "Heist": {"Bank": ["Robber"]}}"#;
+        std::fs::write(&path, duplicated).unwrap();
+        assert!(matches!(
+            SpyfallRepo::from_path(&path),
+            Err(RepoErr::DuplicateLocation(location)) if location == "Bank"
+        ));
+
+        let padded = r#"{"Bank": ["Teller"], " Bank ": ["Robber"], " Zoo": ["Keeper"]}"#;
+        std::fs::write(&path, padded).unwrap();
+        assert!(matches!(
+            SpyfallRepo::from_path(&path),
+            Err(RepoErr::DuplicateLocation(location)) if location == "Bank"
+        ));
+        std::fs::write(&path, r#"{"Bank": ["Teller"], " Zoo": ["Keeper"]}"#).unwrap();
+        let repo = SpyfallRepo::from_path(&path).unwrap();
+        assert_eq!(repo.locations(), ["Bank", "Zoo"]);
+        assert_eq!(repo.roles("Zoo"), ["Keeper"]);
+
+        std::fs::write(&path, r#"["Bank"]"#).unwrap();
+        assert!(matches!(
+            SpyfallRepo::from_path(&path),
+            Err(RepoErr::Malformed(_))
+        ));
+        std::fs::write(&path, r#"{"Bank": [], "Atlantis": ["Mermaid"], "Zoo": []}"#).unwrap();
+        match SpyfallRepo::from_path(&path) {
+            Err(RepoErr::NoRoles(locations)) => assert_eq!(locations, ["Bank", "Zoo"]),
+            repo => panic!("expected the empty locations to be refused, got {:?}", repo),
+        }
+        assert!(matches!(
+            SpyfallRepo::from_path(dir.join("missing.json")),
+            Err(RepoErr::Unreadable(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! Everything a deployment is configured with, and the accept loop that serves it
+
+use crate::broker::{broker_actor, BrokerConfig};
+use crate::client::{client_actor, message_limit};
+use crate::codec::Codec;
+use crate::origin::AllowedOrigins;
+use crate::repo::SpyfallRepo;
+use crate::{health, AsyncResult};
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use smol::{channel, net::TcpListener, stream::StreamExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// every client message is a small JSON object, this leaves plenty of room for chat
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+pub struct ServerConfig {
+    // where websockets (and health checks) are accepted
+    pub address: String,
+    // the largest websocket message a client can send, in bytes
+    pub max_message_bytes: usize,
+    // the origins browsers may connect from, any origin can when empty
+    pub allowed_origins: AllowedOrigins,
+    // where to read the locations and roles from, instead of the roles.json built in
+    pub roles_path: Option<PathBuf>,
+    // the rooms, and the players in them
+    pub broker: BrokerConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "localhost:4212".to_string(),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            allowed_origins: AllowedOrigins::default(),
+            roles_path: None,
+            broker: BrokerConfig::default(),
+        }
+    }
+}
+
+/// Serves websockets on the configured address until the listener fails.
+/// Fails straight away when the broker settings are unusable, the roles can't be read or the address can't be bound
+pub async fn deploy(config: ServerConfig) -> AsyncResult<()> {
+    let ServerConfig {
+        address,
+        max_message_bytes,
+        allowed_origins,
+        roles_path,
+        mut broker,
+    } = config;
+    broker.validate()?;
+    if let Some(path) = roles_path {
+        broker.repo = SpyfallRepo::from_path(&path)
+            .map_err(|err| format!("Could not load {}: {}", path.display(), err))?;
+    }
+    if allowed_origins.is_empty() {
+        log::info!("Accepting websockets from any origin");
+    }
+    // a message can't be larger than its frames, so limiting messages limits frames too
+    let max_message = message_limit(max_message_bytes);
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(max_message.max() as usize),
+        max_frame_size: Some(max_message.max() as usize),
+        ..WebSocketConfig::default()
+    };
+
+    let listener = TcpListener::bind(&address).await?;
+    log::info!("Server hosted on {}", address);
+    let mut incoming_conns = listener.incoming();
+    let (broker_tx, broker_rx) = channel::unbounded();
+    let metrics = Arc::clone(&broker.metrics);
+    smol::spawn(broker_actor(broker_rx, broker)).detach();
+
+    log::info!("listening for new connections...");
+    while let Some(tcp_stream) = incoming_conns.next().await {
+        if let Ok(mut tcp_stream) = tcp_stream {
+            let peer = tcp_stream.peer_addr().ok().map(|addr| addr.ip());
+            log::debug!("Handling connection from: {:?}", peer);
+            let broker_alive = !broker_tx.is_closed();
+            match health::try_answer_http(&mut tcp_stream, broker_alive, &metrics).await {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(err) => {
+                    log::warn!("Failed to read from the connection: {}", err);
+                    continue;
+                }
+            }
+            let mut codec = Codec::Json;
+            #[allow(clippy::result_large_err)]
+            let handshake = |request: &_, response| {
+                let mut response = allowed_origins.check(request, response)?;
+                codec = Codec::negotiate(request, &mut response);
+                Ok(response)
+            };
+            let accepted = async_tungstenite::accept_hdr_async_with_config(
+                tcp_stream,
+                handshake,
+                Some(ws_config),
+            );
+            if let Ok(websocket) = accepted.await {
+                // an oversized message fails the read, which drops the client like any broken connection
+                let client = client_actor(websocket, broker_tx.clone(), peer, codec, max_message);
+                smol::spawn(client).detach();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deploying_fails_on_unreadable_roles() {
+        let config = ServerConfig {
+            roles_path: Some(PathBuf::from("no/such/roles.json")),
+            ..ServerConfig::default()
+        };
+        let err = smol::block_on(deploy(config)).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Could not load no/such/roles.json"));
+    }
+}
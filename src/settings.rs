@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+
+// a second spy needs at least two players left to question
+const MIN_TWO_SPIES_PLAYERS: usize = 4;
+// with fewer, the spy's questions and answers would give them away straight away
+pub const MIN_PLAYERS_TO_START_GAME: usize = 3;
+
+/// Experimental rule variants a room can opt into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Variant {
+    // some rounds are dealt without a spy at all
+    DecoyRounds,
+    // the spy is dealt a fake location and role instead of being told they are the spy
+    BlindSpy,
+    // rooms of at least `two_spies_from` players are dealt a second spy
+    TwoSpies,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 3] = [Variant::DecoyRounds, Variant::BlindSpy, Variant::TwoSpies];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    Allowed,
+    Rejected(&'static str),
+}
+
+/// Whether two variants can be enabled together.
+/// Every pair is spelled out so adding a variant forces a decision about each interaction
+pub fn compatibility(a: Variant, b: Variant) -> Compatibility {
+    use Compatibility::*;
+    use Variant::*;
+    match (a, b) {
+        (DecoyRounds, DecoyRounds) | (BlindSpy, BlindSpy) | (TwoSpies, TwoSpies) => Allowed,
+        // decoy rounds simply deal no spy to hand a fake assignment to
+        (DecoyRounds, BlindSpy) | (BlindSpy, DecoyRounds) => Allowed,
+        (DecoyRounds, TwoSpies) | (TwoSpies, DecoyRounds) => Allowed,
+        (BlindSpy, TwoSpies) | (TwoSpies, BlindSpy) => {
+            Rejected("two blind spies would share a cover and vouch for each other's fake location")
+        }
+    }
+}
+
+/// A small set of variants, (de)serialized as a list of their names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<Variant>", into = "Vec<Variant>")]
+pub struct Variants(u8);
+
+impl Variants {
+    pub fn contains(self, variant: Variant) -> bool {
+        self.0 & variant.bit() != 0
+    }
+
+    pub fn insert(&mut self, variant: Variant) {
+        self.0 |= variant.bit();
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Variant> {
+        Variant::ALL
+            .iter()
+            .copied()
+            .filter(move |v| self.contains(*v))
+    }
+}
+
+impl From<Vec<Variant>> for Variants {
+    fn from(list: Vec<Variant>) -> Self {
+        let mut variants = Variants::default();
+        for variant in list {
+            variants.insert(variant);
+        }
+        variants
+    }
+}
+
+impl From<Variants> for Vec<Variant> {
+    fn from(variants: Variants) -> Self {
+        variants.iter().collect()
+    }
+}
+
+/// Host-chosen rules for a room, picked when the room is created
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    pub variants: Variants,
+    // rounds are untimed when this is None
+    pub round_secs: Option<u64>,
+    // how much time an approved extension vote adds to the round
+    pub extension_secs: u64,
+    // whether idle players are flagged and removed while the room is in the lobby
+    pub afk_in_lobby: bool,
+    // how many players it takes for the TwoSpies variant to deal a second spy
+    pub two_spies_from: usize,
+    // with two spies, each is told who the other is (blind spies are not, it would give them away)
+    pub spies_collude: bool,
+    // the first questioner is always dealt a location
+    pub spy_never_first: bool,
+    // play a match, which the first player to reach this score wins
+    pub target_score: Option<u32>,
+    // how many of the latest locations are kept out of the next deal
+    pub location_memory: usize,
+    // listed for anyone to join, unless the room also has a password
+    pub public: bool,
+    // rounds only start once everyone connected has marked themselves ready
+    pub require_ready: bool,
+    // a name someone in the room already has is given a numbered suffix, instead of being refused
+    pub auto_rename: bool,
+    // how many connected players it takes to start a round, if the server doesn't ask for more
+    pub min_players: usize,
+    // no two players at a location share a role, locations without enough of them are passed over
+    pub distinct_roles: bool,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            variants: Variants::default(),
+            round_secs: None,
+            extension_secs: 2 * 60,
+            afk_in_lobby: true,
+            two_spies_from: 5,
+            spies_collude: false,
+            // the spy opening the questioning with no role to go on is awkward, so groups opt out of it
+            spy_never_first: true,
+            target_score: None,
+            location_memory: 5,
+            public: false,
+            require_ready: false,
+            auto_rename: false,
+            min_players: MIN_PLAYERS_TO_START_GAME,
+            // a small pack can still be played, with some players sharing a role
+            distinct_roles: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum SettingsErr {
+    IncompatibleVariants {
+        first: Variant,
+        second: Variant,
+        reason: &'static str,
+    },
+    TwoSpiesTooEarly {
+        min: usize,
+    },
+    TooFewPlayers {
+        min: usize,
+    },
+}
+
+impl RoomSettings {
+    pub fn validate(&self) -> Result<(), SettingsErr> {
+        for first in self.variants.iter() {
+            for second in self.variants.iter() {
+                if let Compatibility::Rejected(reason) = compatibility(first, second) {
+                    return Err(SettingsErr::IncompatibleVariants {
+                        first,
+                        second,
+                        reason,
+                    });
+                }
+            }
+        }
+        if self.two_spies() && self.two_spies_from < MIN_TWO_SPIES_PLAYERS {
+            return Err(SettingsErr::TwoSpiesTooEarly {
+                min: MIN_TWO_SPIES_PLAYERS,
+            });
+        }
+        if self.min_players < MIN_PLAYERS_TO_START_GAME {
+            return Err(SettingsErr::TooFewPlayers {
+                min: MIN_PLAYERS_TO_START_GAME,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn decoy_rounds(&self) -> bool {
+        self.variants.contains(Variant::DecoyRounds)
+    }
+
+    pub fn blind_spy(&self) -> bool {
+        self.variants.contains(Variant::BlindSpy)
+    }
+
+    pub fn two_spies(&self) -> bool {
+        self.variants.contains(Variant::TwoSpies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatibility_matrix_is_symmetric_and_enforced() {
+        for a in Variant::ALL {
+            for b in Variant::ALL {
+                assert_eq!(compatibility(a, b), compatibility(b, a), "{:?}/{:?}", a, b);
+                let settings = RoomSettings {
+                    variants: Variants::from(vec![a, b]),
+                    ..RoomSettings::default()
+                };
+                match compatibility(a, b) {
+                    Compatibility::Allowed => assert_eq!(settings.validate(), Ok(())),
+                    Compatibility::Rejected(_) => assert!(settings.validate().is_err()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blind_spies_come_alone() {
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::BlindSpy, Variant::TwoSpies]),
+            ..RoomSettings::default()
+        };
+        match settings.validate() {
+            Err(SettingsErr::IncompatibleVariants { first, second, .. }) => {
+                assert_eq!((first, second), (Variant::BlindSpy, Variant::TwoSpies))
+            }
+            res => panic!("expected the variants to be refused, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn two_spies_need_enough_players_to_question() {
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::TwoSpies]),
+            two_spies_from: 3,
+            ..RoomSettings::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            Err(SettingsErr::TwoSpiesTooEarly { min: 4 })
+        );
+        // the threshold means nothing while the variant is off
+        let settings = RoomSettings {
+            variants: Variants::default(),
+            ..settings
+        };
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rooms_need_at_least_three_players() {
+        let settings = RoomSettings {
+            min_players: 2,
+            ..RoomSettings::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            Err(SettingsErr::TooFewPlayers { min: 3 })
+        );
+        let settings = RoomSettings {
+            min_players: 6,
+            ..settings
+        };
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn variants_serialize_as_a_list() {
+        let settings = RoomSettings {
+            variants: Variants::from(vec![Variant::BlindSpy, Variant::DecoyRounds]),
+            ..RoomSettings::default()
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(json.starts_with(r#"{"variants":["DecoyRounds","BlindSpy"],"#));
+        assert_eq!(
+            serde_json::from_str::<RoomSettings>(&json).unwrap(),
+            settings
+        );
+        assert_eq!(
+            serde_json::from_str::<RoomSettings>("{}").unwrap(),
+            RoomSettings::default()
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! What the broker writes down about its rooms, so they survive the server restarting.
+//! Only what can't be rebuilt is kept: connections are made again by players reconnecting
+//! with their tokens, and a round being played is lost along with the assignments in it
+
+use crate::broker::RoundSummary;
+use crate::password::RoomPassword;
+use crate::settings::RoomSettings;
+use crate::{PlayerId, RoomId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub room_id: RoomId,
+    pub host: PlayerId,
+    // in joining order
+    pub seats: Vec<SeatSnapshot>,
+    pub settings: RoomSettings,
+    pub min_players: usize,
+    pub scores: HashMap<PlayerId, u32>,
+    pub history: Vec<RoundSummary>,
+    pub tie_break: Vec<PlayerId>,
+    pub rounds_started: u32,
+    pub recent_locations: Vec<String>,
+    pub category: Option<String>,
+    pub password: Option<RoomPassword>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeatSnapshot {
+    pub name: PlayerId,
+    // what the player takes the seat back with
+    pub reconnect_token: String,
+}
+
+#[derive(Debug)]
+pub enum SnapshotErr {
+    Unreadable(std::io::Error),
+    Malformed(serde_json::Error),
+}
+
+impl std::fmt::Display for SnapshotErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreadable(io_err) => write!(f, "the snapshot could not be read: {}", io_err),
+            Self::Malformed(serde_err) => {
+                write!(f, "the snapshot is not a list of rooms: {}", serde_err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotErr {}
+
+/// The rooms written to `path`, none when nothing has been written there yet
+pub async fn load(path: &Path) -> Result<Vec<RoomSnapshot>, SnapshotErr> {
+    let json = match smol::fs::read_to_string(path).await {
+        Ok(json) => json,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(SnapshotErr::Unreadable(err)),
+    };
+    serde_json::from_str(&json).map_err(SnapshotErr::Malformed)
+}
+
+/// Replaces what is at `path` with `rooms`. Written next to it first, so a crash mid-write
+/// leaves the previous snapshot rather than half of this one
+pub async fn save(path: &Path, rooms: &[RoomSnapshot]) -> std::io::Result<()> {
+    let json = serde_json::to_vec(rooms)?;
+    let mut partial = OsString::from(path);
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    smol::fs::write(&partial, json).await?;
+    smol::fs::rename(&partial, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_read_back_or_refused() {
+        smol::block_on(async {
+            let dir = std::env::temp_dir().join(format!("spyfall-snapshot-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("rooms.json");
+
+            // the first start of a server has nothing to restore
+            assert!(load(&path).await.unwrap().is_empty());
+            let room = RoomSnapshot {
+                room_id: "R00M".to_string(),
+                host: "Ahab".to_string(),
+                seats: vec![SeatSnapshot {
+                    name: "Ahab".to_string(),
+                    reconnect_token: "again".to_string(),
+                }],
+                settings: RoomSettings::default(),
+                min_players: 3,
+                scores: HashMap::from([("Ahab".to_string(), 2)]),
+                history: vec![],
+                tie_break: vec![],
+                rounds_started: 1,
+                recent_locations: vec!["Bank".to_string()],
+                category: None,
+                password: None,
+            };
+            save(&path, &[room]).await.unwrap();
+            let rooms = load(&path).await.unwrap();
+            assert_eq!(rooms.len(), 1);
+            assert_eq!(rooms[0].seats[0].reconnect_token, "again");
+            assert_eq!(rooms[0].scores["Ahab"], 2);
+            assert!(!dir.join("rooms.json.partial").exists());
+
+            std::fs::write(&path, r#"{"rooms": 3}"#).unwrap();
+            assert!(matches!(load(&path).await, Err(SnapshotErr::Malformed(_))));
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+    }
+}
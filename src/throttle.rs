@@ -0,0 +1,137 @@
+use crate::limits::{Limit, LimitInfo};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Lets through bursts of up to `burst` messages, refilled at a steady rate
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    burst: Limit,
+    capacity: u32,
+    refill_every: Duration,
+    tokens: u32,
+    // when the last token was added, or the bucket was last full
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: Limit, per_sec: u32, now: Instant) -> Self {
+        let capacity = burst.max() as u32;
+        Self {
+            burst,
+            capacity,
+            refill_every: Duration::from_secs(1) / per_sec,
+            tokens: capacity,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes a token if there is one, letting the message through
+    pub fn try_take(&mut self, now: Instant) -> Result<(), LimitInfo> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let earned = (elapsed.as_nanos() / self.refill_every.as_nanos()) as u32;
+        if self.tokens + earned >= self.capacity {
+            self.tokens = self.capacity;
+            self.refilled_at = now;
+        } else {
+            // the part of a token still being earned carries over
+            self.tokens += earned;
+            self.refilled_at += self.refill_every * earned;
+        }
+        // what the burst would come to with this message
+        self.burst
+            .check(u64::from(self.capacity - self.tokens) + 1)?;
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+/// Counts what each key (e.g. a peer address) did within the last `window`
+#[derive(Debug, Clone)]
+pub struct SlidingWindow<K> {
+    window: Duration,
+    events: HashMap<K, VecDeque<Instant>>,
+}
+
+impl<K: Hash + Eq> SlidingWindow<K> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            events: HashMap::new(),
+        }
+    }
+
+    /// Records an event for `key`, unless that would take it over `limit` within the window
+    pub fn try_record(&mut self, key: K, limit: Limit, now: Instant) -> Result<(), LimitInfo> {
+        let window = self.window;
+        let events = self.events.entry(key).or_default();
+        while events
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= window)
+        {
+            events.pop_front();
+        }
+        limit.check(events.len() as u64 + 1)?;
+        events.push_back(now);
+        Ok(())
+    }
+
+    /// Forgets the keys with nothing left in the window, so the map only holds recent keys
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.events.retain(|_, events| {
+            events
+                .back()
+                .is_some_and(|at| now.saturating_duration_since(*at) < window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_are_let_through_then_throttled() {
+        let start = Instant::now();
+        let burst = Limit::new("max_things", 3);
+        let mut bucket = TokenBucket::new(burst, 2, start);
+        assert!((0..3).all(|_| bucket.try_take(start).is_ok()));
+        assert_eq!(bucket.try_take(start), burst.check(4));
+        // a token every half second
+        assert!(bucket.try_take(start + Duration::from_millis(400)).is_err());
+        assert!(bucket.try_take(start + Duration::from_millis(600)).is_ok());
+        assert!(bucket.try_take(start + Duration::from_millis(900)).is_err());
+        assert!(bucket.try_take(start + Duration::from_millis(1000)).is_ok());
+        // idling refills up to the burst, no further
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.try_take(later).is_ok()));
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test]
+    fn windows_slide_and_are_pruned() {
+        let start = Instant::now();
+        let limit = Limit::new("max_things", 2);
+        let mut window = SlidingWindow::new(Duration::from_secs(10));
+        assert!(window.try_record("a", limit, start).is_ok());
+        assert!(window
+            .try_record("a", limit, start + Duration::from_secs(5))
+            .is_ok());
+        let info = window
+            .try_record("a", limit, start + Duration::from_secs(9))
+            .unwrap_err();
+        assert_eq!(info, limit.check(3).unwrap_err());
+        // other keys have their own count
+        assert!(window.try_record("b", limit, start).is_ok());
+        // the first event falls out of the window
+        assert!(window
+            .try_record("a", limit, start + Duration::from_secs(10))
+            .is_ok());
+
+        window.prune(start + Duration::from_secs(12));
+        assert_eq!(window.events.len(), 1);
+        window.prune(start + Duration::from_secs(20));
+        assert!(window.events.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::PlayerId;
+use std::collections::HashMap;
+
+/// A yes/no vote among the players of a room, decided by a strict majority either way
+#[derive(Debug, Clone, Default)]
+pub struct Vote {
+    ballots: HashMap<PlayerId, bool>,
+}
+
+impl Vote {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records (or changes) a player's ballot
+    pub fn cast(&mut self, voter: PlayerId, approve: bool) {
+        self.ballots.insert(voter, approve);
+    }
+
+    /// Forgets the ballot of a player who is no longer part of the electorate
+    pub fn withdraw(&mut self, voter: &PlayerId) {
+        self.ballots.remove(voter);
+    }
+
+    /// The outcome once a strict majority of `electorate` agrees, None while it is still undecided
+    pub fn outcome(&self, electorate: usize) -> Option<bool> {
+        let yes = self.ballots.values().filter(|approve| **approve).count();
+        let no = self.ballots.len() - yes;
+        if yes * 2 > electorate {
+            Some(true)
+        } else if no * 2 >= electorate {
+            // a tie can no longer be won
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_decides() {
+        let mut vote = Vote::new();
+        vote.cast("Ahab".to_string(), true);
+        assert_eq!(vote.outcome(3), None);
+        vote.cast("Ishmael".to_string(), true);
+        assert_eq!(vote.outcome(3), Some(true));
+        // changing your mind counts
+        vote.cast("Ishmael".to_string(), false);
+        assert_eq!(vote.outcome(3), None);
+        vote.cast("Stubb".to_string(), false);
+        assert_eq!(vote.outcome(3), Some(false));
+    }
+
+    #[test]
+    fn ties_reject() {
+        let mut vote = Vote::new();
+        vote.cast("Ahab".to_string(), true);
+        vote.cast("Ishmael".to_string(), true);
+        vote.cast("Stubb".to_string(), false);
+        assert_eq!(vote.outcome(4), None);
+        vote.cast("Flask".to_string(), false);
+        assert_eq!(vote.outcome(4), Some(false));
+        vote.withdraw(&"Flask".to_string());
+        assert_eq!(vote.outcome(3), Some(true));
+    }
+}
@@ -0,0 +1,62 @@
+//! Drives the broker through the library API alone, the way an embedding server would
+
+use smol::channel;
+use spyfall::broker::{broker_actor, BrokerConfig, BrokerMsg};
+use spyfall::client::{ClientMsg, Join, RoomMsg, PROTOCOL_VERSION};
+use spyfall::settings::RoomSettings;
+
+fn join(name: &str, room: Option<String>) -> Join {
+    Join {
+        room,
+        name: name.to_string(),
+        settings: RoomSettings::default(),
+        password: None,
+        reconnect_token: None,
+        requested_code: None,
+        invite: None,
+        protocol_version: PROTOCOL_VERSION,
+        peer: None,
+    }
+}
+
+#[test]
+fn rooms_can_be_played_without_a_websocket() {
+    smol::block_on(async {
+        let (broker_tx, broker_rx) = channel::unbounded();
+        let broker_task = smol::spawn(broker_actor(broker_rx, BrokerConfig::default()));
+        let mut seats = vec![];
+        let mut room = None;
+        for name in ["Ahab", "Ishmael", "Queequeg"] {
+            let (join_tx, join_rx) = channel::bounded(1);
+            let msg = ClientMsg::Join(join(name, room.clone()), join_tx);
+            broker_tx.send(msg).await.unwrap();
+            let (connected, rx) = join_rx.recv().await.unwrap().unwrap();
+            room = Some(connected.room_id.clone());
+            seats.push((connected, rx));
+        }
+
+        let (host, _) = &seats[0];
+        let start = RoomMsg::Start {
+            room: host.room_id.clone(),
+            name: host.name.clone(),
+        };
+        broker_tx
+            .send(ClientMsg::Room(host.id, start))
+            .await
+            .unwrap();
+        for (connected, rx) in &seats {
+            let started = loop {
+                match rx.recv().await.unwrap() {
+                    BrokerMsg::Started(start) => break start,
+                    _ => continue,
+                }
+            };
+            // what an embedding server would pass on to its own clients
+            let started = serde_json::to_value(&started).unwrap();
+            assert_eq!(started["round"], 1, "{}", connected.name);
+        }
+
+        drop((broker_tx, seats));
+        assert_eq!(broker_task.await.unwrap().len(), 1);
+    })
+}